@@ -7,11 +7,13 @@ use std::{
 
 use tokio::{
     io::{AsyncBufReadExt, BufReader},
+    sync::mpsc,
     task::JoinError,
 };
 
 use chat::{
-    client::{self, InitError, LoopError},
+    client::{self, Command, Event, InitError, LoopError},
+    protocol::{Admin, Ban, Cursor, Notice, Origin, Presence, Request, RoomAction, Target},
     serialization::Deserializable,
 };
 
@@ -63,20 +65,78 @@ async fn main() -> Result<(), Error<String>> {
         }
     }
 
+    let (reads, mut read_receipts) = mpsc::unbounded_channel();
     let conn = client::Builder::new::<String>()
         .name(map.remove("name").unwrap_or_else(|| read_line("name")))
         .addr(map.remove("address").unwrap_or_else(|| read_line("address")))
         .password(map.remove("password").unwrap_or_else(|| read_line("password")).into_bytes())
         .first(map.remove("new").map_or(None, check_bool).unwrap_or_else(|| check_bool(read_line("new? [Y/n]")).unwrap()))
-        .writer(|user, message| println!("{user}> {message}"))
+        .writer(move |event| match event {
+            Event::Message(delivery, message) => {
+                match &delivery.origin {
+                    Origin::User(user) => println!("{user}> {message}"),
+                    Origin::Room { room, from } => println!("#{room} {from}> {message}"),
+                }
+                let _ = reads.send(Command::read(&delivery));
+            }
+            Event::Notice(Notice::Room { action, room, ok }) => println!("{action:?} #{room}: {}", if ok { "ok" } else { "failed" }),
+            Event::Notice(Notice::Status { id, user, status }) => println!("[{}] {status:?} by {user}", id.0),
+            Event::Notice(Notice::Block { user, blocked, ok }) => println!("{} {user}: {}", if blocked { "block" } else { "unblock" }, if ok { "ok" } else { "failed" }),
+            Event::Notice(Notice::Accepted { .. } | Notice::Rejected { .. } | Notice::Presence { .. } | Notice::Shutdown { .. } | Notice::History { .. }) => {}
+            Event::Notice(Notice::Admin(reply)) => println!("{reply:?}"),
+            Event::Rejected(target, reason) => println!("{target:?}: {reason:?}"),
+            Event::Presence { user, presence, text } => println!("{user} is {presence:?}{}", text.map_or_else(String::new, |text| format!(" ({text})"))),
+            Event::History { with, messages, more } => {
+                for (delivery, _, message) in messages {
+                    println!("{with:?} {}> {message}", delivery.origin.sender());
+                }
+                if more {
+                    println!("{with:?}: more history available");
+                }
+            }
+            Event::Shutdown { reason, reconnect } => println!("server going away{}{}", reason.map_or_else(String::new, |reason| format!(": {reason}")), reconnect.map_or_else(String::new, |after| format!(", reconnect in {}s", after.as_secs()))),
+        })
         .connect::<1024>().await?;
 
     let mut lines = BufReader::new(tokio::io::stdin()).lines();
     loop {
         let Ok(Some(name)) = lines.next_line().await else { break; };
         if name.len() == 0 { break; }
-        let Ok(Some(message)) = lines.next_line().await else { break; };
-        let Ok(()) = conn.send((name, message)) else { break; };
+        while let Ok(read) = read_receipts.try_recv() {
+            let _ = conn.send(read);
+        }
+        let command = match name.split_once(' ') {
+            Some(("/create", room)) => Command::Request(Request::Room(RoomAction::Create, room.to_owned())),
+            Some(("/join", room)) => Command::Request(Request::Room(RoomAction::Join, room.to_owned())),
+            Some(("/leave", room)) => Command::Request(Request::Room(RoomAction::Leave, room.to_owned())),
+            Some(("/block", user)) => Command::Request(Request::Block { user: user.to_owned(), blocked: true }),
+            Some(("/unblock", user)) => Command::Request(Request::Block { user: user.to_owned(), blocked: false }),
+            Some(("/admin", "sessions")) => Command::Request(Request::Admin(Admin::Sessions)),
+            Some(("/kick", user)) => Command::Request(Request::Admin(Admin::Kick(user.to_owned()))),
+            Some(("/ban", user)) => Command::Request(Request::Admin(Admin::Ban { ban: Ban::User(user.to_owned()), duration: None })),
+            Some(("/mute", user)) => Command::Request(Request::Admin(Admin::Mute { user: user.to_owned(), duration: None })),
+            Some(("/unmute", user)) => Command::Request(Request::Admin(Admin::Unmute(user.to_owned()))),
+            Some(("/admin", "lockouts")) => Command::Request(Request::Admin(Admin::Lockouts)),
+            Some(("/admin", "stats")) => Command::Request(Request::Admin(Admin::Stats)),
+            Some(("/admin", "reload")) => Command::Request(Request::Admin(Admin::Reload)),
+            Some(("/unlock", user)) => Command::Request(Request::Admin(Admin::Unlock(Ban::User(user.to_owned())))),
+            Some(("/watch", user)) => Command::subscribe(user.to_owned()),
+            Some(("/unwatch", user)) => Command::unsubscribe(user.to_owned()),
+            Some(("/away", text)) => Command::presence(Presence::Away, Some(text.to_owned())),
+            Some(("/history", with)) => Command::history(match with.strip_prefix('#') {
+                Some(room) => Target::Room(room.to_owned()),
+                None => Target::User(with.to_owned()),
+            }, Cursor::Latest, 20),
+            Some(("/back", text)) => Command::presence(Presence::Online, Some(text.to_owned()).filter(|text| !text.is_empty())),
+            _ => {
+                let Ok(Some(message)) = lines.next_line().await else { break; };
+                match name.strip_prefix('#') {
+                    Some(room) => Command::Send(Target::Room(room.to_owned()), message),
+                    None => Command::Send(Target::User(name), message),
+                }
+            }
+        };
+        let Ok(()) = conn.send(command) else { break; };
     }
     Ok(conn.shutdown().await??)
 }
\ No newline at end of file
@@ -4,6 +4,8 @@ use std::{
     collections::{
         hash_map::Entry,
         HashMap,
+        HashSet,
+        VecDeque,
     },
     fs::{
         File,
@@ -15,8 +17,13 @@ use std::{
         SeekFrom,
         Write,
     },
+    net::SocketAddr,
     path::Path,
     future::Future,
+    time::{
+        Duration,
+        SystemTime,
+    },
 };
 use futures::future;
 
@@ -28,19 +35,41 @@ use tokio::{
 use chat::{
     server::{
         self,
+        AdminRequest,
+        AuthMethod,
+        ConfigError,
+        IntoAddr,
+        Listener,
+        Reload,
         ServerError,
     },
     db::{
         DataBase,
+        History,
+        Offline,
         Password,
+        Record,
         User,
     },
-    serialization::Deserializable,
-    logger::StdioLogger,
+    logger::{AnyLogger, LevelFilter, Logger, StdioLogger},
+    protocol::{Admin, Ban, Cursor, Delivery, Target},
+    ticket::TicketId,
+    Bot,
+    MessageHook,
+    Outbox,
+    RateLimit,
+    Scope,
+    Verdict,
 };
 
 struct InMemoryDB {
     users: HashMap<String, (User, Password)>,
+    revoked: HashMap<TicketId, SystemTime>,
+    revoked_users: HashMap<User, SystemTime>,
+    rooms: HashMap<String, HashSet<User>>,
+    offline: HashMap<User, VecDeque<Offline>>,
+    blocked: HashSet<(User, User)>,
+    bans: HashMap<Ban, Option<SystemTime>>,
     file: File,
 }
 
@@ -70,6 +99,12 @@ impl InMemoryDB {
         }
         Self {
             users,
+            revoked: HashMap::new(),
+            revoked_users: HashMap::new(),
+            rooms: HashMap::new(),
+            offline: HashMap::new(),
+            blocked: HashSet::new(),
+            bans: HashMap::new(),
             file,
         }
     }
@@ -93,6 +128,21 @@ impl DataBase for InMemoryDB {
     type LogInFuture = impl Future<Output=Option<User>> + Send;
     type CreateUserFuture = impl Future<Output=Option<User>> + Send;
     type UserFromUsernameFuture = impl Future<Output=Option<User>> + Send;
+    type TicketRevokedFuture = impl Future<Output=bool> + Send;
+    type RevokeTicketFuture = impl Future<Output=()> + Send;
+    type CreateRoomFuture = impl Future<Output=bool> + Send;
+    type JoinRoomFuture = impl Future<Output=bool> + Send;
+    type LeaveRoomFuture = impl Future<Output=bool> + Send;
+    type RoomMembersFuture = impl Future<Output=Option<Vec<User>>> + Send;
+    type BlockedFuture = impl Future<Output=bool> + Send;
+    type SetBlockedFuture = impl Future<Output=bool> + Send;
+    type BanFuture = impl Future<Output=()> + Send;
+    type UnbanFuture = impl Future<Output=bool> + Send;
+    type BannedFuture = impl Future<Output=bool> + Send;
+    type StoreOfflineFuture = impl Future<Output=()> + Send;
+    type TakeOfflineFuture = impl Future<Output=Vec<Offline>> + Send;
+    type RevokeTicketsFuture = impl Future<Output=()> + Send;
+    type TicketsRevokedFuture = impl Future<Output=Option<SystemTime>> + Send;
 
     fn log_in(&mut self, name: String, password: &[u8]) -> Self::LogInFuture {
         fn log_in(db: &mut InMemoryDB, name: String, password: &[u8]) -> Option<User> {
@@ -117,41 +167,269 @@ impl DataBase for InMemoryDB {
     fn user_from_username(&mut self, name: &str) -> Self::UserFromUsernameFuture {
         future::ready(self.users.get(name).map(|it| it.0.clone()))
     }
+
+    fn ticket_revoked(&mut self, id: &TicketId) -> Self::TicketRevokedFuture {
+        future::ready(self.revoked.contains_key(id))
+    }
+
+    fn revoke_ticket(&mut self, id: TicketId, expires: SystemTime) -> Self::RevokeTicketFuture {
+        let now = SystemTime::now();
+        self.revoked.retain(|_, expires| *expires > now);
+        self.revoked.insert(id, expires);
+        future::ready(())
+    }
+
+    fn create_room(&mut self, name: String, owner: User) -> Self::CreateRoomFuture {
+        future::ready(match self.rooms.entry(name) {
+            Entry::Occupied(_) => false,
+            Entry::Vacant(e) => {
+                e.insert(HashSet::from([owner]));
+                true
+            }
+        })
+    }
+
+    fn join_room(&mut self, name: &str, user: User) -> Self::JoinRoomFuture {
+        future::ready(self.rooms.get_mut(name).is_some_and(|members| members.insert(user)))
+    }
+
+    fn leave_room(&mut self, name: &str, user: &User) -> Self::LeaveRoomFuture {
+        future::ready(self.rooms.get_mut(name).is_some_and(|members| members.remove(user)))
+    }
+
+    fn room_members(&mut self, name: &str) -> Self::RoomMembersFuture {
+        future::ready(self.rooms.get(name).map(|members| members.iter().cloned().collect()))
+    }
+
+    fn blocked(&mut self, user: &User, by: &User) -> Self::BlockedFuture {
+        future::ready(self.blocked.contains(&(user.clone(), by.clone())))
+    }
+
+    fn set_blocked(&mut self, user: User, by: User, blocked: bool) -> Self::SetBlockedFuture {
+        future::ready(match (self.users.contains_key(&user.to_string()), blocked) {
+            (false, _) => false,
+            (true, true) => self.blocked.insert((user, by)),
+            (true, false) => self.blocked.remove(&(user, by)),
+        })
+    }
+
+    fn ban(&mut self, ban: Ban, until: Option<SystemTime>) -> Self::BanFuture {
+        self.bans.insert(ban, until);
+        future::ready(())
+    }
+
+    fn unban(&mut self, ban: &Ban) -> Self::UnbanFuture {
+        future::ready(self.bans.remove(ban).is_some())
+    }
+
+    fn banned(&mut self, ban: &Ban) -> Self::BannedFuture {
+        let now = SystemTime::now();
+        self.bans.retain(|_, until| until.map_or(true, |until| until > now));
+        future::ready(self.bans.contains_key(ban))
+    }
+
+    fn store_offline(&mut self, user: User, message: Offline, limit: usize) -> Self::StoreOfflineFuture {
+        let messages = self.offline.entry(user).or_default();
+        messages.push_back(message);
+        while messages.len() > limit {
+            messages.pop_front();
+        }
+        future::ready(())
+    }
+
+    fn take_offline(&mut self, user: &User) -> Self::TakeOfflineFuture {
+        future::ready(self.offline.remove(user).map_or_else(Vec::new, Vec::from))
+    }
+
+    fn revoke_tickets(&mut self, user: User, before: SystemTime) -> Self::RevokeTicketsFuture {
+        self.revoked_users.insert(user, before);
+        future::ready(())
+    }
+
+    fn tickets_revoked(&mut self, user: &User) -> Self::TicketsRevokedFuture {
+        future::ready(self.revoked_users.get(user).copied())
+    }
+}
+
+#[derive(Default)]
+struct InMemoryHistory(Vec<Record>);
+
+impl History for InMemoryHistory {
+    type RecordFuture = impl Future<Output=()> + Send;
+    type QueryFuture = impl Future<Output=Vec<Record>> + Send;
+
+    fn record(&mut self, record: Record) -> Self::RecordFuture {
+        self.0.push(record);
+        future::ready(())
+    }
+
+    fn query(&mut self, user: &User, with: &Target, cursor: Cursor, limit: usize) -> Self::QueryFuture {
+        let matches = |record: &&Record| match (with, &record.to) {
+            (Target::Room(room), Target::Room(to)) => room == to,
+            (Target::User(with), Target::User(to)) => {
+                record.from == *user && to == with || record.from.to_string() == *with && *to == user.to_string()
+            }
+            _ => false,
+        };
+        let records = match cursor {
+            Cursor::After(id) => self.0.iter().filter(matches).filter(|record| record.id > id).take(limit).cloned().collect(),
+            Cursor::Latest | Cursor::Before(_) => {
+                let mut records = self.0.iter()
+                    .rev()
+                    .filter(matches)
+                    .filter(|record| match cursor {
+                        Cursor::Before(id) => record.id < id,
+                        _ => true,
+                    })
+                    .take(limit)
+                    .cloned()
+                    .collect::<Vec<_>>();
+                records.reverse();
+                records
+            }
+        };
+        future::ready(records)
+    }
+}
+
+struct Censor(&'static [&'static str]);
+
+impl MessageHook<String> for Censor {
+    fn check(&self, _: &User, _: &Target, message: &String) -> Verdict<String> {
+        if !self.0.iter().any(|word| message.contains(word)) {
+            return Verdict::Allow;
+        }
+        Verdict::Modify(self.0.iter().fold(message.clone(), |message, word| message.replace(word, &"*".repeat(word.len()))))
+    }
+}
+
+struct Help;
+
+impl Bot<String> for Help {
+    fn message(&mut self, delivery: Delivery, message: String, outbox: &Outbox<String>) {
+        let reply = match message.trim() {
+            "/rooms" => "use /create, /join and /leave with a room name, then write to #room".to_owned(),
+            "/admin" => "admins can use /admin sessions, /kick, /ban, /mute and /unmute".to_owned(),
+            _ => "try /rooms or /admin".to_owned(),
+        };
+        outbox.send(Target::User(delivery.origin.sender().to_owned()), reply);
+    }
 }
 
 #[derive(Debug)]
-enum Error<M: Deserializable> {
+enum Error {
     Cancel(io::Error),
     Join(JoinError),
-    Server(ServerError<M>),
+    Server(ServerError),
+    Config(ConfigError),
 }
 
-impl<M: Deserializable> From<io::Error> for Error<M> {
+impl From<io::Error> for Error {
     fn from(value: io::Error) -> Self {
         Self::Cancel(value)
     }
 }
 
-impl<M: Deserializable> From<JoinError> for Error<M> {
+impl From<JoinError> for Error {
     fn from(value: JoinError) -> Self {
         Self::Join(value)
     }
 }
 
-impl<M: Deserializable> From<ServerError<M>> for Error<M> {
-    fn from(value: ServerError<M>) -> Self {
+impl From<ServerError> for Error {
+    fn from(value: ServerError) -> Self {
         Self::Server(value)
     }
 }
 
-#[tokio::main(flavor = "current_thread")]
-async fn main() -> Result<(), Error<String>> {
-    let handle = server::Builder::new()
-        .addr(([0, 0, 0, 0], 5000))
-        .db(InMemoryDB::new("chat.txt"))
-        .logger(StdioLogger)
+impl From<ConfigError> for Error {
+    fn from(value: ConfigError) -> Self {
+        Self::Config(value)
+    }
+}
+
+fn from_config(path: &str) -> Result<server::Builder<server::ConfiguredAddr, InMemoryDB, LevelFilter<AnyLogger>>, ConfigError> {
+    let config = server::Config::load(path)?;
+    if config.block_size != 1024 {
+        return Err(ConfigError::invalid("server.block_size", "this server is built for 1024 byte blocks"));
+    }
+    let db = match config.database.backend.as_str() {
+        "file" => InMemoryDB::new(config.database.path.clone().unwrap_or_else(|| "chat.txt".into())),
+        backend => return Err(ConfigError::invalid("database.backend", format!("unsupported backend {backend:?}, expected \"file\""))),
+    };
+    config.builder(db)
+}
+
+async fn run<A: IntoAddr, L: Logger + Send + 'static>(builder: server::Builder<A, InMemoryDB, L>) -> Result<(), Error> {
+    let handle = builder
+        .hook(Censor(&["darn", "heck"]))
+        .bot("help".to_owned(), Help)
+        .history(InMemoryHistory::default())
         .serve::<1024, String>();
     tokio::signal::ctrl_c().await?;
+    let (request, reply) = AdminRequest::new(Admin::Shutdown { reason: Some("restarting".to_owned()), reconnect: Some(Duration::from_secs(30)) });
+    if handle.send(request).is_ok() {
+        let _ = reply.await;
+    }
     // let _ = tokio::io::BufReader::new(tokio::io::stdin()).read_line(&mut String::new()).await;
     Ok(handle.shutdown().await??)
 }
+
+#[tokio::main(flavor = "current_thread")]
+async fn main() -> Result<(), Error> {
+    // server --config examples/chat.toml
+    // server [port] [domain federation-port peer=addr...]
+    let mut args = std::env::args().skip(1).peekable();
+    if args.next_if_eq("--config").is_some() {
+        let path = args.next().unwrap_or_else(|| "chat.toml".to_owned());
+        return run(from_config(&path)?).await;
+    }
+    let port = args.next().and_then(|port| port.parse().ok()).unwrap_or(5000);
+    let mut builder = server::Builder::new()
+        .addr(([0, 0, 0, 0], port))
+        .db(InMemoryDB::new(match port {
+            5000 => "chat.txt".to_owned(),
+            port => format!("chat-{port}.txt"),
+        }))
+        .logger(StdioLogger)
+        .resumption_tickets(Duration::from_secs(24 * 60 * 60))
+        .offline_messages(100)
+        .offline_retention(Duration::from_secs(7 * 24 * 60 * 60))
+        .max_message_size(64 * 1024)
+        .admins(["admin".to_owned()])
+        .message_rate(Scope::User, RateLimit::new(10, 20))
+        .byte_rate(Scope::Connection, RateLimit::new(256 * 1024, 1024 * 1024))
+        .login_rate(Scope::Ip, RateLimit::new(1, 5))
+        .login_backoff(Duration::from_secs(1), Duration::from_secs(60))
+        .login_lockout(10, Duration::from_secs(15 * 60))
+        .max_connections(1024)
+        .max_connections_per_ip(16)
+        .max_handshakes(64)
+        .handshake_timeout(Duration::from_secs(10))
+        .drain_timeout(Duration::from_secs(5))
+        .listener(Listener::unix(format!("chat-{port}.sock")).methods([AuthMethod::Password, AuthMethod::Ticket]).admin(false))
+        .admin_socket(format!("chat-admin-{port}.sock"))
+        .metrics(SocketAddr::from(([127, 0, 0, 1], port + 4000)))
+        .reload(|| {
+            let admins = std::fs::read_to_string("admins.txt").map_err(|e| format!("admins.txt: {e}"))?;
+            Ok(Reload { admins: admins.lines().map(str::trim).filter(|line| !line.is_empty()).map(str::to_owned).collect() })
+        });
+    // the help bot's account is kept in the user file, so it needs the same secret on every run to log back in
+    if let Ok(shared) = std::env::var("CHAT_BOT_SECRET") {
+        let mut secret = [0; 32];
+        secret.iter_mut().zip(shared.bytes()).for_each(|(byte, shared)| *byte = shared);
+        builder = builder.bot_secret(secret);
+    }
+    if let (Some(domain), Some(federation)) = (args.next(), args.next().and_then(|port| port.parse().ok())) {
+        let mut secret = [0; 32];
+        let shared = std::env::var("CHAT_FEDERATION_SECRET").unwrap_or_default();
+        secret.iter_mut().zip(shared.bytes()).for_each(|(byte, shared)| *byte = shared);
+        builder = builder.domain(domain).federation(SocketAddr::from(([0, 0, 0, 0], federation)));
+        for peer in args {
+            let Some((domain, addr)) = peer.split_once('=') else { continue };
+            let Ok(addr) = addr.parse() else { continue };
+            builder = builder.peer(domain.to_owned(), addr, secret);
+        }
+    }
+    run(builder).await
+}
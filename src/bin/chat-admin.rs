@@ -0,0 +1,89 @@
+use std::{
+    io::{self, BufRead, BufReader, Write},
+    net::Shutdown,
+    os::unix::net::UnixStream,
+    process::ExitCode,
+};
+
+const USAGE: &str = "usage: chat-admin [--socket <path>] [--json] <users | stats | kick <user> | reload | shutdown [reason]>";
+
+fn main() -> ExitCode {
+    let mut socket = "chat-admin.sock".to_owned();
+    let mut json = false;
+    let mut command = Vec::new();
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--socket" | "-s" => match args.next() {
+                Some(path) => socket = path,
+                None => return usage(),
+            },
+            "--json" => json = true,
+            "--help" | "-h" => return usage(),
+            _ => command.push(arg),
+        }
+    }
+    let Some((name, rest)) = command.split_first() else {
+        return usage();
+    };
+    let line = match json {
+        true => {
+            let argument = match name.as_str() {
+                "kick" => Some(("user", rest.join(" "))),
+                "shutdown" if !rest.is_empty() => Some(("reason", rest.join(" "))),
+                _ => None,
+            };
+            match argument {
+                Some((key, value)) => format!("{{\"command\":{},\"{key}\":{}}}", quote(name), quote(&value)),
+                None => format!("{{\"command\":{}}}", quote(name)),
+            }
+        }
+        false => command.join(" "),
+    };
+    match send(&socket, &line) {
+        Ok(true) => ExitCode::SUCCESS,
+        Ok(false) => ExitCode::FAILURE,
+        Err(e) => {
+            eprintln!("chat-admin: {socket}: {e}");
+            ExitCode::FAILURE
+        }
+    }
+}
+
+fn send(socket: &str, line: &str) -> io::Result<bool> {
+    let mut stream = UnixStream::connect(socket)?;
+    writeln!(stream, "{line}")?;
+    stream.shutdown(Shutdown::Write)?;
+    let mut ok = true;
+    let mut stdout = io::stdout().lock();
+    for line in BufReader::new(stream).lines() {
+        let line = line?;
+        if line == "failed" || line.starts_with("error:") || line.contains("\"ok\":false") {
+            ok = false;
+        }
+        if !line.is_empty() {
+            writeln!(stdout, "{line}")?;
+        }
+    }
+    Ok(ok)
+}
+
+fn quote(value: &str) -> String {
+    let mut quoted = String::with_capacity(value.len() + 2);
+    quoted.push('"');
+    for c in value.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            c if c.is_control() => quoted.push_str(&format!("\\u{:04x}", c as u32)),
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+fn usage() -> ExitCode {
+    eprintln!("{USAGE}");
+    ExitCode::FAILURE
+}
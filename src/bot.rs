@@ -0,0 +1,87 @@
+use std::sync::{
+    atomic::{AtomicU64, Ordering},
+    Arc,
+};
+
+use tokio::sync::mpsc::{self, UnboundedReceiver, UnboundedSender};
+
+use crate::protocol::{Delivery, Notice, Target};
+
+pub trait Bot<M>: Send + 'static {
+    fn start(&mut self, _: Outbox<M>) {}
+
+    fn message(&mut self, delivery: Delivery, message: M, outbox: &Outbox<M>);
+
+    fn notice(&mut self, _: Notice, _: &Outbox<M>) {}
+}
+
+pub trait Bots<M> {
+    fn collect(self, bots: &mut Vec<(String, Box<dyn Bot<M>>)>);
+}
+
+impl<M> Bots<M> for () {
+    fn collect(self, _: &mut Vec<(String, Box<dyn Bot<M>>)>) {}
+}
+
+impl<M, B: Bots<M>, T: Bot<M>> Bots<M> for (B, (String, T)) {
+    fn collect(self, bots: &mut Vec<(String, Box<dyn Bot<M>>)>) {
+        let (rest, (name, bot)) = self;
+        rest.collect(bots);
+        bots.push((name, Box::new(bot)));
+    }
+}
+
+pub struct Outbox<M> {
+    seq: Arc<AtomicU64>,
+    sender: UnboundedSender<(u64, Target, M)>,
+}
+
+impl<M> Outbox<M> {
+    pub(crate) fn new() -> (Self, UnboundedReceiver<(u64, Target, M)>) {
+        let (sender, receiver) = mpsc::unbounded_channel();
+        (Self { seq: Default::default(), sender }, receiver)
+    }
+
+    pub fn send(&self, target: Target, message: M) -> u64 {
+        let seq = self.seq.fetch_add(1, Ordering::Relaxed) + 1;
+        let _ = self.sender.send((seq, target, message));
+        seq
+    }
+}
+
+impl<M> Clone for Outbox<M> {
+    fn clone(&self) -> Self {
+        Self {
+            seq: self.seq.clone(),
+            sender: self.sender.clone(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct Quiet;
+
+    impl Bot<String> for Quiet {
+        fn message(&mut self, _: Delivery, _: String, _: &Outbox<String>) {}
+    }
+
+    #[test]
+    fn bots_are_collected_in_registration_order() {
+        let mut bots = Vec::new();
+        (((), ("first".to_owned(), Quiet)), ("second".to_owned(), Quiet)).collect(&mut bots);
+        assert_eq!(bots.iter().map(|(name, _)| name.as_str()).collect::<Vec<_>>(), ["first", "second"]);
+    }
+
+    #[test]
+    fn outbox_clones_share_sequence_numbers() {
+        let (outbox, mut receiver) = Outbox::new();
+        let clone = outbox.clone();
+        assert_eq!(outbox.send(Target::User("alice".to_owned()), "one".to_owned()), 1);
+        assert_eq!(clone.send(Target::Room("lobby".to_owned()), "two".to_owned()), 2);
+        assert_eq!(receiver.try_recv().unwrap(), (1, Target::User("alice".to_owned()), "one".to_owned()));
+        assert_eq!(receiver.try_recv().unwrap(), (2, Target::Room("lobby".to_owned()), "two".to_owned()));
+    }
+}
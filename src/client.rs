@@ -1,30 +1,72 @@
-use std::marker::PhantomData;
-use futures::FutureExt;
+use std::{
+    collections::HashMap,
+    marker::PhantomData,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant, SystemTime},
+};
+use futures::{
+    channel::mpsc::{unbounded, UnboundedReceiver, UnboundedSender},
+    FutureExt,
+};
 
 use tokio::{
     io,
     net::{
+        self,
         TcpStream,
         ToSocketAddrs,
     },
     sync::mpsc,
+    task::{self, JoinHandle},
 };
 
 use crate::{
-    handle::Handle,
+    handle::{
+        Handle,
+        Overflow,
+        Receiver,
+    },
     message::Message,
+    mux::{
+        Closed,
+        Encoded,
+        Mux,
+        MuxError,
+        BULK,
+        CHAT,
+        CONTROL,
+    },
+    protocol::{
+        Cursor,
+        Delivery,
+        Envelope,
+        HistoryEntry,
+        MessageId,
+        Notice,
+        Presence,
+        Reason,
+        Request,
+        Status,
+        Target,
+    },
     stream::{
+        self,
         BlockStream,
         ReadError,
         WriteError,
     },
     serialization::{
+        self,
         Serializable,
         Deserializable,
         Deserializer,
     },
 };
-use crate::Uninitialized;
+use crate::{
+    CipherSuite,
+    KeyExchange,
+    Uninitialized,
+};
 
 pub struct Builder<A, N, P, F, W, M> {
     addr: A,
@@ -32,9 +74,107 @@ pub struct Builder<A, N, P, F, W, M> {
     password: P,
     first: F,
     writer: W,
+    options: Options,
     _marker: PhantomData<M>,
 }
 
+pub enum Command<M> {
+    Send(Target, M),
+    Tracked(Target, M, UnboundedSender<Result<Receipt, Reason>>),
+    Request(Request),
+}
+
+impl<M> Command<M> {
+    pub fn tracked(target: Target, message: M) -> (Self, Receipts) {
+        let (sender, receiver) = unbounded();
+        (Self::Tracked(target, message, sender), receiver)
+    }
+
+    pub fn read(delivery: &Delivery) -> Self {
+        Self::Request(Request::Read {
+            id: delivery.id,
+            from: delivery.origin.sender().to_owned(),
+        })
+    }
+
+    pub fn subscribe(user: String) -> Self {
+        Self::Request(Request::Subscribe { user, subscribed: true })
+    }
+
+    pub fn unsubscribe(user: String) -> Self {
+        Self::Request(Request::Subscribe { user, subscribed: false })
+    }
+
+    pub fn presence(presence: Presence, text: Option<String>) -> Self {
+        Self::Request(Request::Presence { presence, text })
+    }
+
+    pub fn history(with: Target, cursor: Cursor, limit: usize) -> Self {
+        Self::Request(Request::History { with, cursor, limit: limit as u64 })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Receipt {
+    pub id: MessageId,
+    pub user: String,
+    pub status: Status,
+}
+
+pub type Receipts = UnboundedReceiver<Result<Receipt, Reason>>;
+
+// statuses come in shortly after a message is accepted, receipts still open after this are given up on
+const RECEIPT_LIFETIME: Duration = Duration::from_secs(600);
+
+#[derive(Debug)]
+pub enum Event<M> {
+    Message(Delivery, M),
+    Notice(Notice),
+    Rejected(Target, Reason),
+    Presence {
+        user: String,
+        presence: Presence,
+        text: Option<String>,
+    },
+    Shutdown {
+        reason: Option<String>,
+        reconnect: Option<Duration>,
+    },
+    History {
+        with: Target,
+        messages: Vec<(Delivery, SystemTime, M)>,
+        more: bool,
+    },
+}
+
+#[derive(Default)]
+struct Options {
+    stream: stream::Config,
+    queue: Option<(usize, Overflow)>,
+    tickets: Option<TicketStore>,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct TicketStore(Arc<Mutex<Option<Vec<u8>>>>);
+
+impl TicketStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn get(&self) -> Option<Vec<u8>> {
+        self.0.lock().unwrap().clone()
+    }
+
+    pub fn set(&self, ticket: Vec<u8>) {
+        *self.0.lock().unwrap() = Some(ticket);
+    }
+
+    pub fn take(&self) -> Option<Vec<u8>> {
+        self.0.lock().unwrap().take()
+    }
+}
+
 impl Builder<Uninitialized, Uninitialized, Uninitialized, Uninitialized, Uninitialized, Uninitialized> {
     pub fn new<M>() -> Builder<Uninitialized, Uninitialized, Uninitialized, Uninitialized, Uninitialized, M> {
         Builder {
@@ -43,6 +183,7 @@ impl Builder<Uninitialized, Uninitialized, Uninitialized, Uninitialized, Uniniti
             password: Uninitialized,
             first: Uninitialized,
             writer: Uninitialized,
+            options: Default::default(),
             _marker: PhantomData,
         }
     }
@@ -51,7 +192,7 @@ impl Builder<Uninitialized, Uninitialized, Uninitialized, Uninitialized, Uniniti
 impl<N, P, F, W, M> Builder<Uninitialized, N, P, F, W, M> {
     pub fn addr<A: ToSocketAddrs>(self, addr: A) -> Builder<A, N, P, F, W, M> {
         let Self {
-            addr: _, name, password, first, writer, _marker
+            addr: _, name, password, first, writer, options, _marker
         } = self;
         Builder {
             addr,
@@ -59,6 +200,7 @@ impl<N, P, F, W, M> Builder<Uninitialized, N, P, F, W, M> {
             password,
             first,
             writer,
+            options,
             _marker,
         }
     }
@@ -67,7 +209,7 @@ impl<N, P, F, W, M> Builder<Uninitialized, N, P, F, W, M> {
 impl<A, P, F, W, M> Builder<A, Uninitialized, P, F, W, M> {
     pub fn name(self, name: String) -> Builder<A, String, P, F, W, M> {
         let Self {
-            addr, name: _, password, first, writer, _marker
+            addr, name: _, password, first, writer, options, _marker
         } = self;
         Builder {
             addr,
@@ -75,6 +217,7 @@ impl<A, P, F, W, M> Builder<A, Uninitialized, P, F, W, M> {
             password,
             first,
             writer,
+            options,
             _marker,
         }
     }
@@ -83,7 +226,7 @@ impl<A, P, F, W, M> Builder<A, Uninitialized, P, F, W, M> {
 impl<A, N, F, W, M> Builder<A, N, Uninitialized, F, W, M> {
     pub fn password(self, password: Vec<u8>) -> Builder<A, N, Vec<u8>, F, W, M> {
         let Self {
-            addr, name, password: _, first, writer, _marker
+            addr, name, password: _, first, writer, options, _marker
         } = self;
         Builder {
             addr,
@@ -91,6 +234,7 @@ impl<A, N, F, W, M> Builder<A, N, Uninitialized, F, W, M> {
             password,
             first,
             writer,
+            options,
             _marker,
         }
     }
@@ -99,7 +243,7 @@ impl<A, N, F, W, M> Builder<A, N, Uninitialized, F, W, M> {
 impl<A, N, P, W, M> Builder<A, N, P, Uninitialized, W, M> {
     pub fn first(self, first: bool) -> Builder<A, N, P, bool, W, M> {
         let Self {
-            addr, name, password, first: _, writer, _marker
+            addr, name, password, first: _, writer, options, _marker
         } = self;
         Builder {
             addr,
@@ -107,13 +251,14 @@ impl<A, N, P, W, M> Builder<A, N, P, Uninitialized, W, M> {
             password,
             first,
             writer,
+            options,
             _marker,
         }
     }
 
     pub fn not_first(self) -> Builder<A, N, P, bool, W, M> {
         let Self {
-            addr, name, password, first: _, writer, _marker
+            addr, name, password, first: _, writer, options, _marker
         } = self;
         Builder {
             addr,
@@ -121,15 +266,16 @@ impl<A, N, P, W, M> Builder<A, N, P, Uninitialized, W, M> {
             password,
             first: false,
             writer,
+            options,
             _marker,
         }
     }
 }
 
 impl<A, N, P, F, M> Builder<A, N, P, F, Uninitialized, M> {
-    pub fn writer<W: FnMut(String, M) + Send + 'static>(self, writer: W) -> Builder<A, N, P, F, W, M> {
+    pub fn writer<W: FnMut(Event<M>) + Send + 'static>(self, writer: W) -> Builder<A, N, P, F, W, M> {
         let Self {
-            addr, name, password, first, writer: _, _marker
+            addr, name, password, first, writer: _, options, _marker
         } = self;
         Builder {
             addr,
@@ -137,16 +283,54 @@ impl<A, N, P, F, M> Builder<A, N, P, F, Uninitialized, M> {
             password,
             first,
             writer,
+            options,
             _marker,
         }
     }
 }
 
-impl<A: ToSocketAddrs, W: FnMut(String, M) + Send + 'static, M: for<'s> Message<Serializer<'s>: Send, Deserializer: Send> + Send + Sync + 'static> Builder<A, String, Vec<u8>, bool, W, M> where <<M as Deserializable>::Deserializer as Deserializer<M>>::UpdateError: Send, <<M as Deserializable>::Deserializer as Deserializer<M>>::FinalizeError: Send {
+impl<A, N, P, F, W, M> Builder<A, N, P, F, W, M> {
+    pub fn cipher_suites<I: IntoIterator<Item=CipherSuite>>(mut self, suites: I) -> Self {
+        self.options.stream.suites(suites);
+        self
+    }
+
+    pub fn key_exchanges<I: IntoIterator<Item=KeyExchange>>(mut self, exchanges: I) -> Self {
+        self.options.stream.exchanges(exchanges);
+        self
+    }
+
+    pub fn keepalive(mut self, interval: Duration) -> Self {
+        self.options.stream.keepalive(interval);
+        self
+    }
+
+    pub fn idle_timeout(mut self, timeout: Duration) -> Self {
+        self.options.stream.idle_timeout(timeout);
+        self
+    }
+
+    pub fn read_timeout(mut self, timeout: Duration) -> Self {
+        self.options.stream.read_timeout(timeout);
+        self
+    }
+
+    pub fn queue(mut self, capacity: usize, overflow: Overflow) -> Self {
+        self.options.queue = Some((capacity, overflow));
+        self
+    }
+
+    pub fn tickets(mut self, tickets: TicketStore) -> Self {
+        self.options.tickets = Some(tickets);
+        self
+    }
+}
+
+impl<A: ToSocketAddrs, W: FnMut(Event<M>) + Send + 'static, M: for<'s> Message<Serializer<'s>: Send, Deserializer: Send> + Send + Sync + 'static> Builder<A, String, Vec<u8>, bool, W, M> where <<M as Deserializable>::Deserializer as Deserializer<M>>::UpdateError: Send, <<M as Deserializable>::Deserializer as Deserializer<M>>::FinalizeError: Send {
     pub async fn connect<
         const N: usize,
-    >(self) -> Result<Handle<(String, M), Result<(), LoopError<M>>>, InitError> where [(); N + 16]: {
-        let Self { addr, name, password, first, mut writer, .. } = self;
+    >(self) -> Result<Handle<Command<M>, Result<(), LoopError<M>>>, InitError> where [(); N + 16]: {
+        let Self { addr, name, password, first, mut writer, options, .. } = self;
 
         async fn write_react<const N: usize, T: Serializable>(stream: &mut BlockStream<N>, message: Option<T>) -> Result<(), InitError> where [(); N + 16]: {
             if let Some(message) = message {
@@ -155,35 +339,158 @@ impl<A: ToSocketAddrs, W: FnMut(String, M) + Send + 'static, M: for<'s> Message<
             Ok(stream.read_block::<Result<(), ()>>().await??)
         }
 
-        let mut stream = BlockStream::<N>::new(TcpStream::connect(addr).await?).await?;
-
-        write_react(&mut stream, Some([if first { 1 } else { 0 }].as_slice())).await?;
-        write_react(&mut stream, Some(name)).await?;
-        write_react(&mut stream, Some(password.as_slice())).await?;
-        write_react(&mut stream, None::<()>).await?;
-
-        Ok(Handle::new(|mut receiver: mpsc::UnboundedReceiver<(String, M)>| async move {
-            loop {
-                futures::select! {
-                m = async {
-                    let user = stream.read_block::<String>().await.map_err(|e| ReadUser(e))?;
-                    let message = stream.read_block::<M>().await.map_err(|e| ReadMessage(e))?;
-                    Ok::<_, LoopError<M>>((user, message))
-                }.fuse() => {
-                    let (user, message) = m?;
-                    writer(user, message)
-                },
-                m = receiver.recv().fuse() => {
-                    let Some((user, message)) = m else {
-                        break
+        let addrs = net::lookup_host(addr).await?.collect::<Vec<_>>();
+
+        let resumed = match options.tickets.as_ref().and_then(|tickets| Some((tickets, tickets.take()?))) {
+            None => None,
+            Some((tickets, ticket)) => {
+                let connected = async {
+                    Ok::<_, InitError>(BlockStream::<N>::connect(TcpStream::connect(addrs.as_slice()).await?, &options.stream).await?)
+                }.await;
+                // the ticket was never presented, so it is still good for the next attempt
+                let mut stream = match connected {
+                    Ok(stream) => stream,
+                    Err(e) => {
+                        tickets.set(ticket);
+                        return Err(e);
+                    }
+                };
+                let resumed = async {
+                    write_react(&mut stream, Some([2].as_slice())).await?;
+                    write_react(&mut stream, Some(ticket)).await?;
+                    write_react(&mut stream, None::<()>).await
+                }.await;
+                resumed.ok().map(|()| stream)
+            }
+        };
+
+        let mut stream = match resumed {
+            Some(stream) => stream,
+            None => {
+                let mut stream = BlockStream::<N>::connect(TcpStream::connect(addrs.as_slice()).await?, &options.stream).await?;
+                write_react(&mut stream, Some([if first { 1 } else { 0 }].as_slice())).await?;
+                write_react(&mut stream, Some(name)).await?;
+                write_react(&mut stream, Some(password.as_slice())).await?;
+                write_react(&mut stream, None::<()>).await?;
+                stream
+            }
+        };
+
+        let ticket = stream.read_block::<Option<Vec<u8>>>().await.map_err(|e| InitError::Ticket(e))?;
+        if let (Some(tickets), Some(ticket)) = (&options.tickets, ticket) {
+            tickets.set(ticket);
+        }
+
+        let (block_reader, block_writer) = stream.split();
+        let mux = Mux::new(block_reader, block_writer, &options.stream);
+        let (control, mut control_incoming) = mux.channel(CONTROL);
+        let (chat, chat_incoming) = mux.channel(CHAT);
+        let (bulk, bulk_incoming) = mux.channel(BULK);
+
+        let (capacity, overflow) = options.queue.map_or((None, Overflow::Block), |(capacity, overflow)| (Some(capacity), overflow));
+        Ok(Handle::with_capacity(capacity, overflow, |mut receiver: Receiver<Command<M>>| async move {
+            let (incoming_sender, mut incoming) = mpsc::unbounded_channel();
+            let reading = [chat_incoming, bulk_incoming].map(|mut channel| {
+                let incoming_sender = incoming_sender.clone();
+                task::spawn(async move {
+                    loop {
+                        let m = async {
+                            let delivery = channel.recv::<Delivery>().await.map_err(|e| ReadDelivery(e))?;
+                            let message = channel.recv::<M>().await.map_err(|e| ReadMessage(e))?;
+                            Ok::<_, LoopError<M>>(Event::Message(delivery, message))
+                        }.await;
+                        let last = m.is_err();
+                        if incoming_sender.send(m).is_err() || last {
+                            break;
+                        }
+                    }
+                })
+            });
+            let notices = task::spawn(async move {
+                loop {
+                    let m = match control_incoming.recv::<Notice>().await {
+                        Ok(notice) => Ok(Event::Notice(notice)),
+                        Err(ReadError::Closed) => break,
+                        Err(e) => Err(LoopError::ReadNotice(e)),
                     };
-                    stream.write_block(Some(user)).await?;
-                    stream.write_block(message).await?;
+                    let last = m.is_err();
+                    if incoming_sender.send(m).is_err() || last {
+                        break;
+                    }
                 }
+            });
+
+            let result = async {
+                let mut seq = 0;
+                let mut pending = HashMap::<u64, (Target, Option<UnboundedSender<Result<Receipt, Reason>>>)>::new();
+                // dropped with this loop, which ends every open receipt stream on disconnect
+                let mut tracked = HashMap::<MessageId, (Instant, UnboundedSender<Result<Receipt, Reason>>)>::new();
+                loop {
+                    futures::select! {
+                        m = incoming.recv().fuse() => match m {
+                            Some(Ok(Event::Notice(Notice::Rejected { seq, reason }))) => if let Some((target, receipts)) = pending.remove(&seq) {
+                                if let Some(receipts) = receipts {
+                                    let _ = receipts.unbounded_send(Err(reason));
+                                }
+                                writer(Event::Rejected(target, reason))
+                            },
+                            Some(Ok(Event::Notice(Notice::Presence { user, presence, text }))) => writer(Event::Presence { user, presence, text }),
+                            Some(Ok(Event::Notice(Notice::Shutdown { reason, reconnect }))) => writer(Event::Shutdown { reason, reconnect }),
+                            Some(Ok(Event::Notice(Notice::History { with, entries, more }))) => writer(Event::History {
+                                with,
+                                messages: entries.into_iter()
+                                    .filter_map(|HistoryEntry { delivery, sent, message }| Some((delivery, sent, serialization::from_bytes::<M>(&message)?)))
+                                    .collect(),
+                                more,
+                            }),
+                            Some(Ok(event)) => {
+                                match &event {
+                                    Event::Notice(Notice::Accepted { seq, id }) => if let Some((_, Some(receipts))) = pending.remove(seq) {
+                                        tracked.retain(|_, (since, receipts)| since.elapsed() < RECEIPT_LIFETIME && !receipts.is_closed());
+                                        tracked.insert(*id, (Instant::now(), receipts));
+                                    },
+                                    Event::Notice(Notice::Status { id, user, status }) => {
+                                        let receipt = Receipt { id: *id, user: user.clone(), status: *status };
+                                        if tracked.get(id).is_some_and(|(_, receipts)| receipts.unbounded_send(Ok(receipt)).is_err()) {
+                                            tracked.remove(id);
+                                        }
+                                    }
+                                    _ => {}
+                                }
+                                writer(event)
+                            }
+                            Some(Err(e)) => break Err(e),
+                            None => break Ok::<_, LoopError<M>>(()),
+                        },
+                        m = receiver.recv().fuse() => {
+                            let (target, message, receipts) = match m {
+                                Some(Command::Send(target, message)) => (target, message, None),
+                                Some(Command::Tracked(target, message, receipts)) => (target, message, Some(receipts)),
+                                Some(Command::Request(request)) => {
+                                    control.send(request).await?;
+                                    continue;
+                                }
+                                None => break Ok(()),
+                            };
+                            seq += 1;
+                            pending.insert(seq, (target.clone(), receipts));
+                            let message = Encoded::new::<N, _>(message);
+                            let channel = if message.blocks() > 1 { &bulk } else { &chat };
+                            channel.send(Some(Envelope { seq, target })).await?;
+                            channel.send_encoded(message).await?;
+                        },
+                    }
+                }
+            }.await;
+            reading.iter().for_each(JoinHandle::abort);
+            notices.abort();
+            if result.is_ok() {
+                chat.send(None::<Envelope>).await?;
+                bulk.send(None::<Envelope>).await?;
             }
-            }
-            stream.write_block(None::<String>).await?;
-            Ok(())
+            let closed = mux.shutdown(None).await;
+            result?;
+            Ok(closed?)
         }))
     }
 }
@@ -193,6 +500,7 @@ pub enum InitError {
     Write(WriteError),
     Read(ReadError<Result<(), ()>>),
     LogIn,
+    Ticket(ReadError<Option<Vec<u8>>>),
     Io(io::Error),
 }
 
@@ -222,14 +530,22 @@ impl From<io::Error> for InitError {
 
 #[derive(Debug)]
 pub enum LoopError<M: Deserializable> {
-    ReadUser(ReadError<String>),
+    ReadDelivery(ReadError<Delivery>),
+    ReadNotice(ReadError<Notice>),
     ReadMessage(ReadError<M>),
-    Write(WriteError),
+    Mux(MuxError),
+    Closed,
 }
 
-impl<M: Deserializable> From<WriteError> for LoopError<M> {
-    fn from(value: WriteError) -> Self {
-        Self::Write(value)
+impl<M: Deserializable> From<MuxError> for LoopError<M> {
+    fn from(value: MuxError) -> Self {
+        Self::Mux(value)
+    }
+}
+
+impl<M: Deserializable> From<Closed> for LoopError<M> {
+    fn from(_: Closed) -> Self {
+        Self::Closed
     }
 }
 
@@ -241,10 +557,10 @@ impl<M: Deserializable> From<ReadMessage<M>> for LoopError<M> {
     }
 }
 
-struct ReadUser(ReadError<String>);
+struct ReadDelivery(ReadError<Delivery>);
 
-impl<M: Deserializable> From<ReadUser> for LoopError<M> {
-    fn from(ReadUser(value): ReadUser) -> Self {
-        Self::ReadUser(value)
+impl<M: Deserializable> From<ReadDelivery> for LoopError<M> {
+    fn from(ReadDelivery(value): ReadDelivery) -> Self {
+        Self::ReadDelivery(value)
     }
 }
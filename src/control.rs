@@ -0,0 +1,177 @@
+use std::{
+    collections::BTreeMap,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use serde_json::{json, Value};
+
+use tokio::{
+    io::{self, AsyncBufReadExt, AsyncWriteExt, BufReader},
+    net::UnixStream,
+    sync::mpsc::UnboundedSender,
+};
+
+use crate::{
+    protocol::{Admin, AdminReply, ConnectionStats, Session},
+    server::{peer_name, AdminRequest},
+};
+
+#[derive(Copy, Clone)]
+enum Format {
+    Text,
+    Json,
+}
+
+enum Command {
+    Users,
+    Stats,
+    Kick(String),
+    Reload,
+    Shutdown(Option<String>),
+}
+
+impl Command {
+    fn parse(line: &str) -> (Format, Result<Self, String>) {
+        match line.starts_with('{') {
+            true => (Format::Json, Self::json(line)),
+            false => (Format::Text, Self::text(line)),
+        }
+    }
+
+    fn text(line: &str) -> Result<Self, String> {
+        let (command, argument) = line.split_once(char::is_whitespace).unwrap_or((line, ""));
+        let argument = argument.trim();
+        match (command, argument) {
+            ("users", "") => Ok(Command::Users),
+            ("stats", "") => Ok(Command::Stats),
+            ("kick", "") => Err("kick needs a user".to_owned()),
+            ("kick", user) => Ok(Command::Kick(user.to_owned())),
+            ("reload", "") => Ok(Command::Reload),
+            ("shutdown", "") => Ok(Command::Shutdown(None)),
+            ("shutdown", reason) => Ok(Command::Shutdown(Some(reason.to_owned()))),
+            _ => Err(format!("unknown command: {line}")),
+        }
+    }
+
+    fn json(line: &str) -> Result<Self, String> {
+        let value = serde_json::from_str::<Value>(line).map_err(|e| e.to_string())?;
+        let string = |key: &str| value.get(key).and_then(Value::as_str).map(str::to_owned);
+        match string("command").as_deref() {
+            Some("users") => Ok(Command::Users),
+            Some("stats") => Ok(Command::Stats),
+            Some("kick") => string("user").map(Command::Kick).ok_or_else(|| "kick needs a user".to_owned()),
+            Some("reload") => Ok(Command::Reload),
+            Some("shutdown") => Ok(Command::Shutdown(string("reason"))),
+            Some(command) => Err(format!("unknown command: {command}")),
+            None => Err("missing command".to_owned()),
+        }
+    }
+
+    fn admin(self) -> Admin {
+        match self {
+            Command::Users => Admin::Sessions,
+            Command::Stats => Admin::Stats,
+            Command::Kick(user) => Admin::Kick(user),
+            Command::Reload => Admin::Reload,
+            Command::Shutdown(reason) => Admin::Shutdown { reason, reconnect: None },
+        }
+    }
+}
+
+pub(crate) async fn session(stream: UnixStream, requests: UnboundedSender<AdminRequest>) -> io::Result<()> {
+    let (reader, mut writer) = stream.into_split();
+    let mut lines = BufReader::new(reader).lines();
+    while let Some(line) = lines.next_line().await? {
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+        let (format, command) = Command::parse(line);
+        let reply = match command {
+            Ok(command) => {
+                let (request, reply) = AdminRequest::new(command.admin());
+                match requests.send(request) {
+                    Ok(()) => reply.await.map_err(|_| "server is shutting down".to_owned()),
+                    Err(_) => Err("server is shutting down".to_owned()),
+                }
+            }
+            Err(e) => Err(e),
+        };
+        writer.write_all(render(format, reply).as_bytes()).await?;
+    }
+    Ok(())
+}
+
+fn render(format: Format, reply: Result<AdminReply, String>) -> String {
+    match format {
+        Format::Text => text(reply),
+        Format::Json => format!("{}\n", json(reply)),
+    }
+}
+
+fn users(sessions: &[Session]) -> BTreeMap<&str, usize> {
+    let mut users = BTreeMap::new();
+    for session in sessions {
+        *users.entry(session.user.as_str()).or_default() += 1;
+    }
+    users
+}
+
+fn uptime(stats: &ConnectionStats) -> u64 {
+    SystemTime::now().duration_since(stats.connected).unwrap_or_default().as_secs()
+}
+
+fn text(reply: Result<AdminReply, String>) -> String {
+    let mut text = match reply {
+        Ok(AdminReply::Sessions(sessions)) => users(&sessions).into_iter()
+            .map(|(user, connections)| format!("{user} {connections}\n"))
+            .collect(),
+        Ok(AdminReply::Stats(stats)) => stats.iter()
+            .map(|stats| format!(
+                "{} {} {} up {}s sent {} ({} bytes) received {} ({} bytes)\n",
+                stats.session.connection,
+                stats.session.user,
+                peer_name(stats.session.addr),
+                uptime(stats),
+                stats.sent,
+                stats.bytes_sent,
+                stats.received,
+                stats.bytes_received,
+            ))
+            .collect(),
+        Ok(AdminReply::Done(true)) => "ok\n".to_owned(),
+        Ok(AdminReply::Done(false)) => "failed\n".to_owned(),
+        Ok(AdminReply::Failed(e)) | Err(e) => format!("error: {e}\n"),
+        Ok(reply) => format!("{reply:?}\n"),
+    };
+    text.push('\n');
+    text
+}
+
+fn json(reply: Result<AdminReply, String>) -> Value {
+    match reply {
+        Ok(AdminReply::Sessions(sessions)) => json!({
+            "users": users(&sessions).into_iter()
+                .map(|(user, connections)| json!({ "user": user, "connections": connections }))
+                .collect::<Vec<_>>(),
+        }),
+        Ok(AdminReply::Stats(stats)) => json!({
+            "connections": stats.iter()
+                .map(|stats| json!({
+                    "connection": stats.session.connection,
+                    "user": stats.session.user,
+                    "addr": peer_name(stats.session.addr),
+                    "connected": stats.connected.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs(),
+                    "uptime": uptime(stats),
+                    "sent": stats.sent,
+                    "received": stats.received,
+                    "bytes_sent": stats.bytes_sent,
+                    "bytes_received": stats.bytes_received,
+                }))
+                .collect::<Vec<_>>(),
+        }),
+        Ok(AdminReply::Done(ok)) => json!({ "ok": ok }),
+        Ok(AdminReply::Failed(e)) | Err(e) => json!({ "ok": false, "error": e }),
+        Ok(reply) => json!({ "ok": false, "error": format!("unexpected reply: {reply:?}") }),
+    }
+}
@@ -1,6 +1,8 @@
 use std::fmt::{Debug, Display, Formatter};
 use std::future::Future;
 use std::string::FromUtf8Error;
+use std::sync::OnceLock;
+use std::time::SystemTime;
 
 use futures::channel::oneshot;
 
@@ -18,6 +20,8 @@ use pbkdf2::password_hash::Error;
 use rand_core1::OsRng;
 use tokio::sync::mpsc::UnboundedReceiver;
 use crate::serialization::{Deserializable, Deserializer, Serializable};
+use crate::protocol::{Ban, Cursor, MessageId, Origin, RoomAction, Target};
+use crate::ticket::{Ticket, TicketId};
 
 pub enum DatabaseEvent {
     LogIn {
@@ -34,6 +38,52 @@ pub enum DatabaseEvent {
         name: String,
         channel: oneshot::Sender<User>,
     },
+    Resume {
+        ticket: Ticket,
+        channel: oneshot::Sender<User>,
+    },
+    Room {
+        action: RoomAction,
+        name: String,
+        user: User,
+        channel: oneshot::Sender<bool>,
+    },
+    RoomMembers {
+        name: String,
+        channel: oneshot::Sender<Vec<User>>,
+    },
+    Blocked {
+        user: User,
+        by: User,
+        channel: oneshot::Sender<bool>,
+    },
+    SetBlocked {
+        user: User,
+        by: User,
+        blocked: bool,
+        channel: oneshot::Sender<bool>,
+    },
+    Ban {
+        ban: Ban,
+        until: Option<SystemTime>,
+    },
+    Unban {
+        ban: Ban,
+        channel: oneshot::Sender<bool>,
+    },
+    Banned {
+        ban: Ban,
+        channel: oneshot::Sender<bool>,
+    },
+    StoreOffline {
+        user: User,
+        message: Offline,
+        limit: usize,
+    },
+    TakeOffline {
+        user: User,
+        channel: oneshot::Sender<Vec<Offline>>,
+    },
 }
 
 fn _f() {
@@ -48,15 +98,92 @@ pub enum DatabaseError {
     LogIn,
     CreateUser,
     GetUser,
+    Resume,
     Channel(User),
+    Reply,
 }
 
 impl DatabaseEvent {
+    pub fn name(&self) -> &'static str {
+        match self {
+            DatabaseEvent::LogIn { .. } => "log_in",
+            DatabaseEvent::CreateUser { .. } => "create_user",
+            DatabaseEvent::GetUser { .. } => "get_user",
+            DatabaseEvent::Resume { .. } => "resume",
+            DatabaseEvent::Room { .. } => "room",
+            DatabaseEvent::RoomMembers { .. } => "room_members",
+            DatabaseEvent::Blocked { .. } => "blocked",
+            DatabaseEvent::SetBlocked { .. } => "set_blocked",
+            DatabaseEvent::Ban { .. } => "ban",
+            DatabaseEvent::Unban { .. } => "unban",
+            DatabaseEvent::Banned { .. } => "banned",
+            DatabaseEvent::StoreOffline { .. } => "store_offline",
+            DatabaseEvent::TakeOffline { .. } => "take_offline",
+        }
+    }
+
     pub async fn execute<DB: DataBase>(self, db: &mut DB) -> Result<(), DatabaseError> {
         match self {
-            DatabaseEvent::LogIn { name, password, channel } => channel.send(db.log_in(name, &password).await.ok_or(DatabaseError::LogIn)?),
+            DatabaseEvent::LogIn { name, password, channel } => {
+                // unknown names cost a hash verification too, so they can not be told apart by timing
+                if db.user_from_username(&name).await.is_none() {
+                    let _ = Password::dummy().verify(&password);
+                    return Err(DatabaseError::LogIn);
+                }
+                channel.send(db.log_in(name, &password).await.ok_or(DatabaseError::LogIn)?)
+            }
             DatabaseEvent::CreateUser { name, password, channel } => channel.send(db.create_user(name, password).await.ok_or(DatabaseError::CreateUser)?),
             DatabaseEvent::GetUser { name, channel } => channel.send(db.user_from_username(&name).await.ok_or(DatabaseError::GetUser)?),
+            DatabaseEvent::Resume { ticket, channel } => {
+                if db.ticket_revoked(&ticket.id).await || db.tickets_revoked(&User::new(ticket.name.clone())).await.is_some_and(|before| ticket.issued <= before) {
+                    return Err(DatabaseError::Resume);
+                }
+                db.revoke_ticket(ticket.id, ticket.expires).await;
+                channel.send(db.user_from_username(&ticket.name).await.ok_or(DatabaseError::Resume)?)
+            }
+            DatabaseEvent::Room { action, name, user, channel } => {
+                let ok = match action {
+                    RoomAction::Create => db.create_room(name, user).await,
+                    RoomAction::Join => db.join_room(&name, user).await,
+                    RoomAction::Leave => db.leave_room(&name, &user).await,
+                };
+                return channel.send(ok).map_err(|_| DatabaseError::Reply);
+            }
+            DatabaseEvent::RoomMembers { name, channel } => {
+                let members = db.room_members(&name).await.unwrap_or_default();
+                return channel.send(members).map_err(|_| DatabaseError::Reply);
+            }
+            DatabaseEvent::Blocked { user, by, channel } => {
+                let blocked = db.blocked(&user, &by).await;
+                return channel.send(blocked).map_err(|_| DatabaseError::Reply);
+            }
+            DatabaseEvent::SetBlocked { user, by, blocked, channel } => {
+                let ok = db.set_blocked(user, by, blocked).await;
+                return channel.send(ok).map_err(|_| DatabaseError::Reply);
+            }
+            DatabaseEvent::Ban { ban, until } => {
+                if let Ban::User(name) = &ban {
+                    db.revoke_tickets(User::new(name.clone()), SystemTime::now()).await;
+                }
+                db.ban(ban, until).await;
+                return Ok(());
+            }
+            DatabaseEvent::Unban { ban, channel } => {
+                let ok = db.unban(&ban).await;
+                return channel.send(ok).map_err(|_| DatabaseError::Reply);
+            }
+            DatabaseEvent::Banned { ban, channel } => {
+                let banned = db.banned(&ban).await;
+                return channel.send(banned).map_err(|_| DatabaseError::Reply);
+            }
+            DatabaseEvent::StoreOffline { user, message, limit } => {
+                db.store_offline(user, message, limit).await;
+                return Ok(());
+            }
+            DatabaseEvent::TakeOffline { user, channel } => {
+                let messages = db.take_offline(&user).await;
+                return channel.send(messages).map_err(|_| DatabaseError::Reply);
+            }
         }.map_err(|e| DatabaseError::Channel(e))
     }
 }
@@ -67,6 +194,16 @@ impl Debug for DatabaseEvent {
             DatabaseEvent::LogIn { name, .. } => f.debug_struct("LogIn").field("name", name).finish(),
             DatabaseEvent::CreateUser { name, .. } => f.debug_struct("CreateUser").field("name", name).finish(),
             DatabaseEvent::GetUser { name, .. } => f.debug_struct("GetUser").field("name", name).finish(),
+            DatabaseEvent::Resume { ticket, .. } => f.debug_struct("Resume").field("name", &ticket.name).finish(),
+            DatabaseEvent::Room { action, name, user, .. } => f.debug_struct("Room").field("action", action).field("name", name).field("user", user).finish(),
+            DatabaseEvent::RoomMembers { name, .. } => f.debug_struct("RoomMembers").field("name", name).finish(),
+            DatabaseEvent::Blocked { user, by, .. } => f.debug_struct("Blocked").field("user", user).field("by", by).finish(),
+            DatabaseEvent::SetBlocked { user, by, blocked, .. } => f.debug_struct("SetBlocked").field("user", user).field("by", by).field("blocked", blocked).finish(),
+            DatabaseEvent::Ban { ban, until } => f.debug_struct("Ban").field("ban", ban).field("until", until).finish(),
+            DatabaseEvent::Unban { ban, .. } => f.debug_struct("Unban").field("ban", ban).finish(),
+            DatabaseEvent::Banned { ban, .. } => f.debug_struct("Banned").field("ban", ban).finish(),
+            DatabaseEvent::StoreOffline { user, message, limit } => f.debug_struct("StoreOffline").field("user", user).field("origin", &message.origin).field("limit", limit).finish(),
+            DatabaseEvent::TakeOffline { user, .. } => f.debug_struct("TakeOffline").field("user", user).finish(),
         }
     }
 }
@@ -75,12 +212,99 @@ pub trait DataBase: Sync + 'static {
     type LogInFuture: Future<Output=Option<User>> + Send;
     type CreateUserFuture: Future<Output=Option<User>> + Send;
     type UserFromUsernameFuture: Future<Output=Option<User>> + Send;
+    type TicketRevokedFuture: Future<Output=bool> + Send;
+    type RevokeTicketFuture: Future<Output=()> + Send;
+    type CreateRoomFuture: Future<Output=bool> + Send;
+    type JoinRoomFuture: Future<Output=bool> + Send;
+    type LeaveRoomFuture: Future<Output=bool> + Send;
+    type RoomMembersFuture: Future<Output=Option<Vec<User>>> + Send;
+    type BlockedFuture: Future<Output=bool> + Send;
+    type SetBlockedFuture: Future<Output=bool> + Send;
+    type BanFuture: Future<Output=()> + Send;
+    type UnbanFuture: Future<Output=bool> + Send;
+    type BannedFuture: Future<Output=bool> + Send;
+    type StoreOfflineFuture: Future<Output=()> + Send;
+    type TakeOfflineFuture: Future<Output=Vec<Offline>> + Send;
+    type RevokeTicketsFuture: Future<Output=()> + Send;
+    type TicketsRevokedFuture: Future<Output=Option<SystemTime>> + Send;
 
     fn log_in(&mut self, name: String, password: &[u8]) -> Self::LogInFuture;
 
     fn create_user(&mut self, name: String, password: Password) -> Self::CreateUserFuture;
 
     fn user_from_username(&mut self, name: &str) -> Self::UserFromUsernameFuture;
+
+    fn ticket_revoked(&mut self, id: &TicketId) -> Self::TicketRevokedFuture;
+
+    fn revoke_ticket(&mut self, id: TicketId, expires: SystemTime) -> Self::RevokeTicketFuture;
+
+    fn create_room(&mut self, name: String, owner: User) -> Self::CreateRoomFuture;
+
+    fn join_room(&mut self, name: &str, user: User) -> Self::JoinRoomFuture;
+
+    fn leave_room(&mut self, name: &str, user: &User) -> Self::LeaveRoomFuture;
+
+    fn room_members(&mut self, name: &str) -> Self::RoomMembersFuture;
+
+    fn blocked(&mut self, user: &User, by: &User) -> Self::BlockedFuture;
+
+    fn set_blocked(&mut self, user: User, by: User, blocked: bool) -> Self::SetBlockedFuture;
+
+    fn ban(&mut self, ban: Ban, until: Option<SystemTime>) -> Self::BanFuture;
+
+    fn unban(&mut self, ban: &Ban) -> Self::UnbanFuture;
+
+    fn banned(&mut self, ban: &Ban) -> Self::BannedFuture;
+
+    fn store_offline(&mut self, user: User, message: Offline, limit: usize) -> Self::StoreOfflineFuture;
+
+    fn take_offline(&mut self, user: &User) -> Self::TakeOfflineFuture;
+
+    // revokes every ticket issued to the user up to `before`, call it when the user's password changes
+    fn revoke_tickets(&mut self, user: User, before: SystemTime) -> Self::RevokeTicketsFuture;
+
+    fn tickets_revoked(&mut self, user: &User) -> Self::TicketsRevokedFuture;
+}
+
+#[derive(Debug)]
+pub enum HistoryEvent {
+    Record(Record),
+    Query {
+        user: User,
+        with: Target,
+        cursor: Cursor,
+        limit: usize,
+        channel: oneshot::Sender<Vec<Record>>,
+    },
+}
+
+impl HistoryEvent {
+    pub async fn execute<S: History>(self, history: &mut S) {
+        match self {
+            HistoryEvent::Record(record) => history.record(record).await,
+            HistoryEvent::Query { user, with, cursor, limit, channel } => {
+                let _ = channel.send(history.query(&user, &with, cursor, limit).await);
+            }
+        }
+    }
+}
+
+pub trait History: Sync + 'static {
+    type RecordFuture: Future<Output=()> + Send;
+    type QueryFuture: Future<Output=Vec<Record>> + Send;
+
+    fn record(&mut self, record: Record) -> Self::RecordFuture;
+
+    fn query(&mut self, user: &User, with: &Target, cursor: Cursor, limit: usize) -> Self::QueryFuture;
+}
+
+#[derive(Debug, Clone)]
+pub struct Record {
+    pub id: MessageId,
+    pub from: User,
+    pub to: Target,
+    pub message: Vec<u8>,
+    pub sent: SystemTime,
 }
 
 #[derive(Debug, Clone, PartialEq, Eq, Hash)]
@@ -141,6 +365,14 @@ impl Deserializable for User {
     }
 }
 
+#[derive(Debug, Clone)]
+pub struct Offline {
+    pub id: MessageId,
+    pub origin: Origin,
+    pub message: Vec<u8>,
+    pub stored: SystemTime,
+}
+
 pub struct Password {
     hash: String,
 }
@@ -152,6 +384,11 @@ impl Password {
         Self { hash }
     }
 
+    pub(crate) fn dummy() -> &'static Password {
+        static DUMMY: OnceLock<Password> = OnceLock::new();
+        DUMMY.get_or_init(|| Password::new(&[]))
+    }
+
     pub fn verify(&self, password: &[u8]) -> Result<(), Error> {
         let hash = PasswordHash::new(&self.hash).unwrap();
         Pbkdf2.verify_password(password, &hash)
@@ -0,0 +1,274 @@
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+};
+
+use hkdf::Hkdf;
+
+use rand_core1::{
+    OsRng,
+    RngCore,
+};
+
+use sha2::Sha256;
+
+use tokio::io;
+
+use crate::{
+    mux::MuxError,
+    protocol::MessageId,
+    serialization::{
+        wire::{wire, Wire, WireReader, WireWriter},
+        Deserializable,
+    },
+    stream::{BlockStream, ReadError, WriteError},
+};
+
+#[derive(Clone)]
+pub(crate) struct Peer {
+    pub addr: SocketAddr,
+    pub secret: [u8; 32],
+}
+
+#[derive(Default)]
+pub(crate) struct Federation {
+    pub domain: Option<String>,
+    pub listen: Option<SocketAddr>,
+    pub peers: HashMap<String, Peer>,
+}
+
+pub(crate) enum Address {
+    Local(String),
+    Remote {
+        user: String,
+        domain: String,
+    },
+    Unknown,
+}
+
+impl Federation {
+    pub fn resolve(&self, name: String) -> Address {
+        let Some((user, domain)) = name.rsplit_once('@') else {
+            return Address::Local(name);
+        };
+        if self.domain.as_deref() == Some(domain) {
+            Address::Local(user.to_owned())
+        } else if self.domain.is_some() && self.peers.contains_key(domain) {
+            Address::Remote { user: user.to_owned(), domain: domain.to_owned() }
+        } else {
+            Address::Unknown
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub(crate) struct Relay {
+    pub id: MessageId,
+    pub from: String,
+    pub to: String,
+    pub message: Vec<u8>,
+}
+
+impl Wire for Relay {
+    fn write(&self, writer: &mut WireWriter) {
+        writer.u64(self.id.0).str(&self.from).str(&self.to).bytes(&self.message);
+    }
+
+    fn read(reader: &mut WireReader<'_>) -> Option<Self> {
+        Some(Relay {
+            id: MessageId(reader.u64()?),
+            from: reader.string()?,
+            to: reader.string()?,
+            message: reader.bytes()?.to_vec(),
+        })
+    }
+}
+
+wire!(Relay);
+
+#[derive(Debug)]
+pub(crate) enum LinkError {
+    Io(io::Error),
+    Write(WriteError),
+    Read,
+    UnknownPeer(String),
+    Authentication,
+    Timeout,
+    Closed,
+    Mux(MuxError),
+}
+
+impl From<io::Error> for LinkError {
+    fn from(value: io::Error) -> Self {
+        Self::Io(value)
+    }
+}
+
+impl From<WriteError> for LinkError {
+    fn from(value: WriteError) -> Self {
+        Self::Write(value)
+    }
+}
+
+impl<T: Deserializable> From<ReadError<T>> for LinkError {
+    fn from(_: ReadError<T>) -> Self {
+        Self::Read
+    }
+}
+
+impl From<MuxError> for LinkError {
+    fn from(value: MuxError) -> Self {
+        Self::Mux(value)
+    }
+}
+
+fn nonce() -> Vec<u8> {
+    let mut nonce = vec![0; 32];
+    OsRng.fill_bytes(&mut nonce);
+    nonce
+}
+
+// the exporter ties the proof to this block stream session, so it can not be relayed through a second one
+fn proof(secret: &[u8; 32], role: &[u8], nonce: &[u8], exporter: &[u8; 32]) -> Vec<u8> {
+    let mut proof = vec![0; 32];
+    Hkdf::<Sha256>::new(Some(nonce), secret)
+        .expand(&[b"chat federation ".as_slice(), role, exporter].concat(), &mut proof)
+        .expect("32 bytes is a valid hkdf output length");
+    proof
+}
+
+fn verify(expected: &[u8], actual: &[u8]) -> Result<(), LinkError> {
+    let diff = expected.iter().zip(actual).fold(0, |diff, (a, b)| diff | (a ^ b));
+    match expected.len() == actual.len() && diff == 0 {
+        true => Ok(()),
+        false => Err(LinkError::Authentication),
+    }
+}
+
+pub(crate) async fn dial<const N: usize>(
+    stream: &mut BlockStream<N>,
+    domain: &str,
+    peer: &Peer,
+) -> Result<(), LinkError> where [(); N + 16]: {
+    let challenge = nonce();
+    let exporter = stream.exporter();
+    stream.write_block(domain).await?;
+    stream.write_block(challenge.as_slice()).await?;
+    let nonce = stream.read_limited::<Vec<u8>>(1).await?;
+    verify(&proof(&peer.secret, b"accept", &challenge, &exporter), &stream.read_limited::<Vec<u8>>(1).await?)?;
+    stream.write_block(proof(&peer.secret, b"dial", &nonce, &exporter)).await?;
+    Ok(())
+}
+
+pub(crate) async fn accept<const N: usize>(
+    stream: &mut BlockStream<N>,
+    peers: &HashMap<String, Peer>,
+) -> Result<String, LinkError> where [(); N + 16]: {
+    let exporter = stream.exporter();
+    let domain = stream.read_limited::<String>(1).await?;
+    let nonce = stream.read_limited::<Vec<u8>>(1).await?;
+    let peer = peers.get(&domain).ok_or_else(|| LinkError::UnknownPeer(domain.clone()))?;
+    let challenge = self::nonce();
+    stream.write_block(challenge.as_slice()).await?;
+    stream.write_block(proof(&peer.secret, b"accept", &nonce, &exporter)).await?;
+    verify(&proof(&peer.secret, b"dial", &challenge, &exporter), &stream.read_limited::<Vec<u8>>(1).await?)?;
+    Ok(domain)
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::net::{TcpListener, TcpStream};
+
+    use crate::{serialization, stream::Config};
+
+    use super::*;
+
+    const SECRET: [u8; 32] = [7; 32];
+
+    fn federation() -> Federation {
+        Federation {
+            domain: Some("here.example".to_owned()),
+            listen: None,
+            peers: HashMap::from([("there.example".to_owned(), Peer { addr: ([127, 0, 0, 1], 0).into(), secret: SECRET })]),
+        }
+    }
+
+    async fn pair() -> (BlockStream<1024>, BlockStream<1024>) {
+        let config = Config::default();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (dialed, accepted) = tokio::join!(
+            async { BlockStream::connect(TcpStream::connect(addr).await.unwrap(), &config).await.unwrap() },
+            async { BlockStream::accept(listener.accept().await.unwrap().0, &config).await.unwrap() },
+        );
+        (dialed, accepted)
+    }
+
+    #[test]
+    fn addresses_resolve_by_domain() {
+        let federation = federation();
+        assert!(matches!(federation.resolve("alice".to_owned()), Address::Local(user) if user == "alice"));
+        assert!(matches!(federation.resolve("alice@here.example".to_owned()), Address::Local(user) if user == "alice"));
+        assert!(matches!(
+            federation.resolve("bob@there.example".to_owned()),
+            Address::Remote { user, domain } if user == "bob" && domain == "there.example"
+        ));
+        assert!(matches!(federation.resolve("carol@elsewhere.example".to_owned()), Address::Unknown));
+        assert!(matches!(Federation::default().resolve("bob@there.example".to_owned()), Address::Unknown));
+    }
+
+    #[test]
+    fn relays_round_trip() {
+        let relay = Relay { id: MessageId(42), from: "alice".to_owned(), to: "bob".to_owned(), message: vec![1, 2, 3] };
+        let read = serialization::from_bytes::<Relay>(&serialization::to_bytes(&relay)).unwrap();
+        assert_eq!((read.id, read.from, read.to, read.message), (relay.id, relay.from, relay.to, relay.message));
+    }
+
+    #[test]
+    fn proofs_depend_on_every_input() {
+        let expected = proof(&SECRET, b"dial", &[1; 32], &[2; 32]);
+        assert_eq!(expected, proof(&SECRET, b"dial", &[1; 32], &[2; 32]));
+        assert_ne!(expected, proof(&[8; 32], b"dial", &[1; 32], &[2; 32]));
+        assert_ne!(expected, proof(&SECRET, b"accept", &[1; 32], &[2; 32]));
+        assert_ne!(expected, proof(&SECRET, b"dial", &[3; 32], &[2; 32]));
+        assert_ne!(expected, proof(&SECRET, b"dial", &[1; 32], &[4; 32]));
+    }
+
+    #[tokio::test]
+    async fn peers_sharing_a_secret_link() {
+        let (mut dialed, mut accepted) = pair().await;
+        let federation = federation();
+        let peer = &federation.peers["there.example"];
+        let (dialed, accepted) = tokio::join!(dial(&mut dialed, "there.example", peer), accept(&mut accepted, &federation.peers));
+        assert!(dialed.is_ok());
+        assert_eq!(accepted.unwrap(), "there.example");
+    }
+
+    // each side drops its stream once it gives up, which is what unblocks the other
+    #[tokio::test]
+    async fn wrong_secrets_are_refused() {
+        let (mut dialed, mut accepted) = pair().await;
+        let peer = Peer { addr: ([127, 0, 0, 1], 0).into(), secret: [8; 32] };
+        let peers = federation().peers;
+        let (dialed, accepted) = tokio::join!(
+            async move { dial(&mut dialed, "there.example", &peer).await },
+            async move { accept(&mut accepted, &peers).await },
+        );
+        assert!(matches!(dialed, Err(LinkError::Authentication)));
+        assert!(accepted.is_err());
+    }
+
+    #[tokio::test]
+    async fn unknown_domains_are_refused() {
+        let (mut dialed, mut accepted) = pair().await;
+        let federation = federation();
+        let peer = federation.peers["there.example"].clone();
+        let peers = federation.peers;
+        let (dialed, accepted) = tokio::join!(
+            async move { dial(&mut dialed, "elsewhere.example", &peer).await },
+            async move { accept(&mut accepted, &peers).await },
+        );
+        assert!(dialed.is_err());
+        assert!(matches!(accepted, Err(LinkError::UnknownPeer(domain)) if domain == "elsewhere.example"));
+    }
+}
@@ -1,11 +1,19 @@
-use std::future::Future;
+use std::{
+    collections::VecDeque,
+    future::Future,
+    sync::{Arc, Mutex},
+};
 
 use tokio::{
     task::{
         self,
         JoinError,
     },
-    sync::mpsc::{self, error::SendError},
+    sync::{
+        mpsc::error::{SendError, TrySendError},
+        Notify,
+    },
+    time::{self, Instant},
 };
 
 struct NoDrop;
@@ -18,27 +26,170 @@ impl Drop for NoDrop {
     }
 }
 
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Overflow {
+    Block,
+    DropOldest,
+    Disconnect,
+}
+
+struct Queue<T> {
+    items: VecDeque<T>,
+    closed: bool,
+}
+
+struct Shared<T> {
+    queue: Mutex<Queue<T>>,
+    capacity: Option<usize>,
+    overflow: Overflow,
+    readable: Notify,
+    writable: Notify,
+}
+
+impl<T> Shared<T> {
+    fn close(&self, discard: bool) {
+        let mut queue = self.queue.lock().unwrap();
+        queue.closed = true;
+        if discard {
+            queue.items.clear();
+        }
+        drop(queue);
+        self.readable.notify_one();
+        self.writable.notify_waiters();
+    }
+}
+
+pub struct Receiver<T> {
+    shared: Arc<Shared<T>>,
+}
+
+impl<T> Receiver<T> {
+    pub async fn recv(&mut self) -> Option<T> {
+        loop {
+            let notified = self.shared.readable.notified();
+            {
+                let mut queue = self.shared.queue.lock().unwrap();
+                if let Some(item) = queue.items.pop_front() {
+                    drop(queue);
+                    self.shared.writable.notify_one();
+                    return Some(item);
+                }
+                if queue.closed {
+                    return None;
+                }
+            }
+            notified.await;
+        }
+    }
+}
+
+impl<T> Drop for Receiver<T> {
+    fn drop(&mut self) {
+        self.shared.close(true);
+    }
+}
+
+struct Sender<T> {
+    shared: Arc<Shared<T>>,
+}
+
+impl<T> Sender<T> {
+    fn push(&self, item: T) -> Result<Option<T>, SendError<T>> {
+        let mut queue = self.shared.queue.lock().unwrap();
+        if queue.closed {
+            return Err(SendError(item));
+        }
+        if self.shared.capacity.is_some_and(|capacity| queue.items.len() >= capacity) {
+            match self.shared.overflow {
+                Overflow::Block => return Ok(Some(item)),
+                Overflow::DropOldest => {
+                    queue.items.pop_front();
+                }
+                Overflow::Disconnect => {
+                    drop(queue);
+                    self.shared.close(true);
+                    return Err(SendError(item));
+                }
+            }
+        }
+        queue.items.push_back(item);
+        drop(queue);
+        self.shared.readable.notify_one();
+        Ok(None)
+    }
+}
+
+impl<T> Drop for Sender<T> {
+    fn drop(&mut self) {
+        self.shared.close(false);
+    }
+}
+
 pub struct Handle<T, R> {
-    sender: mpsc::UnboundedSender<T>,
+    sender: Sender<T>,
     future: task::JoinHandle<R>,
     no_drop: NoDrop,
 }
 
+impl<T: Send + 'static, R> Handle<T, R> {
+    pub fn depth(&self) -> impl Fn() -> usize + Send + Sync + 'static {
+        let shared = self.sender.shared.clone();
+        move || shared.queue.lock().unwrap().items.len()
+    }
+}
+
 impl<T, R: Send + 'static> Handle<T, R> {
-    pub fn new<F: FnOnce(mpsc::UnboundedReceiver<T>) -> Fut, Fut: Future<Output=R> + Send + 'static>(future: F) -> Self {
-        let (sender, receiver) = mpsc::unbounded_channel();
+    pub fn new<F: FnOnce(Receiver<T>) -> Fut, Fut: Future<Output=R> + Send + 'static>(future: F) -> Self {
+        Self::with_capacity(None, Overflow::Block, future)
+    }
+
+    pub fn bounded<F: FnOnce(Receiver<T>) -> Fut, Fut: Future<Output=R> + Send + 'static>(capacity: usize, overflow: Overflow, future: F) -> Self {
+        Self::with_capacity(Some(capacity), overflow, future)
+    }
+
+    pub fn with_capacity<F: FnOnce(Receiver<T>) -> Fut, Fut: Future<Output=R> + Send + 'static>(capacity: Option<usize>, overflow: Overflow, future: F) -> Self {
+        let shared = Arc::new(Shared {
+            queue: Mutex::new(Queue {
+                items: VecDeque::new(),
+                closed: false,
+            }),
+            capacity,
+            overflow,
+            readable: Notify::new(),
+            writable: Notify::new(),
+        });
+        let receiver = Receiver { shared: shared.clone() };
         // let local = LocalSet::new();
         // let future = local.spawn_local(future(receiver));
         let future = task::spawn(future(receiver));
         Self {
-            sender,
+            sender: Sender { shared },
             future,
             no_drop: NoDrop,
         }
     }
 
-    pub fn send(&self, message: T) -> Result<(), SendError<T>> {
-        Ok(self.sender.send(message)?)
+    pub fn send(&self, message: T) -> Result<(), TrySendError<T>> {
+        match self.sender.push(message) {
+            Ok(None) => Ok(()),
+            Ok(Some(message)) => Err(TrySendError::Full(message)),
+            Err(SendError(message)) => Err(TrySendError::Closed(message)),
+        }
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.sender.shared.queue.lock().unwrap().closed
+    }
+
+    pub async fn send_async(&self, mut message: T) -> Result<(), SendError<T>> {
+        loop {
+            let notified = self.sender.shared.writable.notified();
+            match self.sender.push(message)? {
+                None => break Ok(()),
+                Some(rejected) => message = rejected,
+            }
+            notified.await;
+        }
     }
 
     pub async fn shutdown(self) -> Result<R, JoinError> {
@@ -51,4 +202,100 @@ impl<T, R: Send + 'static> Handle<T, R> {
         drop(shutdown_sender);
         join_handle.await
     }
+
+    // the task is aborted if it has not finished by the deadline, in which case there is no result
+    pub async fn shutdown_by(self, deadline: Instant) -> Option<Result<R, JoinError>> {
+        let Self {
+            sender: shutdown_sender,
+            future: mut join_handle,
+            no_drop,
+        } = self;
+        std::mem::forget(no_drop);
+        drop(shutdown_sender);
+        match time::timeout_at(deadline, &mut join_handle).await {
+            Ok(result) => Some(result),
+            Err(_) => {
+                join_handle.abort();
+                None
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use futures::{channel::oneshot, join};
+
+    use super::*;
+
+    // the receiver only starts draining once the gate opens, so the queue fills up first
+    fn gated(capacity: usize, overflow: Overflow) -> (Handle<u32, Vec<u32>>, oneshot::Sender<()>) {
+        let (gate, opened) = oneshot::channel();
+        let handle = Handle::bounded(capacity, overflow, |mut receiver| async move {
+            let _ = opened.await;
+            let mut items = Vec::new();
+            while let Some(item) = receiver.recv().await {
+                items.push(item);
+            }
+            items
+        });
+        (handle, gate)
+    }
+
+    #[tokio::test]
+    async fn full_queue_refuses_sends() {
+        let (handle, gate) = gated(2, Overflow::Block);
+        assert!(handle.send(1).is_ok());
+        assert!(handle.send(2).is_ok());
+        assert!(matches!(handle.send(3), Err(TrySendError::Full(3))));
+        assert!(!handle.is_closed());
+        gate.send(()).unwrap();
+        assert_eq!(handle.shutdown().await.unwrap(), [1, 2]);
+    }
+
+    #[tokio::test]
+    async fn blocking_send_waits_for_room() {
+        let (handle, gate) = gated(2, Overflow::Block);
+        assert!(handle.send(1).is_ok());
+        assert!(handle.send(2).is_ok());
+        let (sent, ()) = join!(handle.send_async(3), async { gate.send(()).unwrap() });
+        assert!(sent.is_ok());
+        assert_eq!(handle.shutdown().await.unwrap(), [1, 2, 3]);
+    }
+
+    #[tokio::test]
+    async fn drop_oldest_keeps_the_newest() {
+        let (handle, gate) = gated(2, Overflow::DropOldest);
+        for item in 1..=4 {
+            assert!(handle.send(item).is_ok());
+        }
+        gate.send(()).unwrap();
+        assert_eq!(handle.shutdown().await.unwrap(), [3, 4]);
+    }
+
+    #[tokio::test]
+    async fn disconnect_closes_and_discards() {
+        let (handle, gate) = gated(2, Overflow::Disconnect);
+        assert!(handle.send(1).is_ok());
+        assert!(handle.send(2).is_ok());
+        assert!(matches!(handle.send(3), Err(TrySendError::Closed(3))));
+        assert!(handle.is_closed());
+        assert!(matches!(handle.send(4), Err(TrySendError::Closed(4))));
+        gate.send(()).unwrap();
+        assert!(handle.shutdown().await.unwrap().is_empty());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn shutdown_by_gives_up_at_the_deadline() {
+        let (handle, gate) = gated(2, Overflow::Block);
+        assert!(handle.shutdown_by(Instant::now() + Duration::from_secs(1)).await.is_none());
+        drop(gate);
+
+        let (handle, gate) = gated(2, Overflow::Block);
+        assert!(handle.send(1).is_ok());
+        gate.send(()).unwrap();
+        assert_eq!(handle.shutdown_by(Instant::now() + Duration::from_secs(1)).await.unwrap().unwrap(), [1]);
+    }
 }
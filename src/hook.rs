@@ -0,0 +1,101 @@
+use crate::{
+    db::User,
+    protocol::{Reason, Target},
+};
+
+pub enum Verdict<M> {
+    Allow,
+    Modify(M),
+    Drop,
+    Reject(Reason),
+}
+
+pub trait MessageHook<M>: Send + Sync + 'static {
+    fn check(&self, from: &User, to: &Target, message: &M) -> Verdict<M>;
+}
+
+impl<M> MessageHook<M> for () {
+    fn check(&self, _: &User, _: &Target, _: &M) -> Verdict<M> {
+        Verdict::Allow
+    }
+}
+
+impl<M, A: MessageHook<M>, B: MessageHook<M>> MessageHook<M> for (A, B) {
+    fn check(&self, from: &User, to: &Target, message: &M) -> Verdict<M> {
+        match self.0.check(from, to, message) {
+            Verdict::Allow => self.1.check(from, to, message),
+            Verdict::Modify(modified) => match self.1.check(from, to, &modified) {
+                Verdict::Allow => Verdict::Modify(modified),
+                verdict => verdict,
+            },
+            verdict => verdict,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex;
+
+    use super::*;
+
+    struct Shout;
+
+    impl MessageHook<String> for Shout {
+        fn check(&self, _: &User, _: &Target, message: &String) -> Verdict<String> {
+            Verdict::Modify(message.to_uppercase())
+        }
+    }
+
+    struct Filter(&'static str);
+
+    impl MessageHook<String> for Filter {
+        fn check(&self, _: &User, _: &Target, message: &String) -> Verdict<String> {
+            match message.contains(self.0) {
+                true => Verdict::Reject(Reason::Filtered),
+                false => Verdict::Allow,
+            }
+        }
+    }
+
+    #[derive(Default)]
+    struct Seen(Mutex<Vec<String>>);
+
+    impl MessageHook<String> for Seen {
+        fn check(&self, _: &User, _: &Target, message: &String) -> Verdict<String> {
+            self.0.lock().unwrap().push(message.clone());
+            Verdict::Allow
+        }
+    }
+
+    fn check<H: MessageHook<String>>(hook: &H, message: &str) -> Verdict<String> {
+        hook.check(&User::new("alice".to_owned()), &Target::User("bob".to_owned()), &message.to_owned())
+    }
+
+    #[test]
+    fn no_hooks_allow_everything() {
+        assert!(matches!(check(&(), "hello"), Verdict::Allow));
+    }
+
+    #[test]
+    fn later_hooks_see_modified_messages() {
+        let hooks = (Shout, Seen::default());
+        assert!(matches!(check(&hooks, "hello"), Verdict::Modify(message) if message == "HELLO"));
+        assert_eq!(*hooks.1.0.lock().unwrap(), ["HELLO"]);
+    }
+
+    #[test]
+    fn a_rejection_ends_the_chain() {
+        let hooks = (Filter("spam"), (Shout, Seen::default()));
+        assert!(matches!(check(&hooks, "spam"), Verdict::Reject(Reason::Filtered)));
+        assert!(hooks.1.1.0.lock().unwrap().is_empty());
+        assert!(matches!(check(&hooks, "ham"), Verdict::Modify(message) if message == "HAM"));
+    }
+
+    #[test]
+    fn later_hooks_can_reject_a_modification() {
+        let hooks = (Shout, Filter("SPAM"));
+        assert!(matches!(check(&hooks, "spam"), Verdict::Reject(Reason::Filtered)));
+        assert!(matches!(check(&hooks, "ham"), Verdict::Modify(message) if message == "HAM"));
+    }
+}
@@ -4,7 +4,6 @@
 #![feature(inline_const)]
 #![feature(never_type)]
 #![feature(type_alias_impl_trait)]
-#![feature(core_intrinsics)]
 #![feature(const_caller_location)]
 #![feature(const_location_fields)]
 #![feature(const_trait_impl)]
@@ -19,16 +18,41 @@
 
 pub mod message;
 #[cfg(feature = "server")]
+mod bot;
+#[cfg(feature = "server")]
+mod control;
+#[cfg(feature = "server")]
 pub mod db;
 #[cfg(feature = "client")]
 pub mod client;
 #[cfg(feature = "server")]
 pub mod server;
+#[cfg(feature = "server")]
+pub mod ticket;
+#[cfg(feature = "server")]
+mod federation;
 mod handle;
+#[cfg(feature = "server")]
+mod hook;
+#[cfg(feature = "server")]
+mod metrics;
+mod mux;
+pub mod protocol;
+#[cfg(feature = "server")]
+mod rate;
 mod stream;
 pub mod serialization;
 pub mod logger;
 
+#[cfg(feature = "server")]
+pub use bot::{Bot, Bots, Outbox};
+pub use handle::Overflow;
+#[cfg(feature = "server")]
+pub use hook::{MessageHook, Verdict};
+#[cfg(feature = "server")]
+pub use rate::{RateLimit, Scope};
+pub use stream::{CipherSuite, KeyExchange};
+
 impl message::Message for String {}
 
 #[derive(Copy, Clone)]
@@ -4,6 +4,7 @@ use std::io::Write;
 use std::sync::Arc;
 use std::sync::Mutex;
 
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord)]
 pub enum LogLevel {
     Fatal,
     Error,
@@ -97,4 +98,81 @@ impl Logger for FileLogger {
     fn info<S: Debug>(&self, message: S) {
         self.log("INFO", message)
     }
-}
\ No newline at end of file
+}
+
+#[derive(Clone)]
+pub enum AnyLogger {
+    Stdio(StdioLogger),
+    File(FileLogger),
+    None,
+}
+
+impl Logger for AnyLogger {
+    fn fatal<S: Debug>(&self, message: S) {
+        match self {
+            AnyLogger::Stdio(logger) => logger.fatal(message),
+            AnyLogger::File(logger) => logger.fatal(message),
+            AnyLogger::None => {}
+        }
+    }
+
+    fn error<S: Debug>(&self, message: S) {
+        match self {
+            AnyLogger::Stdio(logger) => logger.error(message),
+            AnyLogger::File(logger) => logger.error(message),
+            AnyLogger::None => {}
+        }
+    }
+
+    fn warn<S: Debug>(&self, message: S) {
+        match self {
+            AnyLogger::Stdio(logger) => logger.warn(message),
+            AnyLogger::File(logger) => logger.warn(message),
+            AnyLogger::None => {}
+        }
+    }
+
+    fn info<S: Debug>(&self, message: S) {
+        match self {
+            AnyLogger::Stdio(logger) => logger.info(message),
+            AnyLogger::File(logger) => logger.info(message),
+            AnyLogger::None => {}
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct LevelFilter<L> {
+    logger: L,
+    level: LogLevel,
+}
+
+impl<L> LevelFilter<L> {
+    pub fn new(logger: L, level: LogLevel) -> Self {
+        Self { logger, level }
+    }
+}
+
+impl<L: Logger> Logger for LevelFilter<L> {
+    fn fatal<S: Debug>(&self, message: S) {
+        self.logger.fatal(message)
+    }
+
+    fn error<S: Debug>(&self, message: S) {
+        if self.level >= LogLevel::Error {
+            self.logger.error(message)
+        }
+    }
+
+    fn warn<S: Debug>(&self, message: S) {
+        if self.level >= LogLevel::Warn {
+            self.logger.warn(message)
+        }
+    }
+
+    fn info<S: Debug>(&self, message: S) {
+        if self.level >= LogLevel::Info {
+            self.logger.info(message)
+        }
+    }
+}
@@ -0,0 +1,139 @@
+use std::{
+    collections::BTreeMap,
+    fmt::Write,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+        Mutex,
+    },
+    time::Duration,
+};
+
+use tokio::{
+    io::{self, AsyncReadExt, AsyncWriteExt},
+    net::TcpStream,
+};
+
+use crate::stream::Traffic;
+
+const BUCKETS: [f64; 9] = [0.0001, 0.0005, 0.001, 0.005, 0.01, 0.05, 0.1, 0.5, 1.0];
+
+#[derive(Default)]
+struct Histogram {
+    buckets: [u64; BUCKETS.len()],
+    sum: f64,
+    count: u64,
+}
+
+impl Histogram {
+    fn observe(&mut self, value: f64) {
+        for (bucket, bound) in self.buckets.iter_mut().zip(BUCKETS) {
+            if value <= bound {
+                *bucket += 1;
+            }
+        }
+        self.sum += value;
+        self.count += 1;
+    }
+}
+
+type Queue = Box<dyn Fn() -> usize + Send + Sync>;
+
+#[derive(Default)]
+pub(crate) struct Metrics {
+    pub connections: AtomicU64,
+    pub connections_total: AtomicU64,
+    pub connections_refused: AtomicU64,
+    pub logins_succeeded: AtomicU64,
+    pub logins_failed: AtomicU64,
+    pub messages_routed: AtomicU64,
+    pub messages_dropped: AtomicU64,
+    pub messages_stored: AtomicU64,
+    pub traffic: Arc<Traffic>,
+    database: Mutex<BTreeMap<&'static str, Histogram>>,
+    queues: Mutex<Vec<(&'static str, Queue)>>,
+    connection_queues: Mutex<BTreeMap<u64, Queue>>,
+}
+
+impl Metrics {
+    pub fn database(&self, operation: &'static str, elapsed: Duration) {
+        self.database.lock().unwrap().entry(operation).or_default().observe(elapsed.as_secs_f64());
+    }
+
+    pub fn queue<F: Fn() -> usize + Send + Sync + 'static>(&self, name: &'static str, depth: F) {
+        self.queues.lock().unwrap().push((name, Box::new(depth)));
+    }
+
+    pub fn connection_queue<F: Fn() -> usize + Send + Sync + 'static>(&self, connection: u64, depth: F) {
+        self.connection_queues.lock().unwrap().insert(connection, Box::new(depth));
+    }
+
+    pub fn disconnected(&self, connection: u64) {
+        self.connection_queues.lock().unwrap().remove(&connection);
+    }
+
+    pub fn render(&self) -> String {
+        fn metric(out: &mut String, name: &str, kind: &str, help: &str, value: u64) {
+            let _ = write!(out, "# HELP {name} {help}\n# TYPE {name} {kind}\n{name} {value}\n");
+        }
+
+        let load = |counter: &AtomicU64| counter.load(Ordering::Relaxed);
+        let mut out = String::new();
+        metric(&mut out, "chat_connections", "gauge", "Logged in client connections.", load(&self.connections));
+        metric(&mut out, "chat_connections_total", "counter", "Accepted client connections.", load(&self.connections_total));
+        metric(&mut out, "chat_connections_refused_total", "counter", "Client connections refused by admission limits.", load(&self.connections_refused));
+        let _ = write!(
+            out,
+            "# HELP chat_logins_total Login attempts by result.\n# TYPE chat_logins_total counter\nchat_logins_total{{result=\"succeeded\"}} {}\nchat_logins_total{{result=\"failed\"}} {}\n",
+            load(&self.logins_succeeded),
+            load(&self.logins_failed),
+        );
+        metric(&mut out, "chat_messages_routed_total", "counter", "Messages accepted by the router.", load(&self.messages_routed));
+        metric(&mut out, "chat_messages_dropped_total", "counter", "Messages rejected, filtered or left undelivered.", load(&self.messages_dropped));
+        metric(&mut out, "chat_messages_stored_total", "counter", "Messages stored for offline recipients.", load(&self.messages_stored));
+        metric(&mut out, "chat_received_bytes_total", "counter", "Bytes read from block streams.", load(&self.traffic.bytes_in));
+        metric(&mut out, "chat_sent_bytes_total", "counter", "Bytes written to block streams.", load(&self.traffic.bytes_out));
+
+        let _ = write!(out, "# HELP chat_queue_depth Items waiting in internal queues.\n# TYPE chat_queue_depth gauge\n");
+        for (name, depth) in self.queues.lock().unwrap().iter() {
+            let _ = writeln!(out, "chat_queue_depth{{queue=\"{name}\"}} {}", depth());
+        }
+
+        let _ = write!(out, "# HELP chat_connection_queue_depth Outgoing items waiting per connection.\n# TYPE chat_connection_queue_depth gauge\n");
+        for (connection, depth) in self.connection_queues.lock().unwrap().iter() {
+            let _ = writeln!(out, "chat_connection_queue_depth{{connection=\"{connection}\"}} {}", depth());
+        }
+
+        let _ = write!(out, "# HELP chat_db_operation_seconds Database operation latency.\n# TYPE chat_db_operation_seconds histogram\n");
+        for (operation, histogram) in self.database.lock().unwrap().iter() {
+            for (bound, count) in BUCKETS.iter().zip(histogram.buckets) {
+                let _ = writeln!(out, "chat_db_operation_seconds_bucket{{operation=\"{operation}\",le=\"{bound}\"}} {count}");
+            }
+            let _ = writeln!(out, "chat_db_operation_seconds_bucket{{operation=\"{operation}\",le=\"+Inf\"}} {}", histogram.count);
+            let _ = writeln!(out, "chat_db_operation_seconds_sum{{operation=\"{operation}\"}} {}", histogram.sum);
+            let _ = writeln!(out, "chat_db_operation_seconds_count{{operation=\"{operation}\"}} {}", histogram.count);
+        }
+        out
+    }
+}
+
+pub(crate) async fn serve(mut stream: TcpStream, metrics: &Metrics) -> io::Result<()> {
+    let mut request = Vec::new();
+    let mut buf = [0; 1024];
+    while !request.windows(4).any(|window| window == b"\r\n\r\n") {
+        let read = stream.read(&mut buf).await?;
+        if read == 0 || request.len() + read > 8 * 1024 {
+            return Ok(());
+        }
+        request.extend_from_slice(&buf[..read]);
+    }
+    let response = match request.split(|byte| *byte == b' ').take(2).collect::<Vec<_>>()[..] {
+        [b"GET", b"/metrics"] => {
+            let body = metrics.render();
+            format!("HTTP/1.1 200 OK\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}", body.len())
+        }
+        _ => "HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n".to_owned(),
+    };
+    stream.write_all(response.as_bytes()).await?;
+    stream.shutdown().await
+}
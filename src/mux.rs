@@ -0,0 +1,554 @@
+use std::{
+    collections::{
+        BTreeMap,
+        VecDeque,
+    },
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use futures::{
+    future,
+    FutureExt,
+};
+
+use tokio::{
+    io::{
+        self,
+        AsyncReadExt,
+        AsyncWriteExt,
+    },
+    sync::{
+        Mutex as SendLock,
+        Notify,
+    },
+    task::{self, JoinError, JoinHandle},
+    time::{
+        self,
+        Instant,
+    },
+};
+
+use crate::{
+    serialization::{
+        Deserializable,
+        Deserializer,
+        Serializable,
+        Serializer,
+    },
+    stream::{
+        Block,
+        BlockError,
+        BlockReader,
+        BlockWriter,
+        Config,
+        HEADER,
+        ReadError,
+        WriteError,
+    },
+};
+
+pub const CONTROL: u32 = 0;
+pub const CHAT: u32 = 1;
+pub const BULK: u32 = 2;
+
+const WINDOW: u32 = 16;
+
+const QUEUE: usize = 2 * WINDOW as usize;
+
+pub struct Encoded(Vec<(Vec<u8>, bool)>);
+
+impl Encoded {
+    pub fn new<const N: usize, B: Serializable>(block: B) -> Self {
+        let mut serializer = block.serializer();
+        let mut chunks = Vec::new();
+        loop {
+            let mut chunk = vec![0; N - HEADER];
+            match serializer.fill(&mut chunk) {
+                None => chunks.push((chunk, false)),
+                Some(len) => {
+                    chunk.truncate(len);
+                    chunks.push((chunk, true));
+                    break Self(chunks);
+                }
+            }
+        }
+    }
+
+    pub fn blocks(&self) -> usize {
+        self.0.len()
+    }
+
+    pub fn bytes(&self) -> usize {
+        self.0.iter().map(|(chunk, _)| chunk.len()).sum()
+    }
+}
+
+struct Channel {
+    outgoing: VecDeque<(Vec<u8>, bool)>,
+    credit: u32,
+    incoming: VecDeque<(Vec<u8>, bool)>,
+    consumed: u32,
+    readable: Arc<Notify>,
+    sendable: Arc<Notify>,
+    sending: Arc<SendLock<()>>,
+}
+
+impl Default for Channel {
+    fn default() -> Self {
+        Self {
+            outgoing: VecDeque::new(),
+            credit: WINDOW,
+            incoming: VecDeque::new(),
+            consumed: 0,
+            readable: Arc::new(Notify::new()),
+            sendable: Arc::new(Notify::new()),
+            sending: Arc::new(SendLock::new(())),
+        }
+    }
+}
+
+struct State {
+    channels: BTreeMap<u32, Channel>,
+    next: u32,
+    pongs: usize,
+    grants: VecDeque<(u32, u32)>,
+    last_seen: Instant,
+    closing: bool,
+    eof: bool,
+    error: Option<MuxError>,
+}
+
+impl State {
+    fn fail(&mut self, error: MuxError) {
+        self.error.get_or_insert(error);
+        self.channels.values().for_each(|channel| {
+            channel.readable.notify_one();
+            channel.sendable.notify_waiters();
+        });
+    }
+
+    fn next_block(&mut self) -> Option<Block> {
+        if self.pongs > 0 {
+            self.pongs -= 1;
+            return Some(Block::Pong);
+        }
+        if let Some((channel, credit)) = self.grants.pop_front() {
+            return Some(Block::Window { channel, credit });
+        }
+        let next = self.next;
+        let channel = self.channels
+            .range(next..)
+            .chain(self.channels.range(..next))
+            .find(|(_, channel)| channel.credit > 0 && !channel.outgoing.is_empty())
+            .map(|(&channel, _)| channel)?;
+        let state = self.channels.get_mut(&channel)?;
+        let (payload, last) = state.outgoing.pop_front()?;
+        state.credit -= 1;
+        state.sendable.notify_waiters();
+        self.next = channel.wrapping_add(1);
+        Some(Block::Data { channel, payload, last })
+    }
+}
+
+struct Shared {
+    state: Mutex<State>,
+    writable: Notify,
+}
+
+pub struct Mux<const N: usize> {
+    shared: Arc<Shared>,
+    read_timeout: Option<Duration>,
+    reading: JoinHandle<()>,
+    writing: JoinHandle<()>,
+}
+
+impl<const N: usize> Mux<N> where [(); N + 16]: {
+    pub fn new<
+        R: AsyncReadExt + Unpin + Send + 'static,
+        W: AsyncWriteExt + Unpin + Send + 'static,
+    >(mut reader: BlockReader<N, R>, mut writer: BlockWriter<N, W>, config: &Config) -> Self {
+        let shared = Arc::new(Shared {
+            state: Mutex::new(State {
+                channels: [CONTROL, CHAT, BULK].into_iter().map(|id| (id, Channel::default())).collect(),
+                next: 0,
+                pongs: 0,
+                grants: VecDeque::new(),
+                last_seen: Instant::now(),
+                closing: false,
+                eof: false,
+                error: None,
+            }),
+            writable: Notify::new(),
+        });
+
+        let reading = task::spawn({
+            let shared = shared.clone();
+            async move {
+                loop {
+                    let block = reader.read_raw().await;
+                    let mut state = shared.state.lock().unwrap();
+                    state.last_seen = Instant::now();
+                    match block {
+                        Ok(Block::Data { channel: id, payload, last }) => {
+                            let Some(channel) = state.channels.get_mut(&id) else {
+                                state.fail(MuxError::Channel(id));
+                                break;
+                            };
+                            channel.incoming.push_back((payload, last));
+                            channel.readable.notify_one();
+                            if channel.incoming.len() > WINDOW as usize {
+                                state.fail(MuxError::Window);
+                                break;
+                            }
+                        }
+                        Ok(Block::Ping) => {
+                            state.pongs += 1;
+                            shared.writable.notify_one();
+                        }
+                        Ok(Block::Pong) => {}
+                        Ok(Block::Window { channel: id, credit }) => {
+                            let Some(channel) = state.channels.get_mut(&id) else {
+                                state.fail(MuxError::Channel(id));
+                                break;
+                            };
+                            channel.credit = channel.credit.saturating_add(credit);
+                            shared.writable.notify_one();
+                        }
+                        Err(BlockError::Network(e)) if e.kind() == io::ErrorKind::UnexpectedEof => {
+                            state.eof = true;
+                            state.channels.values().for_each(|channel| channel.readable.notify_one());
+                            break;
+                        }
+                        Err(e) => {
+                            state.fail(MuxError::Read(e));
+                            break;
+                        }
+                    }
+                }
+                shared.writable.notify_one();
+            }
+        });
+
+        let writing = task::spawn({
+            let shared = shared.clone();
+            let keepalive = config.keepalive;
+            let idle_timeout = config.idle_timeout;
+            async move {
+                let mut last_ping = Instant::now();
+                loop {
+                    let notified = shared.writable.notified();
+                    let (block, last_seen) = {
+                        let mut state = shared.state.lock().unwrap();
+                        if state.error.is_some() {
+                            break;
+                        }
+                        let block = state.next_block();
+                        let drained = state.channels.values().all(|channel| channel.outgoing.is_empty());
+                        if block.is_none() && (state.eof || state.closing && drained) {
+                            break;
+                        }
+                        (block, state.last_seen)
+                    };
+                    let block = match block {
+                        Some(block) => block,
+                        None => {
+                            let deadline = [
+                                keepalive.map(|keepalive| (last_ping + keepalive, Beat::Ping)),
+                                idle_timeout.map(|timeout| (last_seen + timeout, Beat::Timeout)),
+                            ].into_iter().flatten().min_by_key(|(at, _)| *at);
+                            let beat = async {
+                                match deadline {
+                                    Some((at, beat)) => {
+                                        time::sleep_until(at).await;
+                                        beat
+                                    }
+                                    None => future::pending().await,
+                                }
+                            };
+                            futures::select! {
+                                _ = notified.fuse() => continue,
+                                beat = beat.fuse() => match beat {
+                                    Beat::Ping => {
+                                        last_ping = Instant::now();
+                                        Block::Ping
+                                    }
+                                    Beat::Timeout => {
+                                        shared.state.lock().unwrap().fail(MuxError::Timeout);
+                                        break;
+                                    }
+                                },
+                            }
+                        }
+                    };
+                    let written = match idle_timeout {
+                        Some(timeout) => time::timeout(timeout, writer.write_raw(block)).await.map_err(|_| MuxError::Timeout),
+                        None => Ok(writer.write_raw(block).await),
+                    };
+                    match written {
+                        Ok(Ok(())) => {}
+                        Ok(Err(e)) => {
+                            shared.state.lock().unwrap().fail(MuxError::Write(e));
+                            break;
+                        }
+                        Err(e) => {
+                            shared.state.lock().unwrap().fail(e);
+                            break;
+                        }
+                    }
+                }
+            }
+        });
+
+        Self {
+            shared,
+            read_timeout: config.read_timeout,
+            reading,
+            writing,
+        }
+    }
+
+    pub fn channel(&self, id: u32) -> (ChannelSender<N>, ChannelReceiver<N>) {
+        let (readable, sendable, sending) = {
+            let mut state = self.shared.state.lock().unwrap();
+            let channel = state.channels.entry(id).or_default();
+            (channel.readable.clone(), channel.sendable.clone(), channel.sending.clone())
+        };
+        (
+            ChannelSender {
+                id,
+                shared: self.shared.clone(),
+                sendable,
+                sending,
+            },
+            ChannelReceiver {
+                id,
+                shared: self.shared.clone(),
+                readable,
+                read_timeout: self.read_timeout,
+            },
+        )
+    }
+
+    pub async fn shutdown(self, deadline: Option<Duration>) -> Result<(), MuxError> {
+        let Self { shared, reading, mut writing, .. } = self;
+        {
+            let mut state = shared.state.lock().unwrap();
+            state.closing = true;
+            state.channels.values().for_each(|channel| channel.sendable.notify_waiters());
+        }
+        shared.writable.notify_one();
+        let drained = match deadline {
+            Some(deadline) => time::timeout(deadline, &mut writing).await.ok(),
+            None => Some((&mut writing).await),
+        };
+        reading.abort();
+        match drained {
+            Some(result) => result?,
+            None => {
+                writing.abort();
+                return Err(MuxError::Drain);
+            }
+        }
+        let error = shared.state.lock().unwrap().error.take();
+        error.map_or(Ok(()), Err)
+    }
+}
+
+#[derive(Copy, Clone, PartialEq, Eq)]
+enum Beat {
+    Ping,
+    Timeout,
+}
+
+#[derive(Clone)]
+pub struct ChannelSender<const N: usize> {
+    id: u32,
+    shared: Arc<Shared>,
+    sendable: Arc<Notify>,
+    sending: Arc<SendLock<()>>,
+}
+
+impl<const N: usize> ChannelSender<N> {
+    pub async fn send<B: Serializable>(&self, block: B) -> Result<(), Closed> {
+        self.send_encoded(Encoded::new::<N, _>(block)).await
+    }
+
+    // a message is queued a chunk at a time as room frees up, senders sharing the channel wait their turn so chunks never interleave
+    pub async fn send_encoded(&self, Encoded(chunks): Encoded) -> Result<(), Closed> {
+        let _sending = self.sending.lock().await;
+        let mut chunks = chunks.into_iter();
+        loop {
+            let notified = self.sendable.notified();
+            {
+                let mut state = self.shared.state.lock().unwrap();
+                if state.closing || state.error.is_some() {
+                    return Err(Closed);
+                }
+                let channel = state.channels.entry(self.id).or_default();
+                let room = QUEUE.saturating_sub(channel.outgoing.len());
+                channel.outgoing.extend(chunks.by_ref().take(room));
+                if chunks.len() == 0 {
+                    break;
+                }
+            }
+            self.shared.writable.notify_one();
+            notified.await;
+        }
+        self.shared.writable.notify_one();
+        Ok(())
+    }
+}
+
+pub struct ChannelReceiver<const N: usize> {
+    id: u32,
+    shared: Arc<Shared>,
+    readable: Arc<Notify>,
+    read_timeout: Option<Duration>,
+}
+
+impl<const N: usize> ChannelReceiver<N> {
+    pub async fn recv<T: Deserializable>(&mut self) -> Result<T, ReadError<T>> {
+        let mut output = T::deserializer();
+        let mut first = true;
+        loop {
+            let (payload, last) = self.chunk(first).await?;
+            output.update(&payload).map_err(|e| ReadError::UpdateError(e))?;
+            if last {
+                break Ok(output.finalize().map_err(|e| ReadError::FinalizeError(e))?);
+            }
+            first = false;
+        }
+    }
+
+    pub async fn recv_limited<T: Deserializable>(&mut self, limit: usize) -> Result<(Option<T>, usize), ReadError<T>> {
+        let mut output = Some(T::deserializer());
+        let mut size = 0;
+        let mut first = true;
+        loop {
+            let (payload, last) = self.chunk(first).await?;
+            size += payload.len();
+            if size > limit {
+                output = None;
+            }
+            if let Some(output) = &mut output {
+                output.update(&payload).map_err(|e| ReadError::UpdateError(e))?;
+            }
+            if last {
+                break match output {
+                    Some(output) => Ok((Some(output.finalize().map_err(|e| ReadError::FinalizeError(e))?), size)),
+                    None => Ok((None, size)),
+                };
+            }
+            first = false;
+        }
+    }
+
+    async fn chunk<T: Deserializable>(&mut self, first: bool) -> Result<(Vec<u8>, bool), ReadError<T>> {
+        let chunk = match self.read_timeout.filter(|_| !first) {
+            None => self.next_chunk().await,
+            Some(timeout) => time::timeout(timeout, self.next_chunk()).await.map_err(|_| ReadError::Timeout)?,
+        };
+        chunk.ok_or(ReadError::Closed)
+    }
+
+    async fn next_chunk(&mut self) -> Option<(Vec<u8>, bool)> {
+        loop {
+            let notified = self.readable.notified();
+            {
+                let mut state = self.shared.state.lock().unwrap();
+                let channel = state.channels.entry(self.id).or_default();
+                if let Some(chunk) = channel.incoming.pop_front() {
+                    channel.consumed += 1;
+                    if channel.consumed >= WINDOW / 2 {
+                        let credit = std::mem::take(&mut channel.consumed);
+                        state.grants.push_back((self.id, credit));
+                        drop(state);
+                        self.shared.writable.notify_one();
+                    }
+                    return Some(chunk);
+                }
+                if state.eof || state.error.is_some() {
+                    return None;
+                }
+            }
+            notified.await;
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct Closed;
+
+#[derive(Debug)]
+pub enum MuxError {
+    Read(BlockError),
+    Write(WriteError),
+    Window,
+    Channel(u32),
+    Timeout,
+    Drain,
+    Join(JoinError),
+}
+
+impl From<JoinError> for MuxError {
+    fn from(value: JoinError) -> Self {
+        Self::Join(value)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::{duplex, DuplexStream};
+
+    use crate::stream::BlockStream;
+
+    use super::*;
+
+    async fn pair() -> (Mux<1024>, Mux<1024>) {
+        let config = Config::default();
+        let (client, server) = duplex(1 << 16);
+        let (client, server) = tokio::join!(
+            BlockStream::<1024, DuplexStream>::connect(client, &config),
+            BlockStream::<1024, DuplexStream>::accept(server, &config),
+        );
+        let (client_reader, client_writer) = client.unwrap().split();
+        let (server_reader, server_writer) = server.unwrap().split();
+        (Mux::new(client_reader, client_writer, &config), Mux::new(server_reader, server_writer, &config))
+    }
+
+    #[tokio::test]
+    async fn long_messages_wait_for_room() {
+        let (client, server) = pair().await;
+        let (sender, _) = client.channel(CHAT);
+        let (_, mut receiver) = server.channel(CHAT);
+        let long = "block ".repeat(20_000);
+        assert!(Encoded::new::<1024, _>(long.as_str()).blocks() > QUEUE + WINDOW as usize);
+        let sending = task::spawn({
+            let long = long.clone();
+            async move { sender.send(long.as_str()).await.is_ok() }
+        });
+        time::sleep(Duration::from_millis(50)).await;
+        assert!(!sending.is_finished());
+        assert!(client.shared.state.lock().unwrap().channels[&CHAT].outgoing.len() <= QUEUE);
+        assert_eq!(receiver.recv::<String>().await.unwrap(), long);
+        assert!(sending.await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn senders_on_one_channel_do_not_interleave() {
+        let (client, server) = pair().await;
+        let (first, _) = client.channel(BULK);
+        let second = first.clone();
+        let (_, mut receiver) = server.channel(BULK);
+        let (a, b) = ("a".repeat(50_000), "b".repeat(50_000));
+        let sending = [(first, a.clone()), (second, b.clone())]
+            .map(|(sender, message)| task::spawn(async move { sender.send(message.as_str()).await.is_ok() }));
+        let mut received = [receiver.recv::<String>().await.unwrap(), receiver.recv::<String>().await.unwrap()];
+        received.sort();
+        assert_eq!(received, [a, b]);
+        for sending in sending {
+            assert!(sending.await.unwrap());
+        }
+    }
+}
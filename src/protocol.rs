@@ -0,0 +1,740 @@
+use std::{
+    net::{IpAddr, SocketAddr},
+    time::{Duration, SystemTime},
+};
+
+use crate::serialization::wire::{wire, Wire, WireReader, WireWriter};
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum Target {
+    User(String),
+    Room(String),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum Origin {
+    User(String),
+    Room {
+        room: String,
+        from: String,
+    },
+}
+
+impl Origin {
+    pub fn sender(&self) -> &str {
+        match self {
+            Origin::User(name) => name,
+            Origin::Room { from, .. } => from,
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct MessageId(pub u64);
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Envelope {
+    pub seq: u64,
+    pub target: Target,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Delivery {
+    pub id: MessageId,
+    pub origin: Origin,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum Cursor {
+    Latest,
+    Before(MessageId),
+    After(MessageId),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HistoryEntry {
+    pub delivery: Delivery,
+    pub sent: SystemTime,
+    pub message: Vec<u8>,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum Status {
+    Delivered,
+    Stored,
+    Read,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Presence {
+    Offline,
+    Away,
+    Online,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum Reason {
+    UnknownRecipient,
+    Blocked,
+    NotMember,
+    TooLarge,
+    RateLimited,
+    Muted,
+    Filtered,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum Ban {
+    User(String),
+    Ip(IpAddr),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Admin {
+    Sessions,
+    Kick(String),
+    Ban {
+        ban: Ban,
+        duration: Option<Duration>,
+    },
+    Unban(Ban),
+    Mute {
+        user: String,
+        duration: Option<Duration>,
+    },
+    Unmute(String),
+    Shutdown {
+        reason: Option<String>,
+        reconnect: Option<Duration>,
+    },
+    Lockouts,
+    Unlock(Ban),
+    Stats,
+    Reload,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Session {
+    pub user: String,
+    pub connection: u64,
+    pub addr: Option<SocketAddr>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Lockout {
+    pub key: Ban,
+    pub failures: u32,
+    pub remaining: Duration,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConnectionStats {
+    pub session: Session,
+    pub connected: SystemTime,
+    pub sent: u64,
+    pub received: u64,
+    pub bytes_sent: u64,
+    pub bytes_received: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AdminReply {
+    Sessions(Vec<Session>),
+    Done(bool),
+    Denied,
+    Lockouts(Vec<Lockout>),
+    Stats(Vec<ConnectionStats>),
+    Failed(String),
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum RoomAction {
+    Create,
+    Join,
+    Leave,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Request {
+    Room(RoomAction, String),
+    Read {
+        id: MessageId,
+        from: String,
+    },
+    Block {
+        user: String,
+        blocked: bool,
+    },
+    Subscribe {
+        user: String,
+        subscribed: bool,
+    },
+    Presence {
+        presence: Presence,
+        text: Option<String>,
+    },
+    Admin(Admin),
+    History {
+        with: Target,
+        cursor: Cursor,
+        limit: u64,
+    },
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Notice {
+    Room {
+        action: RoomAction,
+        room: String,
+        ok: bool,
+    },
+    Accepted {
+        seq: u64,
+        id: MessageId,
+    },
+    Status {
+        id: MessageId,
+        user: String,
+        status: Status,
+    },
+    Rejected {
+        seq: u64,
+        reason: Reason,
+    },
+    Block {
+        user: String,
+        blocked: bool,
+        ok: bool,
+    },
+    Presence {
+        user: String,
+        presence: Presence,
+        text: Option<String>,
+    },
+    Admin(AdminReply),
+    Shutdown {
+        reason: Option<String>,
+        reconnect: Option<Duration>,
+    },
+    History {
+        with: Target,
+        entries: Vec<HistoryEntry>,
+        more: bool,
+    },
+}
+
+impl Wire for Target {
+    fn write(&self, writer: &mut WireWriter) {
+        match self {
+            Target::User(name) => writer.u8(0).str(name),
+            Target::Room(room) => writer.u8(1).str(room),
+        };
+    }
+
+    fn read(reader: &mut WireReader<'_>) -> Option<Self> {
+        match reader.u8()? {
+            0 => Some(Target::User(reader.string()?)),
+            1 => Some(Target::Room(reader.string()?)),
+            _ => None,
+        }
+    }
+}
+
+impl Wire for Origin {
+    fn write(&self, writer: &mut WireWriter) {
+        match self {
+            Origin::User(name) => writer.u8(0).str(name),
+            Origin::Room { room, from } => writer.u8(1).str(room).str(from),
+        };
+    }
+
+    fn read(reader: &mut WireReader<'_>) -> Option<Self> {
+        match reader.u8()? {
+            0 => Some(Origin::User(reader.string()?)),
+            1 => Some(Origin::Room {
+                room: reader.string()?,
+                from: reader.string()?,
+            }),
+            _ => None,
+        }
+    }
+}
+
+impl Wire for MessageId {
+    fn write(&self, writer: &mut WireWriter) {
+        writer.u64(self.0);
+    }
+
+    fn read(reader: &mut WireReader<'_>) -> Option<Self> {
+        Some(MessageId(reader.u64()?))
+    }
+}
+
+impl Wire for Envelope {
+    fn write(&self, writer: &mut WireWriter) {
+        writer.u64(self.seq).wire(&self.target);
+    }
+
+    fn read(reader: &mut WireReader<'_>) -> Option<Self> {
+        Some(Envelope {
+            seq: reader.u64()?,
+            target: reader.wire()?,
+        })
+    }
+}
+
+impl Wire for Delivery {
+    fn write(&self, writer: &mut WireWriter) {
+        writer.wire(&self.id).wire(&self.origin);
+    }
+
+    fn read(reader: &mut WireReader<'_>) -> Option<Self> {
+        Some(Delivery {
+            id: reader.wire()?,
+            origin: reader.wire()?,
+        })
+    }
+}
+
+impl Wire for Cursor {
+    fn write(&self, writer: &mut WireWriter) {
+        match self {
+            Cursor::Latest => writer.u8(0),
+            Cursor::Before(id) => writer.u8(1).wire(id),
+            Cursor::After(id) => writer.u8(2).wire(id),
+        };
+    }
+
+    fn read(reader: &mut WireReader<'_>) -> Option<Self> {
+        match reader.u8()? {
+            0 => Some(Cursor::Latest),
+            1 => Some(Cursor::Before(reader.wire()?)),
+            2 => Some(Cursor::After(reader.wire()?)),
+            _ => None,
+        }
+    }
+}
+
+impl Wire for HistoryEntry {
+    fn write(&self, writer: &mut WireWriter) {
+        writer.wire(&self.delivery).wire(&self.sent).bytes(&self.message);
+    }
+
+    fn read(reader: &mut WireReader<'_>) -> Option<Self> {
+        Some(HistoryEntry {
+            delivery: reader.wire()?,
+            sent: reader.wire()?,
+            message: reader.bytes()?.to_vec(),
+        })
+    }
+}
+
+impl Wire for Status {
+    fn write(&self, writer: &mut WireWriter) {
+        writer.u8(match self {
+            Status::Delivered => 0,
+            Status::Stored => 1,
+            Status::Read => 2,
+        });
+    }
+
+    fn read(reader: &mut WireReader<'_>) -> Option<Self> {
+        match reader.u8()? {
+            0 => Some(Status::Delivered),
+            1 => Some(Status::Stored),
+            2 => Some(Status::Read),
+            _ => None,
+        }
+    }
+}
+
+impl Wire for Presence {
+    fn write(&self, writer: &mut WireWriter) {
+        writer.u8(match self {
+            Presence::Offline => 0,
+            Presence::Away => 1,
+            Presence::Online => 2,
+        });
+    }
+
+    fn read(reader: &mut WireReader<'_>) -> Option<Self> {
+        match reader.u8()? {
+            0 => Some(Presence::Offline),
+            1 => Some(Presence::Away),
+            2 => Some(Presence::Online),
+            _ => None,
+        }
+    }
+}
+
+impl Wire for Reason {
+    fn write(&self, writer: &mut WireWriter) {
+        writer.u8(match self {
+            Reason::UnknownRecipient => 0,
+            Reason::Blocked => 1,
+            Reason::NotMember => 2,
+            Reason::TooLarge => 3,
+            Reason::RateLimited => 4,
+            Reason::Muted => 5,
+            Reason::Filtered => 6,
+        });
+    }
+
+    fn read(reader: &mut WireReader<'_>) -> Option<Self> {
+        match reader.u8()? {
+            0 => Some(Reason::UnknownRecipient),
+            1 => Some(Reason::Blocked),
+            2 => Some(Reason::NotMember),
+            3 => Some(Reason::TooLarge),
+            4 => Some(Reason::RateLimited),
+            5 => Some(Reason::Muted),
+            6 => Some(Reason::Filtered),
+            _ => None,
+        }
+    }
+}
+
+impl Wire for Ban {
+    fn write(&self, writer: &mut WireWriter) {
+        match self {
+            Ban::User(user) => writer.u8(0).str(user),
+            Ban::Ip(ip) => writer.u8(1).wire(ip),
+        };
+    }
+
+    fn read(reader: &mut WireReader<'_>) -> Option<Self> {
+        match reader.u8()? {
+            0 => Some(Ban::User(reader.string()?)),
+            1 => Some(Ban::Ip(reader.wire()?)),
+            _ => None,
+        }
+    }
+}
+
+impl Wire for Admin {
+    fn write(&self, writer: &mut WireWriter) {
+        match self {
+            Admin::Sessions => writer.u8(0),
+            Admin::Kick(user) => writer.u8(1).str(user),
+            Admin::Ban { ban, duration } => writer.u8(2).wire(ban).wire(duration),
+            Admin::Unban(ban) => writer.u8(3).wire(ban),
+            Admin::Mute { user, duration } => writer.u8(4).str(user).wire(duration),
+            Admin::Unmute(user) => writer.u8(5).str(user),
+            Admin::Shutdown { reason, reconnect } => writer.u8(6).wire(reason).wire(reconnect),
+            Admin::Lockouts => writer.u8(7),
+            Admin::Unlock(key) => writer.u8(8).wire(key),
+            Admin::Stats => writer.u8(9),
+            Admin::Reload => writer.u8(10),
+        };
+    }
+
+    fn read(reader: &mut WireReader<'_>) -> Option<Self> {
+        match reader.u8()? {
+            0 => Some(Admin::Sessions),
+            1 => Some(Admin::Kick(reader.string()?)),
+            2 => Some(Admin::Ban {
+                ban: reader.wire()?,
+                duration: reader.wire()?,
+            }),
+            3 => Some(Admin::Unban(reader.wire()?)),
+            4 => Some(Admin::Mute {
+                user: reader.string()?,
+                duration: reader.wire()?,
+            }),
+            5 => Some(Admin::Unmute(reader.string()?)),
+            6 => Some(Admin::Shutdown {
+                reason: reader.wire()?,
+                reconnect: reader.wire()?,
+            }),
+            7 => Some(Admin::Lockouts),
+            8 => Some(Admin::Unlock(reader.wire()?)),
+            9 => Some(Admin::Stats),
+            10 => Some(Admin::Reload),
+            _ => None,
+        }
+    }
+}
+
+impl Wire for Session {
+    fn write(&self, writer: &mut WireWriter) {
+        writer.str(&self.user).u64(self.connection).wire(&self.addr);
+    }
+
+    fn read(reader: &mut WireReader<'_>) -> Option<Self> {
+        Some(Session {
+            user: reader.string()?,
+            connection: reader.u64()?,
+            addr: reader.wire()?,
+        })
+    }
+}
+
+impl Wire for Lockout {
+    fn write(&self, writer: &mut WireWriter) {
+        writer.wire(&self.key).u64(self.failures as u64).wire(&self.remaining);
+    }
+
+    fn read(reader: &mut WireReader<'_>) -> Option<Self> {
+        Some(Lockout {
+            key: reader.wire()?,
+            failures: reader.u64()?.try_into().ok()?,
+            remaining: reader.wire()?,
+        })
+    }
+}
+
+impl Wire for ConnectionStats {
+    fn write(&self, writer: &mut WireWriter) {
+        writer.wire(&self.session)
+            .wire(&self.connected)
+            .u64(self.sent)
+            .u64(self.received)
+            .u64(self.bytes_sent)
+            .u64(self.bytes_received);
+    }
+
+    fn read(reader: &mut WireReader<'_>) -> Option<Self> {
+        Some(ConnectionStats {
+            session: reader.wire()?,
+            connected: reader.wire()?,
+            sent: reader.u64()?,
+            received: reader.u64()?,
+            bytes_sent: reader.u64()?,
+            bytes_received: reader.u64()?,
+        })
+    }
+}
+
+impl Wire for AdminReply {
+    fn write(&self, writer: &mut WireWriter) {
+        match self {
+            AdminReply::Sessions(sessions) => writer.u8(0).wire(sessions),
+            AdminReply::Done(ok) => writer.u8(1).bool(*ok),
+            AdminReply::Denied => writer.u8(2),
+            AdminReply::Lockouts(lockouts) => writer.u8(3).wire(lockouts),
+            AdminReply::Stats(stats) => writer.u8(4).wire(stats),
+            AdminReply::Failed(error) => writer.u8(5).str(error),
+        };
+    }
+
+    fn read(reader: &mut WireReader<'_>) -> Option<Self> {
+        match reader.u8()? {
+            0 => Some(AdminReply::Sessions(reader.wire()?)),
+            1 => Some(AdminReply::Done(reader.bool()?)),
+            2 => Some(AdminReply::Denied),
+            3 => Some(AdminReply::Lockouts(reader.wire()?)),
+            4 => Some(AdminReply::Stats(reader.wire()?)),
+            5 => Some(AdminReply::Failed(reader.string()?)),
+            _ => None,
+        }
+    }
+}
+
+impl Wire for RoomAction {
+    fn write(&self, writer: &mut WireWriter) {
+        writer.u8(match self {
+            RoomAction::Create => 0,
+            RoomAction::Join => 1,
+            RoomAction::Leave => 2,
+        });
+    }
+
+    fn read(reader: &mut WireReader<'_>) -> Option<Self> {
+        match reader.u8()? {
+            0 => Some(RoomAction::Create),
+            1 => Some(RoomAction::Join),
+            2 => Some(RoomAction::Leave),
+            _ => None,
+        }
+    }
+}
+
+impl Wire for Request {
+    fn write(&self, writer: &mut WireWriter) {
+        match self {
+            Request::Room(action, room) => writer.u8(0).wire(action).str(room),
+            Request::Read { id, from } => writer.u8(1).wire(id).str(from),
+            Request::Block { user, blocked } => writer.u8(2).str(user).bool(*blocked),
+            Request::Subscribe { user, subscribed } => writer.u8(3).str(user).bool(*subscribed),
+            Request::Presence { presence, text } => writer.u8(4).wire(presence).wire(text),
+            Request::Admin(admin) => writer.u8(5).wire(admin),
+            Request::History { with, cursor, limit } => writer.u8(6).wire(with).wire(cursor).u64(*limit),
+        };
+    }
+
+    fn read(reader: &mut WireReader<'_>) -> Option<Self> {
+        match reader.u8()? {
+            0 => Some(Request::Room(reader.wire()?, reader.string()?)),
+            1 => Some(Request::Read {
+                id: reader.wire()?,
+                from: reader.string()?,
+            }),
+            2 => Some(Request::Block {
+                user: reader.string()?,
+                blocked: reader.bool()?,
+            }),
+            3 => Some(Request::Subscribe {
+                user: reader.string()?,
+                subscribed: reader.bool()?,
+            }),
+            4 => Some(Request::Presence {
+                presence: reader.wire()?,
+                text: reader.wire()?,
+            }),
+            5 => Some(Request::Admin(reader.wire()?)),
+            6 => Some(Request::History {
+                with: reader.wire()?,
+                cursor: reader.wire()?,
+                limit: reader.u64()?,
+            }),
+            _ => None,
+        }
+    }
+}
+
+impl Wire for Notice {
+    fn write(&self, writer: &mut WireWriter) {
+        match self {
+            Notice::Room { action, room, ok } => writer.u8(0).wire(action).str(room).bool(*ok),
+            Notice::Accepted { seq, id } => writer.u8(1).u64(*seq).wire(id),
+            Notice::Status { id, user, status } => writer.u8(2).wire(id).str(user).wire(status),
+            Notice::Rejected { seq, reason } => writer.u8(3).u64(*seq).wire(reason),
+            Notice::Block { user, blocked, ok } => writer.u8(4).str(user).bool(*blocked).bool(*ok),
+            Notice::Presence { user, presence, text } => writer.u8(5).str(user).wire(presence).wire(text),
+            Notice::Admin(reply) => writer.u8(6).wire(reply),
+            Notice::Shutdown { reason, reconnect } => writer.u8(7).wire(reason).wire(reconnect),
+            Notice::History { with, entries, more } => writer.u8(8).wire(with).wire(entries).bool(*more),
+        };
+    }
+
+    fn read(reader: &mut WireReader<'_>) -> Option<Self> {
+        match reader.u8()? {
+            0 => Some(Notice::Room {
+                action: reader.wire()?,
+                room: reader.string()?,
+                ok: reader.bool()?,
+            }),
+            1 => Some(Notice::Accepted {
+                seq: reader.u64()?,
+                id: reader.wire()?,
+            }),
+            2 => Some(Notice::Status {
+                id: reader.wire()?,
+                user: reader.string()?,
+                status: reader.wire()?,
+            }),
+            3 => Some(Notice::Rejected {
+                seq: reader.u64()?,
+                reason: reader.wire()?,
+            }),
+            4 => Some(Notice::Block {
+                user: reader.string()?,
+                blocked: reader.bool()?,
+                ok: reader.bool()?,
+            }),
+            5 => Some(Notice::Presence {
+                user: reader.string()?,
+                presence: reader.wire()?,
+                text: reader.wire()?,
+            }),
+            6 => Some(Notice::Admin(reader.wire()?)),
+            7 => Some(Notice::Shutdown {
+                reason: reader.wire()?,
+                reconnect: reader.wire()?,
+            }),
+            8 => Some(Notice::History {
+                with: reader.wire()?,
+                entries: reader.wire()?,
+                more: reader.bool()?,
+            }),
+            _ => None,
+        }
+    }
+}
+
+wire!(Target, Origin, Envelope, Delivery, Request, Notice);
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        fmt::Debug,
+        net::Ipv4Addr,
+        time::UNIX_EPOCH,
+    };
+
+    use crate::serialization::{from_bytes, to_bytes, Deserializable, Serializable};
+
+    use super::*;
+
+    fn round_trip<T: Serializable + Deserializable + PartialEq + Debug>(value: T) {
+        let bytes = to_bytes(&value);
+        assert_eq!(from_bytes::<T>(&bytes), Some(value));
+        assert_eq!(from_bytes::<T>(&bytes[..bytes.len() - 1]), None);
+    }
+
+    fn session() -> Session {
+        Session {
+            user: "alice".to_owned(),
+            connection: 3,
+            addr: Some(SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 5000)),
+        }
+    }
+
+    #[test]
+    fn envelopes_and_deliveries() {
+        round_trip(Envelope { seq: 1, target: Target::User("bob".to_owned()) });
+        round_trip(Envelope { seq: u64::MAX, target: Target::Room("lobby".to_owned()) });
+        round_trip(Delivery { id: MessageId(9), origin: Origin::User("alice".to_owned()) });
+        round_trip(Delivery { id: MessageId(10), origin: Origin::Room { room: "lobby".to_owned(), from: "alice".to_owned() } });
+    }
+
+    #[test]
+    fn requests() {
+        round_trip(Request::Room(RoomAction::Join, "lobby".to_owned()));
+        round_trip(Request::Read { id: MessageId(4), from: "bob".to_owned() });
+        round_trip(Request::Block { user: "bob".to_owned(), blocked: true });
+        round_trip(Request::Subscribe { user: "bob".to_owned(), subscribed: false });
+        round_trip(Request::Presence { presence: Presence::Away, text: Some("lunch".to_owned()) });
+        round_trip(Request::Presence { presence: Presence::Online, text: None });
+        round_trip(Request::History { with: Target::Room("lobby".to_owned()), cursor: Cursor::Before(MessageId(7)), limit: 50 });
+        round_trip(Request::Admin(Admin::Ban { ban: Ban::Ip(IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1))), duration: Some(Duration::from_secs(60)) }));
+        round_trip(Request::Admin(Admin::Mute { user: "bob".to_owned(), duration: None }));
+        round_trip(Request::Admin(Admin::Shutdown { reason: Some("upgrade".to_owned()), reconnect: Some(Duration::from_secs(30)) }));
+        round_trip(Request::Admin(Admin::Unlock(Ban::User("bob".to_owned()))));
+        round_trip(Request::Admin(Admin::Reload));
+    }
+
+    #[test]
+    fn notices() {
+        round_trip(Notice::Accepted { seq: 2, id: MessageId(5) });
+        round_trip(Notice::Status { id: MessageId(5), user: "bob".to_owned(), status: Status::Read });
+        round_trip(Notice::Rejected { seq: 3, reason: Reason::RateLimited });
+        round_trip(Notice::Presence { user: "bob".to_owned(), presence: Presence::Offline, text: None });
+        round_trip(Notice::Shutdown { reason: None, reconnect: Some(Duration::from_secs(5)) });
+        round_trip(Notice::History {
+            with: Target::User("bob".to_owned()),
+            entries: vec![HistoryEntry {
+                delivery: Delivery { id: MessageId(1), origin: Origin::User("bob".to_owned()) },
+                sent: UNIX_EPOCH + Duration::from_millis(1_700_000_000_123),
+                message: vec![7; 3000],
+            }],
+            more: true,
+        });
+    }
+
+    #[test]
+    fn admin_replies() {
+        round_trip(Notice::Admin(AdminReply::Sessions(vec![session(), Session { addr: None, ..session() }])));
+        round_trip(Notice::Admin(AdminReply::Lockouts(vec![Lockout { key: Ban::User("bob".to_owned()), failures: 3, remaining: Duration::from_secs(120) }])));
+        round_trip(Notice::Admin(AdminReply::Stats(vec![ConnectionStats {
+            session: session(),
+            connected: UNIX_EPOCH + Duration::from_secs(1_700_000_000),
+            sent: 1,
+            received: 2,
+            bytes_sent: 3,
+            bytes_received: 4,
+        }])));
+        round_trip(Notice::Admin(AdminReply::Failed("no".to_owned())));
+        round_trip(Notice::Admin(AdminReply::Denied));
+    }
+}
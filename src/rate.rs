@@ -0,0 +1,455 @@
+use std::{
+    collections::HashMap,
+    hash::Hash,
+    net::IpAddr,
+    sync::{Mutex, MutexGuard},
+    time::Duration,
+};
+
+use tokio::time::Instant;
+
+use crate::{
+    db::User,
+    protocol::{Ban, Lockout},
+};
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct RateLimit {
+    per_second: f64,
+    burst: f64,
+}
+
+impl RateLimit {
+    pub fn new(per_second: u32, burst: u32) -> Self {
+        Self {
+            per_second: per_second as f64,
+            burst: burst.max(1) as f64,
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum Scope {
+    Connection,
+    User,
+    Ip,
+}
+
+pub(crate) struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+impl Bucket {
+    fn new(limit: &RateLimit) -> Self {
+        Self {
+            tokens: limit.burst,
+            updated: Instant::now(),
+        }
+    }
+
+    fn refill(&mut self, limit: &RateLimit) {
+        let now = Instant::now();
+        self.tokens = (self.tokens + (now - self.updated).as_secs_f64() * limit.per_second).min(limit.burst);
+        self.updated = now;
+    }
+
+    // anything costing more than the burst is charged the whole burst, otherwise it could never pass
+    fn allows(&mut self, limit: &RateLimit, cost: f64) -> bool {
+        self.refill(limit);
+        self.tokens >= cost.min(limit.burst)
+    }
+
+    fn charge(&mut self, limit: &RateLimit, cost: f64) {
+        self.refill(limit);
+        self.tokens -= cost.min(limit.burst);
+    }
+}
+
+// keys tracked per map, past which the least useful entries are given up
+const TRACKED: usize = 4096;
+
+struct Buckets<K> {
+    limit: RateLimit,
+    buckets: Mutex<HashMap<K, Bucket>>,
+}
+
+impl<K: Eq + Hash + Clone> Buckets<K> {
+    // full buckets behave like missing ones so they go first, then the fullest remaining bucket
+    fn make_room(&self, buckets: &mut HashMap<K, Bucket>) {
+        if buckets.len() < TRACKED {
+            return;
+        }
+        buckets.retain(|_, bucket| {
+            bucket.refill(&self.limit);
+            bucket.tokens < self.limit.burst
+        });
+        if buckets.len() >= TRACKED {
+            let fullest = buckets.iter().max_by(|(_, a), (_, b)| a.tokens.total_cmp(&b.tokens)).map(|(key, _)| key.clone());
+            if let Some(key) = fullest {
+                buckets.remove(&key);
+            }
+        }
+    }
+}
+
+struct Scoped<'a, K> {
+    key: K,
+    buckets: &'a Buckets<K>,
+    held: MutexGuard<'a, HashMap<K, Bucket>>,
+}
+
+impl<K: Eq + Hash + Clone> Scoped<'_, K> {
+    fn allows(&mut self, cost: f64) -> bool {
+        match self.held.get_mut(&self.key) {
+            Some(bucket) => bucket.allows(&self.buckets.limit, cost),
+            None => true,
+        }
+    }
+
+    fn charge(&mut self, cost: f64) {
+        if !self.held.contains_key(&self.key) {
+            self.buckets.make_room(&mut self.held);
+        }
+        self.held.entry(self.key.clone()).or_insert_with(|| Bucket::new(&self.buckets.limit)).charge(&self.buckets.limit, cost)
+    }
+}
+
+// the buckets stay locked from the check to the charge, so concurrent connections cannot spend the same tokens
+struct Held<'a, 'b> {
+    connection: Option<(&'b mut Bucket, &'a RateLimit)>,
+    user: Option<Scoped<'a, User>>,
+    ip: Option<Scoped<'a, IpAddr>>,
+}
+
+impl Held<'_, '_> {
+    fn allows(&mut self, cost: f64) -> bool {
+        self.connection.as_mut().is_none_or(|(bucket, limit)| bucket.allows(limit, cost))
+            && self.user.as_mut().is_none_or(|user| user.allows(cost))
+            && self.ip.as_mut().is_none_or(|ip| ip.allows(cost))
+    }
+
+    fn charge(&mut self, cost: f64) {
+        if let Some((bucket, limit)) = &mut self.connection {
+            bucket.charge(limit, cost);
+        }
+        if let Some(user) = &mut self.user {
+            user.charge(cost);
+        }
+        if let Some(ip) = &mut self.ip {
+            ip.charge(cost);
+        }
+    }
+}
+
+#[derive(Default)]
+pub(crate) struct Limits {
+    connection: Option<RateLimit>,
+    user: Option<Buckets<User>>,
+    ip: Option<Buckets<IpAddr>>,
+}
+
+impl Limits {
+    pub fn set(&mut self, scope: Scope, limit: RateLimit) {
+        match scope {
+            Scope::Connection => self.connection = Some(limit),
+            Scope::User => self.user = Some(Buckets { limit, buckets: Default::default() }),
+            Scope::Ip => self.ip = Some(Buckets { limit, buckets: Default::default() }),
+        }
+    }
+
+    pub fn limits(&self, scope: Scope) -> bool {
+        match scope {
+            Scope::Connection => self.connection.is_some(),
+            Scope::User => self.user.is_some(),
+            Scope::Ip => self.ip.is_some(),
+        }
+    }
+
+    pub fn bucket(&self) -> Option<Bucket> {
+        self.connection.as_ref().map(Bucket::new)
+    }
+
+    fn hold<'a, 'b>(&'a self, bucket: Option<&'b mut Bucket>, user: Option<&User>, ip: Option<IpAddr>) -> Held<'a, 'b> {
+        Held {
+            connection: bucket.zip(self.connection.as_ref()),
+            user: user.zip(self.user.as_ref()).map(|(user, buckets)| Scoped { key: user.clone(), buckets, held: buckets.buckets.lock().unwrap() }),
+            ip: ip.zip(self.ip.as_ref()).map(|(ip, buckets)| Scoped { key: ip, buckets, held: buckets.buckets.lock().unwrap() }),
+        }
+    }
+
+    pub fn allows(&self, bucket: Option<&mut Bucket>, user: Option<&User>, ip: Option<IpAddr>, cost: usize) -> bool {
+        self.hold(bucket, user, ip).allows(cost as f64)
+    }
+
+    // no scope is charged unless every scope has room
+    pub fn take(&self, bucket: Option<&mut Bucket>, user: Option<&User>, ip: Option<IpAddr>, cost: usize) -> bool {
+        let mut held = self.hold(bucket, user, ip);
+        if !held.allows(cost as f64) {
+            return false;
+        }
+        held.charge(cost as f64);
+        true
+    }
+}
+
+struct Failures {
+    count: u32,
+    until: Instant,
+}
+
+#[derive(Default)]
+pub(crate) struct Lockouts {
+    backoff: Option<(Duration, Duration)>,
+    lockout: Option<(u32, Duration)>,
+    failures: Mutex<HashMap<Ban, Failures>>,
+}
+
+impl Lockouts {
+    pub fn backoff(&mut self, base: Duration, max: Duration) {
+        self.backoff = Some((base, max));
+    }
+
+    pub fn lockout(&mut self, failures: u32, duration: Duration) {
+        self.lockout = Some((failures.max(1), duration));
+    }
+
+    fn forget(&self) -> Duration {
+        self.lockout.map(|(_, duration)| duration)
+            .into_iter()
+            .chain(self.backoff.map(|(_, max)| max))
+            .max()
+            .unwrap_or_default()
+    }
+
+    pub fn locked(&self, key: &Ban) -> bool {
+        self.failures.lock().unwrap().get(key).is_some_and(|failures| failures.until > Instant::now())
+    }
+
+    pub fn fail(&self, key: Ban) {
+        if self.backoff.is_none() && self.lockout.is_none() {
+            return;
+        }
+        let now = Instant::now();
+        let forget = self.forget();
+        let mut failures = self.failures.lock().unwrap();
+        if failures.len() >= TRACKED && !failures.contains_key(&key) {
+            failures.retain(|_, failures| failures.until + forget > now);
+            // a fresh failure ends sooner than a lockout, so the entry closest to expiring is given up first
+            if failures.len() >= TRACKED {
+                let soonest = failures.iter().min_by_key(|(_, failures)| failures.until).map(|(key, _)| key.clone());
+                if let Some(key) = soonest {
+                    failures.remove(&key);
+                }
+            }
+        }
+        let failures = failures.entry(key).or_insert(Failures { count: 0, until: now });
+        if failures.until + forget <= now {
+            failures.count = 0;
+        }
+        failures.count = failures.count.saturating_add(1);
+        let delay = match (self.lockout, self.backoff) {
+            (Some((threshold, duration)), _) if failures.count >= threshold => duration,
+            (_, Some((base, max))) => base.saturating_mul(2u32.saturating_pow(failures.count - 1)).min(max),
+            _ => Duration::ZERO,
+        };
+        failures.until = now + delay;
+    }
+
+    pub fn unlock(&self, key: &Ban) -> bool {
+        self.failures.lock().unwrap().remove(key).is_some()
+    }
+
+    pub fn list(&self) -> Vec<Lockout> {
+        let now = Instant::now();
+        self.failures.lock().unwrap()
+            .iter()
+            .filter(|(_, failures)| failures.until > now)
+            .map(|(key, failures)| Lockout {
+                key: key.clone(),
+                failures: failures.count,
+                remaining: failures.until - now,
+            })
+            .collect()
+    }
+}
+
+#[derive(Default)]
+pub(crate) struct RateLimits {
+    pub messages: Limits,
+    pub bytes: Limits,
+    pub logins: Limits,
+    pub lockouts: Lockouts,
+}
+
+impl RateLimits {
+    // a message is charged to both limits or to neither, messages are always locked before bytes
+    pub fn take_message(&self, messages: Option<&mut Bucket>, bytes: Option<&mut Bucket>, user: &User, ip: Option<IpAddr>, size: usize) -> bool {
+        let mut messages = self.messages.hold(messages, Some(user), ip);
+        let mut bytes = self.bytes.hold(bytes, Some(user), ip);
+        if !(messages.allows(1.0) && bytes.allows(size as f64)) {
+            return false;
+        }
+        messages.charge(1.0);
+        bytes.charge(size as f64);
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+
+    use tokio::time;
+
+    use super::*;
+
+    const IP: IpAddr = IpAddr::V4(Ipv4Addr::LOCALHOST);
+
+    fn user(name: &str) -> User {
+        User::new(name.to_owned())
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn bucket_refills_up_to_burst() {
+        let mut limits = Limits::default();
+        limits.set(Scope::Connection, RateLimit::new(1, 3));
+        let mut bucket = limits.bucket();
+        for _ in 0..3 {
+            assert!(limits.take(bucket.as_mut(), None, None, 1));
+        }
+        assert!(!limits.take(bucket.as_mut(), None, None, 1));
+        time::advance(Duration::from_secs(1)).await;
+        assert!(limits.take(bucket.as_mut(), None, None, 1));
+        assert!(!limits.take(bucket.as_mut(), None, None, 1));
+        time::advance(Duration::from_secs(60)).await;
+        for _ in 0..3 {
+            assert!(limits.take(bucket.as_mut(), None, None, 1));
+        }
+        assert!(!limits.take(bucket.as_mut(), None, None, 1));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn costs_above_burst_take_the_whole_bucket() {
+        let mut limits = Limits::default();
+        limits.set(Scope::User, RateLimit::new(10, 10));
+        assert!(limits.take(None, Some(&user("alice")), None, 100));
+        assert!(!limits.take(None, Some(&user("alice")), None, 1));
+        time::advance(Duration::from_secs(1)).await;
+        assert!(limits.take(None, Some(&user("alice")), None, 100));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn refused_takes_charge_no_scope() {
+        let mut limits = Limits::default();
+        limits.set(Scope::User, RateLimit::new(1, 1));
+        limits.set(Scope::Ip, RateLimit::new(1, 2));
+        assert!(limits.take(None, Some(&user("alice")), Some(IP), 1));
+        assert!(!limits.take(None, Some(&user("alice")), Some(IP), 1));
+        assert!(limits.take(None, Some(&user("bob")), Some(IP), 1));
+        assert!(!limits.take(None, Some(&user("carol")), Some(IP), 1));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn refused_messages_charge_neither_limit() {
+        let mut rates = RateLimits::default();
+        rates.messages.set(Scope::User, RateLimit::new(1, 2));
+        rates.bytes.set(Scope::User, RateLimit::new(1000, 100));
+        let alice = user("alice");
+        assert!(rates.take_message(None, None, &alice, None, 100));
+        assert!(!rates.take_message(None, None, &alice, None, 1));
+        time::advance(Duration::from_millis(100)).await;
+        assert!(rates.take_message(None, None, &alice, None, 1));
+        assert!(!rates.take_message(None, None, &alice, None, 1));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn buckets_are_capped() {
+        let mut limits = Limits::default();
+        limits.set(Scope::User, RateLimit::new(1, 1));
+        for index in 0..TRACKED + 10 {
+            assert!(limits.take(None, Some(&user(&index.to_string())), None, 1));
+        }
+        assert_eq!(limits.user.as_ref().unwrap().buckets.lock().unwrap().len(), TRACKED);
+        assert!(!limits.take(None, Some(&user(&(TRACKED + 9).to_string())), None, 1));
+    }
+
+    #[test]
+    fn scopes_are_reported() {
+        let mut limits = Limits::default();
+        limits.set(Scope::Ip, RateLimit::new(1, 1));
+        assert!(limits.limits(Scope::Ip));
+        assert!(!limits.limits(Scope::Connection));
+        assert!(!limits.limits(Scope::User));
+        assert!(limits.bucket().is_none());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn backoff_doubles_up_to_max() {
+        let mut lockouts = Lockouts::default();
+        lockouts.backoff(Duration::from_secs(1), Duration::from_secs(4));
+        let key = Ban::User("alice".to_owned());
+        for delay in [1, 2, 4, 4] {
+            lockouts.fail(key.clone());
+            assert!(lockouts.locked(&key));
+            time::advance(Duration::from_secs(delay) - Duration::from_millis(1)).await;
+            assert!(lockouts.locked(&key));
+            time::advance(Duration::from_millis(1)).await;
+            assert!(!lockouts.locked(&key));
+        }
+        assert_eq!(lockouts.list().len(), 0);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn lockout_after_threshold_until_unlocked() {
+        let mut lockouts = Lockouts::default();
+        lockouts.lockout(2, Duration::from_secs(60));
+        let key = Ban::Ip(IP);
+        lockouts.fail(key.clone());
+        assert!(!lockouts.locked(&key));
+        lockouts.fail(key.clone());
+        assert!(lockouts.locked(&key));
+        let list = lockouts.list();
+        assert_eq!(list.len(), 1);
+        assert_eq!(list[0].failures, 2);
+        assert!(lockouts.unlock(&key));
+        assert!(!lockouts.locked(&key));
+        assert!(!lockouts.unlock(&key));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn failures_are_forgotten() {
+        let mut lockouts = Lockouts::default();
+        lockouts.lockout(2, Duration::from_secs(60));
+        let key = Ban::User("alice".to_owned());
+        lockouts.fail(key.clone());
+        time::advance(Duration::from_secs(61)).await;
+        lockouts.fail(key.clone());
+        assert!(!lockouts.locked(&key));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn failures_are_capped_keeping_lockouts() {
+        let mut lockouts = Lockouts::default();
+        lockouts.backoff(Duration::from_secs(1), Duration::from_secs(1));
+        lockouts.lockout(2, Duration::from_secs(60));
+        let key = Ban::Ip(IP);
+        lockouts.fail(key.clone());
+        lockouts.fail(key.clone());
+        time::advance(Duration::from_millis(1)).await;
+        for index in 0..TRACKED + 10 {
+            lockouts.fail(Ban::User(index.to_string()));
+        }
+        assert_eq!(lockouts.failures.lock().unwrap().len(), TRACKED);
+        assert!(lockouts.locked(&key));
+    }
+
+    #[test]
+    fn nothing_configured_never_locks() {
+        let lockouts = Lockouts::default();
+        let key = Ban::User("alice".to_owned());
+        lockouts.fail(key.clone());
+        assert!(!lockouts.locked(&key));
+        assert!(lockouts.list().is_empty());
+    }
+}
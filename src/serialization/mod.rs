@@ -4,6 +4,7 @@ mod result;
 mod never;
 mod unit;
 mod string;
+pub mod wire;
 
 pub trait Serializable {
     type Serializer<'s>: Serializer where Self: 's;
@@ -38,6 +39,27 @@ impl<T> Serializable for &T where T: Serializable {
     }
 }
 
+pub fn to_bytes<T: Serializable>(value: &T) -> Vec<u8> {
+    let mut serializer = value.serializer();
+    let mut bytes = Vec::new();
+    let mut chunk = [0; 1024];
+    loop {
+        match serializer.fill(&mut chunk) {
+            None => bytes.extend_from_slice(&chunk),
+            Some(len) => {
+                bytes.extend_from_slice(&chunk[..len]);
+                break bytes;
+            }
+        }
+    }
+}
+
+pub fn from_bytes<T: Deserializable>(bytes: &[u8]) -> Option<T> {
+    let mut deserializer = T::deserializer();
+    deserializer.update(bytes).ok()?;
+    deserializer.finalize().ok()
+}
+
 // impl<T, U:Deserializer<T>> Deserialize for T {
 //     type Deserializer = U;
 // }
@@ -56,10 +78,11 @@ impl<'s> Buf<'s> {
     }
 
     pub fn fill(&mut self, buf: &mut [u8]) -> Option<usize> {
-        buf.iter_mut().zip(self.buf[self.pos..].iter().copied()).for_each(|(a, b)| *a = b);
-        let pos = self.pos;
-        self.pos += buf.len();
-        self.buf.len().checked_sub(pos)
+        let remaining = &self.buf[self.pos..];
+        let len = remaining.len().min(buf.len());
+        buf[..len].copy_from_slice(&remaining[..len]);
+        self.pos += len;
+        (remaining.len() <= buf.len()).then_some(len)
     }
 }
 
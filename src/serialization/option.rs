@@ -2,7 +2,7 @@ use crate::serialization::{Deserializable, Deserializer, Serializable, Serialize
 
 #[derive(Debug)]
 pub enum OptionSerializer<'s, T: Serializable + 's> {
-    Some(T::Serializer<'s>),
+    Some(T::Serializer<'s>, bool),
     None,
 }
 
@@ -13,10 +13,12 @@ impl<T: Serializable> Serializer for OptionSerializer<'_, T> {
                 buf[0] = 0;
                 Some(1)
             }
-            OptionSerializer::Some(t) => {
+            OptionSerializer::Some(t, tagged @ false) => {
                 buf[0] = 1;
+                *tagged = true;
                 t.fill(&mut buf[1..]).map(|t| t + 1)
             }
+            OptionSerializer::Some(t, true) => t.fill(buf),
         }
     }
 }
@@ -27,7 +29,7 @@ impl<T: Serializable> Serializable for Option<T> {
     fn serializer(&self) -> Self::Serializer<'_> {
         match self {
             None => OptionSerializer::None,
-            Some(t) => OptionSerializer::Some(t.serializer())
+            Some(t) => OptionSerializer::Some(t.serializer(), false)
         }
     }
 }
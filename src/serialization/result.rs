@@ -1,22 +1,26 @@
 use crate::serialization::{Deserializable, Deserializer, Serializable, Serializer};
 
 pub enum ResultSerializer<'s, T: Serializable + 's, E: Serializable + 's> {
-    Ok(T::Serializer<'s>),
-    Err(E::Serializer<'s>),
+    Ok(T::Serializer<'s>, bool),
+    Err(E::Serializer<'s>, bool),
 }
 
 impl<T: Serializable, E: Serializable> Serializer for ResultSerializer<'_, T, E> {
     fn fill(&mut self, buf: &mut [u8]) -> Option<usize> {
         match self {
-            ResultSerializer::Ok(t) => {
+            ResultSerializer::Ok(t, tagged @ false) => {
                 buf[0] = 1;
-                t.fill(&mut buf[1..])
+                *tagged = true;
+                t.fill(&mut buf[1..]).map(|t| t + 1)
             }
-            ResultSerializer::Err(t) => {
+            ResultSerializer::Err(t, tagged @ false) => {
                 buf[0] = 0;
-                t.fill(&mut buf[1..])
+                *tagged = true;
+                t.fill(&mut buf[1..]).map(|t| t + 1)
             }
-        }.map(|t| t + 1)
+            ResultSerializer::Ok(t, true) => t.fill(buf),
+            ResultSerializer::Err(t, true) => t.fill(buf),
+        }
     }
 }
 
@@ -25,8 +29,8 @@ impl<T: Serializable, E: Serializable> Serializable for Result<T, E> {
 
     fn serializer(&self) -> Self::Serializer<'_> {
         match self {
-            Ok(t) => ResultSerializer::Ok(t.serializer()),
-            Err(t) => ResultSerializer::Err(t.serializer()),
+            Ok(t) => ResultSerializer::Ok(t.serializer(), false),
+            Err(t) => ResultSerializer::Err(t.serializer(), false),
         }
     }
 }
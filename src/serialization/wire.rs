@@ -0,0 +1,269 @@
+use std::{
+    marker::PhantomData,
+    net::{IpAddr, SocketAddr},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use crate::serialization::{Buf, Deserializer, Serializer};
+
+pub trait Wire: Sized {
+    fn write(&self, writer: &mut WireWriter);
+
+    fn read(reader: &mut WireReader<'_>) -> Option<Self>;
+}
+
+#[derive(Default)]
+pub struct WireWriter(Vec<u8>);
+
+impl WireWriter {
+    pub fn u8(&mut self, value: u8) -> &mut Self {
+        self.0.push(value);
+        self
+    }
+
+    pub fn u16(&mut self, value: u16) -> &mut Self {
+        self.0.extend_from_slice(&value.to_be_bytes());
+        self
+    }
+
+    pub fn u64(&mut self, value: u64) -> &mut Self {
+        self.0.extend_from_slice(&value.to_be_bytes());
+        self
+    }
+
+    pub fn bool(&mut self, value: bool) -> &mut Self {
+        self.u8(value as u8)
+    }
+
+    pub fn bytes(&mut self, value: &[u8]) -> &mut Self {
+        self.u64(value.len() as u64);
+        self.0.extend_from_slice(value);
+        self
+    }
+
+    pub fn str(&mut self, value: &str) -> &mut Self {
+        self.bytes(value.as_bytes())
+    }
+
+    pub fn wire<T: Wire>(&mut self, value: &T) -> &mut Self {
+        value.write(self);
+        self
+    }
+}
+
+pub struct WireReader<'s>(&'s [u8]);
+
+impl<'s> WireReader<'s> {
+    pub fn u8(&mut self) -> Option<u8> {
+        let (&value, rest) = self.0.split_first()?;
+        self.0 = rest;
+        Some(value)
+    }
+
+    pub fn u16(&mut self) -> Option<u16> {
+        let value = self.0.get(..2)?.try_into().ok()?;
+        self.0 = &self.0[2..];
+        Some(u16::from_be_bytes(value))
+    }
+
+    pub fn u64(&mut self) -> Option<u64> {
+        let value = self.0.get(..8)?.try_into().ok()?;
+        self.0 = &self.0[8..];
+        Some(u64::from_be_bytes(value))
+    }
+
+    pub fn bool(&mut self) -> Option<bool> {
+        match self.u8()? {
+            0 => Some(false),
+            1 => Some(true),
+            _ => None,
+        }
+    }
+
+    pub fn bytes(&mut self) -> Option<&'s [u8]> {
+        let len = self.u64()?.try_into().ok()?;
+        let value = self.0.get(..len)?;
+        self.0 = &self.0[len..];
+        Some(value)
+    }
+
+    pub fn string(&mut self) -> Option<String> {
+        String::from_utf8(self.bytes()?.to_vec()).ok()
+    }
+
+    pub fn wire<T: Wire>(&mut self) -> Option<T> {
+        T::read(self)
+    }
+}
+
+impl Wire for String {
+    fn write(&self, writer: &mut WireWriter) {
+        writer.str(self);
+    }
+
+    fn read(reader: &mut WireReader<'_>) -> Option<Self> {
+        reader.string()
+    }
+}
+
+impl<T: Wire> Wire for Vec<T> {
+    fn write(&self, writer: &mut WireWriter) {
+        writer.u64(self.len() as u64);
+        self.iter().for_each(|value| value.write(writer));
+    }
+
+    fn read(reader: &mut WireReader<'_>) -> Option<Self> {
+        (0..reader.u64()?).map(|_| reader.wire()).collect()
+    }
+}
+
+impl Wire for Duration {
+    fn write(&self, writer: &mut WireWriter) {
+        writer.u64(self.as_secs());
+    }
+
+    fn read(reader: &mut WireReader<'_>) -> Option<Self> {
+        Some(Duration::from_secs(reader.u64()?))
+    }
+}
+
+impl Wire for SystemTime {
+    fn write(&self, writer: &mut WireWriter) {
+        writer.u64(self.duration_since(UNIX_EPOCH).unwrap_or_default().as_millis() as u64);
+    }
+
+    fn read(reader: &mut WireReader<'_>) -> Option<Self> {
+        UNIX_EPOCH.checked_add(Duration::from_millis(reader.u64()?))
+    }
+}
+
+impl Wire for IpAddr {
+    fn write(&self, writer: &mut WireWriter) {
+        match self {
+            IpAddr::V4(ip) => writer.u8(4).bytes(&ip.octets()),
+            IpAddr::V6(ip) => writer.u8(6).bytes(&ip.octets()),
+        };
+    }
+
+    fn read(reader: &mut WireReader<'_>) -> Option<Self> {
+        match reader.u8()? {
+            4 => Some(IpAddr::from(<[u8; 4]>::try_from(reader.bytes()?).ok()?)),
+            6 => Some(IpAddr::from(<[u8; 16]>::try_from(reader.bytes()?).ok()?)),
+            _ => None,
+        }
+    }
+}
+
+impl Wire for SocketAddr {
+    fn write(&self, writer: &mut WireWriter) {
+        writer.wire(&self.ip()).u16(self.port());
+    }
+
+    fn read(reader: &mut WireReader<'_>) -> Option<Self> {
+        Some(SocketAddr::new(reader.wire()?, reader.u16()?))
+    }
+}
+
+impl<T: Wire> Wire for Option<T> {
+    fn write(&self, writer: &mut WireWriter) {
+        match self {
+            None => writer.u8(0),
+            Some(value) => writer.u8(1).wire(value),
+        };
+    }
+
+    fn read(reader: &mut WireReader<'_>) -> Option<Self> {
+        match reader.u8()? {
+            0 => Some(None),
+            1 => Some(Some(reader.wire()?)),
+            _ => None,
+        }
+    }
+}
+
+pub struct WireSerializer {
+    buf: Vec<u8>,
+    pos: usize,
+}
+
+impl WireSerializer {
+    pub fn new<T: Wire>(value: &T) -> Self {
+        let mut writer = WireWriter::default();
+        value.write(&mut writer);
+        Self {
+            buf: writer.0,
+            pos: 0,
+        }
+    }
+}
+
+impl Serializer for WireSerializer {
+    fn fill(&mut self, buf: &mut [u8]) -> Option<usize> {
+        let mut inner = Buf {
+            buf: &self.buf,
+            pos: self.pos,
+        };
+        let len = inner.fill(buf);
+        self.pos = inner.pos;
+        len
+    }
+}
+
+pub struct WireDeserializer<T> {
+    buf: Vec<u8>,
+    _marker: PhantomData<T>,
+}
+
+impl<T> WireDeserializer<T> {
+    pub fn new() -> Self {
+        Self {
+            buf: Vec::new(),
+            _marker: PhantomData,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct Malformed;
+
+impl<T: Wire> Deserializer<T> for WireDeserializer<T> {
+    type UpdateError = !;
+    type FinalizeError = Malformed;
+
+    fn update(&mut self, slice: &[u8]) -> Result<(), Self::UpdateError> {
+        Ok(self.buf.extend_from_slice(slice))
+    }
+
+    fn finalize(self) -> Result<T, Self::FinalizeError> {
+        let mut reader = WireReader(&self.buf);
+        let value = T::read(&mut reader).ok_or(Malformed)?;
+        match reader.0 {
+            [] => Ok(value),
+            _ => Err(Malformed),
+        }
+    }
+}
+
+macro_rules! wire {
+    ($($t:ty),* $(,)?) => {
+        $(
+            impl $crate::serialization::Serializable for $t {
+                type Serializer<'s> = $crate::serialization::wire::WireSerializer;
+
+                fn serializer(&self) -> Self::Serializer<'_> {
+                    $crate::serialization::wire::WireSerializer::new(self)
+                }
+            }
+
+            impl $crate::serialization::Deserializable for $t {
+                type Deserializer = $crate::serialization::wire::WireDeserializer<$t>;
+
+                fn deserializer() -> Self::Deserializer {
+                    $crate::serialization::wire::WireDeserializer::new()
+                }
+            }
+        )*
+    };
+}
+
+pub(crate) use wire;
@@ -1,10 +1,26 @@
 use std::{
     collections::{
         hash_map::Entry,
+        BTreeMap,
         HashMap,
+        HashSet,
+        VecDeque,
     },
     fmt::Debug,
-    sync::Arc,
+    iter,
+    net::{IpAddr, SocketAddr},
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+        Mutex,
+        RwLock,
+    },
+    time::{
+        Duration,
+        SystemTime,
+        UNIX_EPOCH,
+    },
 };
 
 use futures::{
@@ -13,16 +29,18 @@ use futures::{
     FutureExt,
 };
 
+use sha2::{Digest, Sha256};
+
 use tokio::{
     io,
     net::{TcpListener, TcpStream, ToSocketAddrs},
     sync::mpsc::{
         self,
-        error::SendError,
-        UnboundedReceiver,
+        error::{SendError, TrySendError},
         UnboundedSender,
     },
-    task::JoinError,
+    task::{self, JoinError, JoinHandle},
+    time,
 };
 
 use crate::{
@@ -30,28 +48,129 @@ use crate::{
         DataBase,
         DatabaseError,
         DatabaseEvent,
+        History,
+        HistoryEvent,
+        Offline,
         Password,
+        Record,
         User,
     },
-    handle::Handle,
+    federation::{
+        self,
+        Address,
+        Federation,
+        LinkError,
+        Peer,
+        Relay,
+    },
+    handle::{
+        Handle,
+        Overflow,
+        Receiver,
+    },
+    control,
     logger::Logger,
     message::Message,
+    metrics::{self, Metrics},
     serialization::{
+        self,
         Deserializable,
         Deserializer,
     },
+    mux::{
+        ChannelReceiver,
+        Closed,
+        Encoded,
+        Mux,
+        MuxError,
+        BULK,
+        CHAT,
+        CONTROL,
+    },
+    protocol::{
+        Admin,
+        AdminReply,
+        Ban,
+        ConnectionStats,
+        Cursor,
+        Delivery,
+        Envelope,
+        HistoryEntry,
+        MessageId,
+        Notice,
+        Origin,
+        Presence,
+        Reason,
+        Request,
+        Session,
+        Status,
+        Target,
+    },
     stream::{
+        self,
         BlockStream,
         ReadError,
         WriteError,
     },
+    rate::RateLimits,
+    ticket::Tickets,
+    Bot,
+    Bots,
+    CipherSuite,
+    KeyExchange,
+    MessageHook,
+    Outbox,
+    RateLimit,
+    Scope,
     Uninitialized,
+    Verdict,
 };
 
-pub struct Builder<A, DB, L> {
+mod config;
+mod listener;
+
+pub use config::{Config, ConfigError, ConfiguredAddr, DatabaseConfig};
+pub use listener::{AuthMethod, Bind, Listener};
+
+use listener::{bind_private, Bound, Socket};
+
+pub struct Builder<A, DB, L, H = (), B = (), S = Uninitialized> {
     addr: A,
     db: DB,
     logger: L,
+    hooks: H,
+    bots: B,
+    history: S,
+    options: Options,
+}
+
+#[derive(Default)]
+struct Options {
+    stream: stream::Config,
+    queue: Option<(usize, Overflow)>,
+    ticket_lifetime: Option<Duration>,
+    ticket_key: Option<[u8; 32]>,
+    offline_limit: Option<usize>,
+    offline_retention: Option<Duration>,
+    max_message_size: Option<usize>,
+    admins: RwLock<HashSet<String>>,
+    rates: RateLimits,
+    max_connections: Option<usize>,
+    max_connections_per_ip: Option<usize>,
+    max_handshakes: Option<usize>,
+    handshake_timeout: Option<Duration>,
+    drain_timeout: Option<Duration>,
+    federation: Federation,
+    admin_socket: Option<PathBuf>,
+    metrics: Option<SocketAddr>,
+    reload: Option<Box<dyn Fn() -> Result<Reload, String> + Send + Sync>>,
+    listeners: Vec<Listener>,
+    bots: HashSet<String>,
+    bot_secret: Option<[u8; 32]>,
+}
+
+pub struct Reload {
+    pub admins: HashSet<String>,
 }
 
 impl Builder<Uninitialized, Uninitialized, Uninitialized> {
@@ -60,28 +179,201 @@ impl Builder<Uninitialized, Uninitialized, Uninitialized> {
             addr: Uninitialized,
             db: Uninitialized,
             logger: Uninitialized,
+            hooks: (),
+            bots: (),
+            history: Uninitialized,
+            options: Default::default(),
         }
     }
 }
 
-impl<DB, L> Builder<Uninitialized, DB, L> {
-    pub fn addr<A: ToSocketAddrs + Send + 'static>(self, addr: A) -> Builder<A, DB, L> {
-        let Self { addr: _, db, logger } = self;
-        Builder { addr, db, logger }
+impl<DB, L, H, B, S> Builder<Uninitialized, DB, L, H, B, S> {
+    pub fn addr<A: ToSocketAddrs + Send + 'static>(self, addr: A) -> Builder<A, DB, L, H, B, S> {
+        let Self { addr: _, db, logger, hooks, bots, history, options } = self;
+        Builder { addr, db, logger, hooks, bots, history, options }
+    }
+}
+
+impl<A, L, H, B, S> Builder<A, Uninitialized, L, H, B, S> {
+    pub fn db<DB: DataBase + Send + 'static>(self, db: DB) -> Builder<A, DB, L, H, B, S> {
+        let Self { addr, db: _, logger, hooks, bots, history, options } = self;
+        Builder { addr, db, logger, hooks, bots, history, options }
+    }
+}
+
+impl<A, DB, H, B, S> Builder<A, DB, Uninitialized, H, B, S> {
+    pub fn logger<L: Logger + Send + 'static>(self, logger: L) -> Builder<A, DB, L, H, B, S> {
+        let Self { addr, db, logger: _, hooks, bots, history, options } = self;
+        Builder { addr, db, logger, hooks, bots, history, options }
     }
 }
 
-impl<A, L> Builder<A, Uninitialized, L> {
-    pub fn db<DB: DataBase + Send + 'static>(self, db: DB) -> Builder<A, DB, L> {
-        let Self { addr, db: _, logger } = self;
-        Builder { addr, db, logger }
+impl<A, DB, L, H, B> Builder<A, DB, L, H, B, Uninitialized> {
+    pub fn history<S: History + Send + 'static>(self, history: S) -> Builder<A, DB, L, H, B, S> {
+        let Self { addr, db, logger, hooks, bots, history: _, options } = self;
+        Builder { addr, db, logger, hooks, bots, history, options }
     }
 }
 
-impl<A, DB> Builder<A, DB, Uninitialized> {
-    pub fn logger<L: Logger + Send + 'static>(self, logger: L) -> Builder<A, DB, L> {
-        let Self { addr, db, logger: _ } = self;
-        Builder { addr, db, logger }
+impl<A, DB, L, H, B, S> Builder<A, DB, L, H, B, S> {
+    pub fn hook<T>(self, hook: T) -> Builder<A, DB, L, (H, T), B, S> {
+        let Self { addr, db, logger, hooks, bots, history, options } = self;
+        Builder { addr, db, logger, hooks: (hooks, hook), bots, history, options }
+    }
+
+    pub fn bot<T>(self, name: String, bot: T) -> Builder<A, DB, L, H, (B, (String, T)), S> {
+        let Self { addr, db, logger, hooks, bots, history, options } = self;
+        Builder { addr, db, logger, hooks, bots: (bots, (name, bot)), history, options }
+    }
+
+    pub fn cipher_suites<I: IntoIterator<Item=CipherSuite>>(mut self, suites: I) -> Self {
+        self.options.stream.suites(suites);
+        self
+    }
+
+    pub fn key_exchanges<I: IntoIterator<Item=KeyExchange>>(mut self, exchanges: I) -> Self {
+        self.options.stream.exchanges(exchanges);
+        self
+    }
+
+    pub fn keepalive(mut self, interval: Duration) -> Self {
+        self.options.stream.keepalive(interval);
+        self
+    }
+
+    pub fn idle_timeout(mut self, timeout: Duration) -> Self {
+        self.options.stream.idle_timeout(timeout);
+        self
+    }
+
+    pub fn read_timeout(mut self, timeout: Duration) -> Self {
+        self.options.stream.read_timeout(timeout);
+        self
+    }
+
+    pub fn connection_queue(mut self, capacity: usize, overflow: Overflow) -> Self {
+        self.options.queue = Some((capacity, overflow));
+        self
+    }
+
+    pub fn resumption_tickets(mut self, lifetime: Duration) -> Self {
+        self.options.ticket_lifetime = Some(lifetime);
+        self
+    }
+
+    pub fn ticket_key(mut self, key: [u8; 32]) -> Self {
+        self.options.ticket_key = Some(key);
+        self
+    }
+
+    pub fn bot_secret(mut self, secret: [u8; 32]) -> Self {
+        self.options.bot_secret = Some(secret);
+        self
+    }
+
+    pub fn offline_messages(mut self, limit: usize) -> Self {
+        self.options.offline_limit = Some(limit);
+        self
+    }
+
+    pub fn offline_retention(mut self, age: Duration) -> Self {
+        self.options.offline_retention = Some(age);
+        self
+    }
+
+    pub fn max_message_size(mut self, bytes: usize) -> Self {
+        self.options.max_message_size = Some(bytes);
+        self
+    }
+
+    pub fn admins<I: IntoIterator<Item=String>>(mut self, admins: I) -> Self {
+        self.options.admins.get_mut().unwrap().extend(admins);
+        self
+    }
+
+    pub fn message_rate(mut self, scope: Scope, limit: RateLimit) -> Self {
+        self.options.rates.messages.set(scope, limit);
+        self
+    }
+
+    pub fn byte_rate(mut self, scope: Scope, limit: RateLimit) -> Self {
+        self.options.rates.bytes.set(scope, limit);
+        self
+    }
+
+    pub fn login_rate(mut self, scope: Scope, limit: RateLimit) -> Self {
+        self.options.rates.logins.set(scope, limit);
+        self
+    }
+
+    pub fn login_backoff(mut self, base: Duration, max: Duration) -> Self {
+        self.options.rates.lockouts.backoff(base, max);
+        self
+    }
+
+    pub fn login_lockout(mut self, failures: u32, duration: Duration) -> Self {
+        self.options.rates.lockouts.lockout(failures, duration);
+        self
+    }
+
+    pub fn max_connections(mut self, limit: usize) -> Self {
+        self.options.max_connections = Some(limit);
+        self
+    }
+
+    pub fn max_connections_per_ip(mut self, limit: usize) -> Self {
+        self.options.max_connections_per_ip = Some(limit);
+        self
+    }
+
+    pub fn max_handshakes(mut self, limit: usize) -> Self {
+        self.options.max_handshakes = Some(limit);
+        self
+    }
+
+    pub fn handshake_timeout(mut self, timeout: Duration) -> Self {
+        self.options.handshake_timeout = Some(timeout);
+        self
+    }
+
+    pub fn drain_timeout(mut self, timeout: Duration) -> Self {
+        self.options.drain_timeout = Some(timeout);
+        self
+    }
+
+    pub fn domain(mut self, domain: String) -> Self {
+        self.options.federation.domain = Some(domain);
+        self
+    }
+
+    pub fn federation(mut self, addr: SocketAddr) -> Self {
+        self.options.federation.listen = Some(addr);
+        self
+    }
+
+    pub fn peer(mut self, domain: String, addr: SocketAddr, secret: [u8; 32]) -> Self {
+        self.options.federation.peers.insert(domain, Peer { addr, secret });
+        self
+    }
+
+    pub fn admin_socket<P: Into<PathBuf>>(mut self, path: P) -> Self {
+        self.options.admin_socket = Some(path.into());
+        self
+    }
+
+    pub fn metrics(mut self, addr: SocketAddr) -> Self {
+        self.options.metrics = Some(addr);
+        self
+    }
+
+    pub fn reload<F: Fn() -> Result<Reload, String> + Send + Sync + 'static>(mut self, reload: F) -> Self {
+        self.options.reload = Some(Box::new(reload));
+        self
+    }
+
+    pub fn listener(mut self, listener: Listener) -> Self {
+        self.options.listeners.push(listener);
+        self
     }
 }
 
@@ -112,6 +404,63 @@ impl IntoLogger for Uninitialized {
     }
 }
 
+impl History for Sink {
+    type RecordFuture = future::Ready<()>;
+    type QueryFuture = future::Ready<Vec<Record>>;
+
+    fn record(&mut self, _: Record) -> Self::RecordFuture {
+        future::ready(())
+    }
+
+    fn query(&mut self, _: &User, _: &Target, _: Cursor, _: usize) -> Self::QueryFuture {
+        future::ready(Vec::new())
+    }
+}
+
+pub trait IntoHistory {
+    type History: History + Send + 'static;
+
+    fn into_history(self) -> Option<Self::History>;
+}
+
+impl IntoHistory for Uninitialized {
+    type History = Sink;
+
+    fn into_history(self) -> Option<Self::History> {
+        None
+    }
+}
+
+pub trait IntoAddr {
+    type Addr: ToSocketAddrs + Send + 'static;
+
+    fn into_addr(self) -> Option<Self::Addr>;
+}
+
+impl IntoAddr for Uninitialized {
+    type Addr = SocketAddr;
+
+    fn into_addr(self) -> Option<Self::Addr> {
+        None
+    }
+}
+
+impl<A: ToSocketAddrs + Send + 'static> IntoAddr for A {
+    type Addr = A;
+
+    fn into_addr(self) -> Option<Self::Addr> {
+        Some(self)
+    }
+}
+
+impl<S: History + Send + 'static> IntoHistory for S {
+    type History = S;
+
+    fn into_history(self) -> Option<Self::History> {
+        Some(self)
+    }
+}
+
 impl<L: Logger + Send + 'static> IntoLogger for L {
     type Logger = L;
 
@@ -121,79 +470,535 @@ impl<L: Logger + Send + 'static> IntoLogger for L {
 }
 
 impl<
-    A: ToSocketAddrs + Send + 'static,
+    A: IntoAddr,
     DB: DataBase + Send + 'static,
     L: IntoLogger,
-> Builder<A, DB, L> {
+    H,
+    B,
+    S: IntoHistory,
+> Builder<A, DB, L, H, B, S> {
     pub fn serve<
         const N: usize,
-        M: for<'s> Message<Serializer<'s>: Send, Deserializer: Send> + Send + Sync + 'static,
-    >(self) -> Handle<!, Result<(), ServerError<M>>> where [(); N + 16]:, <<M as Deserializable>::Deserializer as Deserializer<M>>::UpdateError: Send, <<M as Deserializable>::Deserializer as Deserializer<M>>::FinalizeError: Send {
-        let Self { addr, db, logger } = self;
+        M: for<'s> Message<Serializer<'s>: Send, Deserializer: Send> + Debug + Send + Sync + 'static,
+    >(self) -> Handle<AdminRequest, Result<(), ServerError>> where H: MessageHook<M>, B: Bots<M>, [(); N + 16]:, <<M as Deserializable>::Deserializer as Deserializer<M>>::UpdateError: Send, <<M as Deserializable>::Deserializer as Deserializer<M>>::FinalizeError: Send {
+        let Self { addr, db, logger, hooks, bots, history, mut options } = self;
+        let history = history.into_history();
+        let hooks = Arc::new(hooks);
+        let mut bot_list = Vec::new();
+        bots.collect(&mut bot_list);
+        options.bots = bot_list.iter().map(|(name, _)| name.clone()).collect();
         let logger = logger.into_logger();
-
-        Handle::new(|mut shutdown_receiver| async move {
+        let rates = Arc::new(std::mem::take(&mut options.rates));
+        let federation = Arc::new(std::mem::take(&mut options.federation));
+        let configured = std::mem::take(&mut options.listeners);
+        let metrics = Arc::new(Metrics::default());
+        options.stream.traffic(metrics.traffic.clone());
+        let tickets = options.ticket_lifetime.map(|lifetime| Arc::new(Tickets::new(options.ticket_key.unwrap_or_else(Tickets::random_key), lifetime)));
+        let bot_secret = options.bot_secret.unwrap_or_else(Tickets::random_key);
+        let options = Arc::new(options);
+        let admission = Admission::default();
+
+        Handle::new(|mut admin_receiver| async move {
+            // logins are attempted once per connection, a connection scoped limit would never apply
+            if rates.logins.limits(Scope::Connection) {
+                return Err(ServerError::Scope(Scope::Connection));
+            }
             let logger_clone = logger.clone();
-            let message_loop = Handle::<TcpStream, Result<(), ServerError<M>>>::new(|mut receiver| async move {
+            let options_clone = options.clone();
+            let federation_listen = federation.listen;
+            let metrics_clone = metrics.clone();
+            let (stopping, stopped) = oneshot::channel::<()>();
+            let message_loop = Handle::<Input, Result<(), ServerError>>::new(|mut receiver| async move {
                 // todo: remove arc
-                let db_loop = Arc::new(Handle::new(|receiver| db_loop(db, receiver)));
+                let metrics = metrics_clone;
+                let db_loop = Arc::new(Handle::new(|receiver| db_loop(db, receiver, metrics.clone(), logger_clone.clone())));
+                let history = history.map(|history| Arc::new(Handle::new(|receiver| history_loop(history, receiver))));
+                metrics.queue("database", db_loop.depth());
+                if let Some(history) = &history {
+                    metrics.queue("history", history.depth());
+                }
 
-                let mut handles = HashMap::<User, Vec<Handle<(User, M), Result<(), ConnectionLoopError<M>>>>>::new();
-                let (message_sender, mut message_receiver) = mpsc::unbounded_channel::<((User, User), M)>();
+                let mut handles = Connections::<M>::new();
+                let (message_sender, mut message_receiver) = mpsc::unbounded_channel::<Dispatch<M>>();
+                let (connected_sender, mut connected_receiver) = mpsc::unbounded_channel();
+                let (offline_sender, mut offline_receiver) = mpsc::unbounded_channel::<(User, u64, Vec<Offline>)>();
+                let options = options_clone;
+                let mut sessions = BTreeMap::new();
+                let mut counters = BTreeMap::new();
+                let mutes = Mutes::default();
+                let mut presences = Presences::default();
+                let mut connections = 0;
+                let ids = Arc::new(AtomicU64::new(SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_micros() as u64));
+
+                for (name, bot) in bot_list {
+                    let Some(user) = bot_user(&db_loop, &bot_secret, name.clone()).await.map_err(|e| ServerError::Database(e))? else {
+                        logger_clone.error(format!("could not register bot {name}, the name may belong to a user"));
+                        continue;
+                    };
+                    let connection = connections;
+                    connections += 1;
+                    let handle = run_bot(user.clone(), connection, bot, hooks.clone(), federation.clone(), ids.clone(), db_loop.clone(), message_sender.clone());
+                    presences.connections.entry(user.clone()).or_default().insert(connection, Presence::Online);
+                    handles.entry(user).or_default().push((connection, handle));
+                }
 
-                loop {
+                let mut links = HashMap::<String, UnboundedSender<Relay>>::new();
+                if let Some(local) = &federation.domain {
+                    for (domain, peer) in &federation.peers {
+                        let (sender, receiver) = mpsc::unbounded_channel();
+                        task::spawn(link_out::<N, _>(domain.clone(), local.clone(), peer.clone(), options.stream.clone(), logger_clone.clone(), receiver));
+                        links.insert(domain.clone(), sender);
+                    }
+                }
+
+                let going_away = loop {
                     futures::select! {
-                        m = message_receiver.recv().fuse() => {
-                            let((from, to), message) = m.ok_or(ServerError::MessageReceiver)?;
-                            if let Some(handle) = handles.get_mut(&from) {
-                                handle.retain_mut(|handle| handle.send((to.clone(), message.clone())).is_ok())
+                        m = message_receiver.recv().fuse() => match m.ok_or(ServerError::MessageReceiver)? {
+                            Dispatch::Message { from, id, route, message } => {
+                                metrics.messages_routed.fetch_add(1, Ordering::Relaxed);
+                                if let Some(history) = &history {
+                                    let record = Record {
+                                        id,
+                                        from: from.clone(),
+                                        to: route.target(),
+                                        message: serialization::to_bytes(&message),
+                                        sent: SystemTime::now(),
+                                    };
+                                    if let Err(e) = history.send(HistoryEvent::Record(record)) {
+                                        logger_clone.error(e);
+                                    }
+                                }
+                                let (origin, recipients) = match route {
+                                    Route::Direct(to) => (Origin::User(from.to_string()), vec![to]),
+                                    Route::Room { room, members } => (
+                                        Origin::Room { room, from: from.to_string() },
+                                        members.into_iter().filter(|member| *member != from).collect(),
+                                    ),
+                                    Route::Remote { user, domain } => {
+                                        let relay = Relay { id, from: from.to_string(), to: user, message: serialization::to_bytes(&message) };
+                                        if !links.get(&domain).is_some_and(|link| link.send(relay).is_ok()) {
+                                            metrics.messages_dropped.fetch_add(1, Ordering::Relaxed);
+                                            logger_clone.warn(format!("no federation link to {domain}"));
+                                        }
+                                        continue
+                                    }
+                                };
+                                let delivery = Delivery { id, origin };
+                                let mut statuses = Vec::new();
+                                for recipient in recipients {
+                                    let mut delivered = false;
+                                    if let Some(handles) = handles.get_mut(&recipient) {
+                                        let mut open = Vec::with_capacity(handles.len());
+                                        for (connection, handle) in handles.drain(..) {
+                                            let outgoing = Outgoing::Message(delivery.clone(), message.clone());
+                                            // a blocking queue holds up routing until the reader catches up or its connection ends
+                                            let sent = match options.queue {
+                                                Some((_, Overflow::Block)) => handle.send_async(outgoing).await.map_err(|SendError(outgoing)| TrySendError::Closed(outgoing)),
+                                                _ => handle.send(outgoing),
+                                            };
+                                            match sent {
+                                                Ok(()) => {
+                                                    delivered = true;
+                                                    open.push((connection, handle));
+                                                }
+                                                Err(TrySendError::Full(_)) => open.push((connection, handle)),
+                                                Err(TrySendError::Closed(_)) => {}
+                                            }
+                                        }
+                                        *handles = open;
+                                    }
+                                    if delivered {
+                                        statuses.push((recipient, Status::Delivered));
+                                        continue;
+                                    }
+                                    if let Some(limit) = options.offline_limit {
+                                        let offline = Offline {
+                                            id,
+                                            origin: delivery.origin.clone(),
+                                            message: serialization::to_bytes(&message),
+                                            stored: SystemTime::now(),
+                                        };
+                                        match db_loop.send(DatabaseEvent::StoreOffline { user: recipient.clone(), message: offline, limit }) {
+                                            Ok(()) => {
+                                                metrics.messages_stored.fetch_add(1, Ordering::Relaxed);
+                                                statuses.push((recipient, Status::Stored));
+                                                continue;
+                                            }
+                                            Err(e) => logger_clone.error(e),
+                                        }
+                                    }
+                                    metrics.messages_dropped.fetch_add(1, Ordering::Relaxed);
+                                }
+                                for (recipient, status) in statuses {
+                                    notify(&handles, &from, Notice::Status { id, user: recipient.to_string(), status });
+                                }
                             }
-                        }
-                        m = receiver.recv().fuse() => {
-                            let Some(stream) = m else { break };
-                            let(user, handle) = match connection_loop(stream, db_loop.clone(), message_sender.clone()).await {
-                                Ok(t) => t,
-                                Err(e) => {
-                                    logger_clone.error(e);
-                                    continue
+                            Dispatch::Notice { to, notice } => notify(&handles, &to, notice),
+                            Dispatch::Subscribe { user, to, subscribed: true } => {
+                                presences.subscribers.entry(to.clone()).or_default().insert(user.clone());
+                                notify(&handles, &user, presences.notice(&to));
+                            }
+                            Dispatch::Subscribe { user, to, subscribed: false } => {
+                                if let Entry::Occupied(mut e) = presences.subscribers.entry(to) {
+                                    e.get_mut().remove(&user);
+                                    if e.get().is_empty() {
+                                        e.remove();
+                                    }
                                 }
-                            };
+                            }
+                            Dispatch::Presence { user, connection, presence, text } => {
+                                let changed = presences.update(&user, |presences| {
+                                    presences.connections.entry(user.clone()).or_default().insert(connection, presence);
+                                    match text {
+                                        Some(text) => presences.text.insert(user.clone(), text),
+                                        None => presences.text.remove(&user),
+                                    };
+                                });
+                                publish(&handles, changed);
+                            }
+                            Dispatch::Admin { admin: Admin::Shutdown { reason, reconnect }, reply } => {
+                                let _ = reply.send(AdminReply::Done(true));
+                                break Notice::Shutdown { reason, reconnect };
+                            }
+                            Dispatch::Admin { admin, reply } => {
+                                let _ = reply.send(administer(&mut handles, &sessions, &counters, &mutes, &rates, &options, &*db_loop, &logger_clone, admin).await);
+                            }
+                            Dispatch::Disconnected { user, connection } => {
+                                let session = sessions.remove(&connection);
+                                counters.remove(&connection);
+                                metrics.disconnected(connection);
+                                metrics.connections.store(sessions.len() as u64, Ordering::Relaxed);
+                                if let Some(handle) = remove(&mut handles, &user, connection) {
+                                    task::spawn(report(handle, connection, session, None, logger_clone.clone()));
+                                }
+                                let changed = presences.update(&user, |presences| {
+                                    if let Entry::Occupied(mut e) = presences.connections.entry(user.clone()) {
+                                        e.get_mut().remove(&connection);
+                                        if e.get().is_empty() {
+                                            e.remove();
+                                        }
+                                    }
+                                });
+                                publish(&handles, changed);
+                                if !handles.contains_key(&user) {
+                                    presences.subscribers.retain(|_, subscribers| {
+                                        subscribers.remove(&user);
+                                        !subscribers.is_empty()
+                                    });
+                                }
+                            }
+                        },
+                        m = receiver.recv().fuse() => match m {
+                            Some(Input::Connection(stream, addr, listener, permit, handshake)) => {
+                                let connection = connections;
+                                connections += 1;
+                                metrics.connections_total.fetch_add(1, Ordering::Relaxed);
+                                task::spawn({
+                                    let options = options.clone();
+                                    let tickets = tickets.clone();
+                                    let ids = ids.clone();
+                                    let mutes = mutes.clone();
+                                    let rates = rates.clone();
+                                    let federation = federation.clone();
+                                    let metrics = metrics.clone();
+                                    let hooks = hooks.clone();
+                                    let history = history.clone();
+                                    let db_loop = db_loop.clone();
+                                    let message_sender = message_sender.clone();
+                                    let connected_sender = connected_sender.clone();
+                                    let logger = logger_clone.clone();
+                                    async move {
+                                        let init = connection_loop(stream, addr, &listener, &options, tickets.as_deref(), connection, permit, ids, mutes, rates, federation, metrics.clone(), hooks, history, db_loop, message_sender);
+                                        let result = match options.handshake_timeout {
+                                            Some(timeout) => time::timeout(timeout, init).await.unwrap_or(Err(ConnectionInitError::Timeout)),
+                                            None => init.await,
+                                        };
+                                        drop(handshake);
+                                        match result {
+                                            Ok((user, handle, counters)) => {
+                                                metrics.logins_succeeded.fetch_add(1, Ordering::Relaxed);
+                                                let _ = connected_sender.send((connection, addr, user, handle, counters));
+                                            }
+                                            Err(e) => {
+                                                if let ConnectionInitError::LogIn(..) = e {
+                                                    metrics.logins_failed.fetch_add(1, Ordering::Relaxed);
+                                                }
+                                                logger.error(e);
+                                            }
+                                        }
+                                    }
+                                });
+                            }
+                            Some(Input::Link(stream, addr, permit, handshake)) => {
+                                task::spawn({
+                                    let options = options.clone();
+                                    let federation = federation.clone();
+                                    let ids = ids.clone();
+                                    let hooks = hooks.clone();
+                                    let db_loop = db_loop.clone();
+                                    let message_sender = message_sender.clone();
+                                    let logger = logger_clone.clone();
+                                    async move {
+                                        if let Err(e) = link_in::<N, M, H>(stream, permit, handshake, &options, &federation, &ids, &*hooks, &db_loop, &message_sender).await {
+                                            logger.error(format!("federation link from {addr} failed: {e:?}"));
+                                        }
+                                    }
+                                });
+                            }
+                            Some(Input::Admin(AdminRequest { admin: Admin::Shutdown { reason, reconnect }, reply })) => {
+                                let _ = reply.send(AdminReply::Done(true));
+                                break Notice::Shutdown { reason, reconnect };
+                            }
+                            Some(Input::Admin(AdminRequest { admin, reply })) => {
+                                let _ = reply.send(administer(&mut handles, &sessions, &counters, &mutes, &rates, &options, &*db_loop, &logger_clone, admin).await);
+                            }
+                            None => break Notice::Shutdown { reason: None, reconnect: None },
+                        },
+                        m = connected_receiver.recv().fuse() => {
+                            let (connection, addr, user, handle, connection_counters) = m.ok_or(ServerError::MessageReceiver)?;
+                            // the connection loop closes its queue before it reports the disconnect, so a closed handle
+                            // here has already been or is about to be cleaned up by Dispatch::Disconnected
+                            if handle.is_closed() {
+                                task::spawn(report(handle, connection, None, None, logger_clone.clone()));
+                                continue;
+                            }
+                            let online = handles.get_mut(&user).is_some_and(|handles| {
+                                handles.retain(|(_, handle)| !handle.is_closed());
+                                !handles.is_empty()
+                            });
+                            // the store is read off the routing loop, the messages are queued once they come back
+                            if let (false, Some(_)) = (online, options.offline_limit) {
+                                let (sender, receiver) = oneshot::channel();
+                                db_loop.send(DatabaseEvent::TakeOffline { user: user.clone(), channel: sender })
+                                    .map_err(|e| ServerError::Database(e))?;
+                                let offline_sender = offline_sender.clone();
+                                let user = user.clone();
+                                task::spawn(async move {
+                                    let _ = offline_sender.send((user, connection, receiver.await.unwrap_or_default()));
+                                });
+                            }
+                            let changed = presences.update(&user, |presences| {
+                                presences.connections.entry(user.clone()).or_default().insert(connection, Presence::Online);
+                            });
+                            sessions.insert(connection, Session { user: user.to_string(), connection, addr });
+                            counters.insert(connection, connection_counters);
+                            metrics.connection_queue(connection, handle.depth());
+                            metrics.connections.store(sessions.len() as u64, Ordering::Relaxed);
                             match handles.entry(user) {
-                                Entry::Occupied(mut e) => { e.get_mut().push(handle) }
-                                Entry::Vacant(e) => { e.insert(vec![handle]); }
+                                Entry::Occupied(mut e) => { e.get_mut().push((connection, handle)) }
+                                Entry::Vacant(e) => { e.insert(vec![(connection, handle)]); }
                             }
+                            publish(&handles, changed);
+                        }
+                        m = offline_receiver.recv().fuse() => {
+                            let (user, connection, pending) = m.ok_or(ServerError::MessageReceiver)?;
+                            let Some(limit) = options.offline_limit else { continue };
+                            let handle = handles.get(&user)
+                                .and_then(|handles| handles.iter().find(|(c, _)| *c == connection))
+                                .map(|(_, handle)| handle);
+                            let cutoff = options.offline_retention.and_then(|age| SystemTime::now().checked_sub(age));
+                            let mut pending = pending.into_iter();
+                            while let Some(offline) = pending.next() {
+                                if cutoff.is_some_and(|cutoff| offline.stored < cutoff) {
+                                    continue;
+                                }
+                                let Some(message) = serialization::from_bytes::<M>(&offline.message) else {
+                                    logger_clone.error(offline);
+                                    continue;
+                                };
+                                let Offline { id, origin, .. } = offline.clone();
+                                let from = User::new(origin.sender().to_owned());
+                                if handle.is_none_or(|handle| handle.send(Outgoing::Message(Delivery { id, origin }, message)).is_err()) {
+                                    // whatever does not fit in the queue, or outlived the connection, goes back to storage for the next login
+                                    for offline in iter::once(offline).chain(pending) {
+                                        if let Err(e) = db_loop.send(DatabaseEvent::StoreOffline { user: user.clone(), message: offline, limit }) {
+                                            logger_clone.error(e);
+                                        }
+                                    }
+                                    break;
+                                }
+                                notify(&handles, &from, Notice::Status { id, user: user.to_string(), status: Status::Delivered });
+                            }
+                        }
+                    }
+                };
+                drop(stopping);
+
+                // every connection gets until the drain timeout to take the notice, flush its queue and close
+                let deadline = time::Instant::now().checked_add(options.drain_timeout.unwrap_or(DRAIN_TIMEOUT));
+                future::join_all(handles.into_values().flatten().map(|(connection, handle)| {
+                    let notice = Outgoing::Notice(going_away.clone());
+                    let session = sessions.remove(&connection);
+                    let logger = logger_clone.clone();
+                    async move {
+                        let sent = match deadline {
+                            Some(deadline) => time::timeout_at(deadline, handle.send_async(notice)).await.is_ok_and(|sent| sent.is_ok()),
+                            None => handle.send_async(notice).await.is_ok(),
+                        };
+                        if !sent {
+                            logger.warn(format!("connection {connection} missed the shutdown notice"));
+                        }
+                        report(handle, connection, session, deadline, logger).await
+                    }
+                })).await;
+                // offline messages still on their way back were never delivered, they are kept for the next login
+                drop(offline_sender);
+                while let Some((user, _, pending)) = offline_receiver.recv().await {
+                    let Some(limit) = options.offline_limit else { break };
+                    for offline in pending {
+                        if let Err(e) = db_loop.send(DatabaseEvent::StoreOffline { user: user.clone(), message: offline, limit }) {
+                            logger_clone.error(e);
                         }
                     }
                 }
-
-                future::join_all(handles.into_values()
-                    .flatten()
-                    .map(Handle::shutdown))
-                    .await
-                    .into_iter()
-                    .try_for_each(|t| match t {
-                        Ok(Ok(())) => Ok(()),
-                        Ok(Err(e)) => Err(ServerError::Connection(e)),
-                        Err(e) => Err(ServerError::Join(e)),
-                    })?;
+                if let Some(history) = history.and_then(Arc::into_inner) {
+                    history.shutdown().await?;
+                }
                 Arc::into_inner(db_loop).ok_or(ServerError::DbArcDrop)?.shutdown().await??;
                 Ok(())
             });
 
 
-            let listener = TcpListener::bind(addr).await?;
-            while let Some(stream) = futures::select! {
-                _ = shutdown_receiver.recv().fuse() => None,
-                accept = listener.accept().fuse() => {
-                    let (stream, _) = accept?;
-                    Some(stream)
-                }
-            } {
-                if let Err(e) = message_loop.send(stream) {
+            let mut listeners = Vec::new();
+            if let Some(addr) = addr.into_addr() {
+                let listener = TcpListener::bind(addr).await?;
+                listeners.push((Arc::new(Listener::tcp(listener.local_addr()?)), Bound::Tcp(listener)));
+            }
+            for listener in configured {
+                let bound = Bound::bind(listener.bind()).await?;
+                listeners.push((Arc::new(listener), bound));
+            }
+            if listeners.is_empty() {
+                return Err(ServerError::NoListeners);
+            }
+            let federation_listener = match federation_listen {
+                Some(addr) => Some(TcpListener::bind(addr).await?),
+                None => None,
+            };
+            let admin_listener = match &options.admin_socket {
+                Some(path) => Some(bind_private(path)?),
+                None => None,
+            };
+            let metrics_listener = match options.metrics {
+                Some(addr) => Some(TcpListener::bind(addr).await?),
+                None => None,
+            };
+            let (control_sender, mut control_receiver) = mpsc::unbounded_channel();
+            let mut stopped = stopped.fuse();
+            loop {
+                let input = futures::select! {
+                    _ = stopped => break,
+                    admin = admin_receiver.recv().fuse() => match admin {
+                        Some(admin) => Input::Admin(admin),
+                        None => break,
+                    },
+                    admin = control_receiver.recv().fuse() => match admin {
+                        Some(admin) => Input::Admin(admin),
+                        None => continue,
+                    },
+                    accept = async {
+                        match &admin_listener {
+                            Some(listener) => listener.accept().await,
+                            None => future::pending().await,
+                        }
+                    }.fuse() => {
+                        let (stream, _) = match accept {
+                            Ok(accepted) => accepted,
+                            Err(e) => {
+                                logger.warn(format!("admin accept failed: {e:?}"));
+                                time::sleep(ACCEPT_RETRY).await;
+                                continue
+                            }
+                        };
+                        task::spawn({
+                            let control_sender = control_sender.clone();
+                            let logger = logger.clone();
+                            async move {
+                                if let Err(e) = control::session(stream, control_sender).await {
+                                    logger.warn(e);
+                                }
+                            }
+                        });
+                        continue
+                    }
+                    accept = async {
+                        match &metrics_listener {
+                            Some(listener) => listener.accept().await,
+                            None => future::pending().await,
+                        }
+                    }.fuse() => {
+                        let (stream, _) = match accept {
+                            Ok(accepted) => accepted,
+                            Err(e) => {
+                                logger.warn(format!("metrics accept failed: {e:?}"));
+                                time::sleep(ACCEPT_RETRY).await;
+                                continue
+                            }
+                        };
+                        task::spawn({
+                            let metrics = metrics.clone();
+                            let logger = logger.clone();
+                            async move {
+                                if let Ok(Err(e)) = time::timeout(METRICS_TIMEOUT, metrics::serve(stream, &metrics)).await {
+                                    logger.warn(e);
+                                }
+                            }
+                        });
+                        continue
+                    }
+                    (accept, index, _) = future::select_all(listeners.iter().map(|(_, bound)| Box::pin(bound.accept()))).fuse() => {
+                        let (stream, addr) = match accept {
+                            Ok(accepted) => accepted,
+                            Err(e) => {
+                                logger.warn(format!("accept failed: {e:?}"));
+                                time::sleep(ACCEPT_RETRY).await;
+                                continue
+                            }
+                        };
+                        match admission.admit(addr.map(|addr| addr.ip()), &options) {
+                            Some((permit, handshake)) => Input::Connection(stream, addr, listeners[index].0.clone(), permit, handshake),
+                            None => {
+                                metrics.connections_refused.fetch_add(1, Ordering::Relaxed);
+                                logger.warn(format!("refused connection from {}", peer_name(addr)));
+                                continue
+                            }
+                        }
+                    }
+                    accept = async {
+                        match &federation_listener {
+                            Some(listener) => listener.accept().await,
+                            None => future::pending().await,
+                        }
+                    }.fuse() => {
+                        let (stream, addr) = match accept {
+                            Ok(accepted) => accepted,
+                            Err(e) => {
+                                logger.warn(format!("federation accept failed: {e:?}"));
+                                time::sleep(ACCEPT_RETRY).await;
+                                continue
+                            }
+                        };
+                        match admission.admit(Some(addr.ip()), &options) {
+                            Some((permit, handshake)) => Input::Link(stream, addr, permit, handshake),
+                            None => {
+                                metrics.connections_refused.fetch_add(1, Ordering::Relaxed);
+                                logger.warn(format!("refused federation link from {addr}"));
+                                continue
+                            }
+                        }
+                    }
+                };
+                if let Err(e) = message_loop.send(input) {
                     logger.error(e)
                 }
             }
 
+            drop(listeners);
+            drop(federation_listener);
+            drop(metrics_listener);
+            if let (Some(_), Some(path)) = (admin_listener, &options.admin_socket) {
+                let _ = std::fs::remove_file(path);
+            }
             message_loop.shutdown().await??;
             Ok(())
         })
@@ -201,35 +1006,271 @@ impl<
 }
 
 
+pub struct AdminRequest {
+    pub admin: Admin,
+    pub reply: oneshot::Sender<AdminReply>,
+}
+
+impl AdminRequest {
+    pub fn new(admin: Admin) -> (Self, oneshot::Receiver<AdminReply>) {
+        let (reply, receiver) = oneshot::channel();
+        (Self { admin, reply }, receiver)
+    }
+}
+
+enum Input {
+    Connection(Socket, Option<SocketAddr>, Arc<Listener>, Permit, Handshake),
+    Link(TcpStream, SocketAddr, Permit, Handshake),
+    Admin(AdminRequest),
+}
+
+#[derive(Default)]
+struct Counts {
+    connections: usize,
+    handshakes: usize,
+    // unix socket peers have no address to share, so they are exempt from the per ip cap
+    ips: HashMap<IpAddr, usize>,
+}
+
+#[derive(Clone, Default)]
+struct Admission(Arc<Mutex<Counts>>);
+
+impl Admission {
+    fn admit(&self, ip: Option<IpAddr>, options: &Options) -> Option<(Permit, Handshake)> {
+        let mut counts = self.0.lock().unwrap();
+        let from_ip = ip.and_then(|ip| counts.ips.get(&ip).copied()).unwrap_or(0);
+        if options.max_connections.is_some_and(|max| counts.connections >= max)
+            || options.max_connections_per_ip.is_some_and(|max| from_ip >= max)
+            || options.max_handshakes.is_some_and(|max| counts.handshakes >= max) {
+            return None;
+        }
+        counts.connections += 1;
+        counts.handshakes += 1;
+        if let Some(ip) = ip {
+            *counts.ips.entry(ip).or_default() += 1;
+        }
+        Some((Permit { admission: self.clone(), ip }, Handshake(self.clone())))
+    }
+}
+
+struct Permit {
+    admission: Admission,
+    ip: Option<IpAddr>,
+}
+
+impl Drop for Permit {
+    fn drop(&mut self) {
+        let mut counts = self.admission.0.lock().unwrap();
+        counts.connections -= 1;
+        if let Some(Entry::Occupied(mut e)) = self.ip.map(|ip| counts.ips.entry(ip)) {
+            *e.get_mut() -= 1;
+            if *e.get() == 0 {
+                e.remove();
+            }
+        }
+    }
+}
+
+struct Handshake(Admission);
+
+impl Drop for Handshake {
+    fn drop(&mut self) {
+        self.0.0.lock().unwrap().handshakes -= 1;
+    }
+}
+
+const HISTORY_PAGE: usize = 100;
+
+const RECEIPTS: usize = 1024;
+
+const INCOMING: usize = 16;
+
+const LINK_RETRY: Duration = Duration::from_secs(5);
+
+const ACCEPT_RETRY: Duration = Duration::from_millis(100);
+
+const DRAIN_TIMEOUT: Duration = Duration::from_secs(10);
+
+const METRICS_TIMEOUT: Duration = Duration::from_secs(10);
+
+type ConnectionHandle<M> = Handle<Outgoing<M>, Result<(), ConnectionLoopError<M>>>;
+
+struct Disconnect<M> {
+    sender: UnboundedSender<Dispatch<M>>,
+    user: User,
+    connection: u64,
+}
+
+impl<M> Drop for Disconnect<M> {
+    fn drop(&mut self) {
+        let _ = self.sender.send(Dispatch::Disconnected { user: self.user.clone(), connection: self.connection });
+    }
+}
+
+type Connections<M> = HashMap<User, Vec<(u64, ConnectionHandle<M>)>>;
+
+struct Counters {
+    connected: SystemTime,
+    sent: AtomicU64,
+    received: AtomicU64,
+    bytes_sent: AtomicU64,
+    bytes_received: AtomicU64,
+}
+
+impl Counters {
+    fn new() -> Self {
+        Self {
+            connected: SystemTime::now(),
+            sent: AtomicU64::new(0),
+            received: AtomicU64::new(0),
+            bytes_sent: AtomicU64::new(0),
+            bytes_received: AtomicU64::new(0),
+        }
+    }
+
+    fn stats(&self, session: Session) -> ConnectionStats {
+        ConnectionStats {
+            session,
+            connected: self.connected,
+            sent: self.sent.load(Ordering::Relaxed),
+            received: self.received.load(Ordering::Relaxed),
+            bytes_sent: self.bytes_sent.load(Ordering::Relaxed),
+            bytes_received: self.bytes_received.load(Ordering::Relaxed),
+        }
+    }
+}
+
+#[derive(Clone, Default)]
+struct Mutes(Arc<Mutex<HashMap<User, Option<SystemTime>>>>);
+
+impl Mutes {
+    fn muted(&self, user: &User) -> bool {
+        let mut mutes = self.0.lock().unwrap();
+        match mutes.get(user) {
+            Some(Some(until)) if *until <= SystemTime::now() => {
+                mutes.remove(user);
+                false
+            }
+            Some(_) => true,
+            None => false,
+        }
+    }
+}
+
 #[derive(Debug)]
-pub enum ServerError<M: Deserializable> {
-    Connection(ConnectionLoopError<M>),
+pub enum Dispatch<M> {
+    Message {
+        from: User,
+        id: MessageId,
+        route: Route,
+        message: M,
+    },
+    Notice {
+        to: User,
+        notice: Notice,
+    },
+    Subscribe {
+        user: User,
+        to: User,
+        subscribed: bool,
+    },
+    Presence {
+        user: User,
+        connection: u64,
+        presence: Presence,
+        text: Option<String>,
+    },
+    Admin {
+        admin: Admin,
+        reply: oneshot::Sender<AdminReply>,
+    },
+    Disconnected {
+        user: User,
+        connection: u64,
+    },
+}
+
+#[derive(Default)]
+struct Presences {
+    connections: HashMap<User, HashMap<u64, Presence>>,
+    text: HashMap<User, String>,
+    subscribers: HashMap<User, HashSet<User>>,
+}
+
+impl Presences {
+    fn notice(&self, user: &User) -> Notice {
+        let presence = self.connections.get(user)
+            .into_iter()
+            .flat_map(HashMap::values)
+            .fold(Presence::Offline, |aggregate, presence| aggregate.max(*presence));
+        Notice::Presence {
+            user: user.to_string(),
+            presence,
+            text: self.text.get(user).cloned(),
+        }
+    }
+
+    fn update<F: FnOnce(&mut Self)>(&mut self, user: &User, f: F) -> Option<(Vec<User>, Notice)> {
+        let before = self.notice(user);
+        f(self);
+        let after = self.notice(user);
+        (before != after).then(|| (self.subscribers.get(user).into_iter().flatten().cloned().collect(), after))
+    }
+}
+
+enum Outgoing<M> {
+    Message(Delivery, M),
+    Notice(Notice),
+}
+
+#[derive(Debug)]
+pub enum Route {
+    Direct(User),
+    Room {
+        room: String,
+        members: Vec<User>,
+    },
+    Remote {
+        user: String,
+        domain: String,
+    },
+}
+
+impl Route {
+    fn target(&self) -> Target {
+        match self {
+            Route::Direct(to) => Target::User(to.to_string()),
+            Route::Room { room, .. } => Target::Room(room.clone()),
+            Route::Remote { user, domain } => Target::User(format!("{user}@{domain}")),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum ServerError {
     Join(JoinError),
     MessageReceiver,
+    Database(TrySendError<DatabaseEvent>),
     DbArcDrop,
     DatabaseLoop(DatabaseError),
     Io(io::Error),
+    NoListeners,
+    Scope(Scope),
 }
 
-impl<M: Deserializable> From<ConnectionLoopError<M>> for ServerError<M> {
-    fn from(value: ConnectionLoopError<M>) -> Self {
-        Self::Connection(value)
-    }
-}
-
-impl<M: Deserializable> From<JoinError> for ServerError<M> {
+impl From<JoinError> for ServerError {
     fn from(value: JoinError) -> Self {
         Self::Join(value)
     }
 }
 
-impl<M: Deserializable> From<DatabaseError> for ServerError<M> {
+impl From<DatabaseError> for ServerError {
     fn from(value: DatabaseError) -> Self {
         Self::DatabaseLoop(value)
     }
 }
 
-impl<M: Deserializable> From<io::Error> for ServerError<M> {
+impl From<io::Error> for ServerError {
     fn from(value: io::Error) -> Self {
         Self::Io(value)
     }
@@ -240,6 +1281,7 @@ enum ConnectionInitError {
     StreamCreation(io::Error),
     LogIn(LogInError, Option<WriteError>),
     Write(WriteError),
+    Timeout,
 }
 
 impl From<io::Error> for ConnectionInitError {
@@ -259,7 +1301,12 @@ enum LogInError {
     First(ReadRespondError<[u8; 1], ()>),
     Name(ReadRespondError<String, ()>),
     Password(ReadRespondError<Vec<u8>, !>),
-    ChannelSend(SendError<DatabaseEvent>),
+    Ticket(ReadRespondError<Vec<u8>, ()>),
+    Banned,
+    Bot,
+    RateLimited,
+    LockedOut,
+    ChannelSend(TrySendError<DatabaseEvent>),
     ChannelReceive,
 }
 
@@ -281,8 +1328,14 @@ impl From<ReadRespondError<Vec<u8>, !>> for LogInError {
     }
 }
 
-impl From<SendError<DatabaseEvent>> for LogInError {
-    fn from(value: SendError<DatabaseEvent>) -> Self {
+impl From<ReadRespondError<Vec<u8>, ()>> for LogInError {
+    fn from(value: ReadRespondError<Vec<u8>, ()>) -> Self {
+        Self::Ticket(value)
+    }
+}
+
+impl From<TrySendError<DatabaseEvent>> for LogInError {
+    fn from(value: TrySendError<DatabaseEvent>) -> Self {
         Self::ChannelSend(value)
     }
 }
@@ -293,6 +1346,12 @@ impl From<Canceled> for LogInError {
     }
 }
 
+enum Mode {
+    LogIn,
+    CreateUser,
+    Resume,
+}
+
 #[derive(Debug)]
 enum ReadRespondError<T: Deserializable, E> {
     Read(ReadError<T>),
@@ -323,8 +1382,9 @@ impl<T: Deserializable, E> From<WriteError> for ReadRespondError<T, E> {
 #[derive(Debug)]
 pub enum ConnectionLoopError<M: Deserializable> {
     Message(MessageReadError<M>),
-    Send(SendError<((User, User), M)>),
-    Write(WriteError),
+    Send(SendError<Dispatch<M>>),
+    Mux(MuxError),
+    Closed,
 }
 
 impl<M: Deserializable> From<MessageReadError<M>> for ConnectionLoopError<M> {
@@ -333,36 +1393,49 @@ impl<M: Deserializable> From<MessageReadError<M>> for ConnectionLoopError<M> {
     }
 }
 
-impl<M: Deserializable> From<SendError<((User, User), M)>> for ConnectionLoopError<M> {
-    fn from(value: SendError<((User, User), M)>) -> Self {
+impl<M: Deserializable> From<SendError<Dispatch<M>>> for ConnectionLoopError<M> {
+    fn from(value: SendError<Dispatch<M>>) -> Self {
         Self::Send(value)
     }
 }
 
-impl<M: Deserializable> From<WriteError> for ConnectionLoopError<M> {
-    fn from(value: WriteError) -> Self {
-        Self::Write(value)
+impl<M: Deserializable> From<MuxError> for ConnectionLoopError<M> {
+    fn from(value: MuxError) -> Self {
+        Self::Mux(value)
+    }
+}
+
+impl<M: Deserializable> From<Closed> for ConnectionLoopError<M> {
+    fn from(_: Closed) -> Self {
+        Self::Closed
     }
 }
 
 #[derive(Debug)]
 pub enum MessageReadError<M: Deserializable> {
-    UserRead(ReadError<Option<String>>),
-    Database(SendError<DatabaseEvent>),
+    EnvelopeRead(ReadError<Option<Envelope>>),
+    RequestRead(ReadError<Request>),
+    Database(TrySendError<DatabaseEvent>),
     User,
     MessageRead(ReadError<M>),
 }
 
-struct ReadUser(ReadError<Option<String>>);
+struct ReadEnvelope(ReadError<Option<Envelope>>);
+
+impl<M: Deserializable> From<ReadEnvelope> for MessageReadError<M> {
+    fn from(ReadEnvelope(value): ReadEnvelope) -> Self {
+        Self::EnvelopeRead(value)
+    }
+}
 
-impl<M: Deserializable> From<ReadUser> for MessageReadError<M> {
-    fn from(ReadUser(value): ReadUser) -> Self {
-        Self::UserRead(value)
+impl<M: Deserializable> From<ReadError<Request>> for MessageReadError<M> {
+    fn from(value: ReadError<Request>) -> Self {
+        Self::RequestRead(value)
     }
 }
 
-impl<M: Deserializable> From<SendError<DatabaseEvent>> for MessageReadError<M> {
-    fn from(value: SendError<DatabaseEvent>) -> Self {
+impl<M: Deserializable> From<TrySendError<DatabaseEvent>> for MessageReadError<M> {
+    fn from(value: TrySendError<DatabaseEvent>) -> Self {
         Self::Database(value)
     }
 }
@@ -381,17 +1454,44 @@ impl<M: Deserializable> From<ReadMessage<M>> for MessageReadError<M> {
     }
 }
 
-async fn connection_loop<M: for<'s> Message<Serializer<'s>: Send, Deserializer: Send> + Send + Sync + 'static, const N: usize>(
-    stream: TcpStream,
+async fn connection_loop<M: for<'s> Message<Serializer<'s>: Send, Deserializer: Send> + Send + Sync + 'static, const N: usize, H: MessageHook<M>>(
+    stream: Socket,
+    addr: Option<SocketAddr>,
+    listener: &Listener,
+    options: &Options,
+    tickets: Option<&Tickets>,
+    connection: u64,
+    permit: Permit,
+    ids: Arc<AtomicU64>,
+    mutes: Mutes,
+    rates: Arc<RateLimits>,
+    federation: Arc<Federation>,
+    metrics: Arc<Metrics>,
+    hooks: Arc<H>,
+    history: Option<Arc<Handle<HistoryEvent, ()>>>,
     db_sender: Arc<Handle<DatabaseEvent, Result<(), DatabaseError>>>,
-    message_sender: UnboundedSender<((User, User), M)>,
-) -> Result<(User, Handle<(User, M), Result<(), ConnectionLoopError<M>>>), ConnectionInitError> where [(); N + 16]:, <<M as Deserializable>::Deserializer as Deserializer<M>>::UpdateError: Send, <<M as Deserializable>::Deserializer as Deserializer<M>>::FinalizeError: Send {
+    message_sender: UnboundedSender<Dispatch<M>>,
+) -> Result<(User, ConnectionHandle<M>, Arc<Counters>), ConnectionInitError> where [(); N + 16]:, <<M as Deserializable>::Deserializer as Deserializer<M>>::UpdateError: Send, <<M as Deserializable>::Deserializer as Deserializer<M>>::FinalizeError: Send {
     async fn log_in<const N: usize>(
-        stream: &mut BlockStream<N>,
+        stream: &mut BlockStream<N, Socket>,
         db: &Handle<DatabaseEvent, Result<(), DatabaseError>>,
+        listener: &Listener,
+        tickets: Option<&Tickets>,
+        ip: Option<IpAddr>,
+        rates: &RateLimits,
+        bots: &HashSet<String>,
     ) -> Result<User, LogInError> where [(); N + 16]: {
+        async fn banned(db: &Handle<DatabaseEvent, Result<(), DatabaseError>>, ban: Ban) -> Result<(), LogInError> {
+            let (sender, receiver) = oneshot::channel();
+            db.send(DatabaseEvent::Banned { ban, channel: sender })?;
+            match receiver.await? {
+                false => Ok(()),
+                true => Err(LogInError::Banned),
+            }
+        }
+
         async fn read_respond<T: Deserializable, const N: usize, U, E, F: FnOnce(T) -> Result<U, E>>(
-            stream: &mut BlockStream<N>,
+            stream: &mut BlockStream<N, Socket>,
             f: F,
         ) -> Result<U, ReadRespondError<T, E>> where [(); N + 16]: {
             let t = f(stream.read_block::<T>().await?);
@@ -403,36 +1503,85 @@ async fn connection_loop<M: for<'s> Message<Serializer<'s>: Send, Deserializer:
             Ok(t.map_err(|e| TransformError(e))?)
         }
 
-        let first = read_respond(stream, |[t]: [u8; 1]| match t {
-            t @ (0 | 1) => Ok(t == 1),
+        let mode = read_respond(stream, |[t]: [u8; 1]| match t {
+            0 if listener.allows(AuthMethod::Password) => Ok(Mode::LogIn),
+            1 if listener.allows(AuthMethod::Register) => Ok(Mode::CreateUser),
+            2 if tickets.is_some() && listener.allows(AuthMethod::Ticket) => Ok(Mode::Resume),
             _ => Err(())
         }).await?;
 
-        let name = read_respond(stream, |t| Ok(t)).await?;
+        if !rates.logins.allows(None, None, ip, 1) {
+            return Err(LogInError::RateLimited);
+        }
+        if ip.is_some_and(|ip| rates.lockouts.locked(&Ban::Ip(ip))) {
+            return Err(LogInError::LockedOut);
+        }
 
         let (sender, receiver) = oneshot::channel();
-        let event = if first {
-            DatabaseEvent::CreateUser {
-                name,
-                password: read_respond(stream, |block: Vec<u8>| Ok(Password::new(&block))).await?,
+        let event = match (mode, tickets) {
+            (Mode::Resume, Some(tickets)) => DatabaseEvent::Resume {
+                ticket: read_respond(stream, |block: Vec<u8>| tickets.open(&block).ok_or(())).await?,
                 channel: sender,
-            }
-        } else {
-            DatabaseEvent::LogIn {
-                name,
-                password: read_respond(stream, |block| Ok(block)).await?,
+            },
+            (Mode::CreateUser, _) => DatabaseEvent::CreateUser {
+                name: read_respond(stream, |name: String| match name.contains('@') {
+                    true => Err(()),
+                    false => Ok(name),
+                }).await?,
+                password: read_respond(stream, |block: Vec<u8>| Ok::<_, !>(Password::new(&block))).await?,
                 channel: sender,
-            }
+            },
+            _ => DatabaseEvent::LogIn {
+                name: read_respond(stream, |t| Ok(t)).await?,
+                password: read_respond(stream, |block: Vec<u8>| Ok::<_, !>(block)).await?,
+                channel: sender,
+            },
         };
 
+        let attempted = match &event {
+            DatabaseEvent::LogIn { name, .. } | DatabaseEvent::CreateUser { name, .. } => Some(User::new(name.clone())),
+            _ => None,
+        };
+        if !rates.logins.take(None, attempted.as_ref(), ip, 1) {
+            return Err(LogInError::RateLimited);
+        }
+        let name = match &event {
+            DatabaseEvent::LogIn { name, .. } => Some(Ban::User(name.clone())),
+            _ => None,
+        };
+        if name.as_ref().is_some_and(|name| rates.lockouts.locked(name)) {
+            return Err(LogInError::LockedOut);
+        }
+        if let Some(ip) = ip {
+            banned(db, Ban::Ip(ip)).await?;
+        }
         db.send(event)?;
-
-        Ok(receiver.await?)
+        let user = receiver.await;
+        if let Some(name) = name {
+            match &user {
+                Ok(_) => {
+                    rates.lockouts.unlock(&name);
+                }
+                Err(_) => {
+                    rates.lockouts.fail(name);
+                    if let Some(ip) = ip {
+                        rates.lockouts.fail(Ban::Ip(ip));
+                    }
+                }
+            }
+        }
+        let user = user?;
+        if bots.contains(&user.to_string()) {
+            return Err(LogInError::Bot);
+        }
+        banned(db, Ban::User(user.to_string())).await?;
+        Ok(user)
     }
 
-    let mut stream = BlockStream::<N>::new(stream).await?;
+    let ip = addr.map(|addr| addr.ip());
+    let mut stream = BlockStream::<N, Socket>::accept(stream, &options.stream).await?;
 
-    let user = match log_in(&mut stream, &*db_sender).await {
+    let user = match log_in(&mut stream, &*db_sender, listener, tickets, ip, &rates, &options.bots).await {
         Ok(user) => Ok(user),
         Err(e) => Err(ConnectionInitError::LogIn(
             e,
@@ -447,52 +1596,710 @@ async fn connection_loop<M: for<'s> Message<Serializer<'s>: Send, Deserializer:
         )),
     }?;
     stream.write_block(Ok::<(), &str>(())).await?;
+    stream.write_block(tickets.map(|tickets| tickets.issue(&user))).await?;
 
     let user_clone = user.clone();
+    let counters = Arc::new(Counters::new());
+    let counters_clone = counters.clone();
+    let (block_reader, block_writer) = stream.split();
+    let mux = Mux::new(block_reader, block_writer, &options.stream);
+    let (control, mut control_incoming) = mux.channel(CONTROL);
+    let (chat, chat_incoming) = mux.channel(CHAT);
+    let (bulk, bulk_incoming) = mux.channel(BULK);
+
+    let max_message_size = options.max_message_size.unwrap_or(usize::MAX);
+    let drain_timeout = Some(options.drain_timeout.unwrap_or(DRAIN_TIMEOUT));
+    let admin = listener.allows_admin() && options.admins.read().unwrap().contains(&user.to_string());
+    let (capacity, overflow) = options.queue.map_or((None, Overflow::Block), |(capacity, overflow)| (Some(capacity), overflow));
+    let handle = Handle::<Outgoing<M>, _>::with_capacity(capacity, overflow, |receiver| async move {
+        let _disconnect = Disconnect { sender: message_sender.clone(), user: user_clone.clone(), connection };
+        let mut receiver = receiver;
+        enum Incoming<M> {
+            Message(u64, Route, M, usize),
+            Notice(Notice),
+            Read(MessageId, String),
+            Dispatch(Dispatch<M>),
+            Admin(Admin),
+            End,
+        }
 
-    let handle = Handle::<(User, M), _>::new(|mut receiver| async move {
         async fn read_message<const N: usize, M: Message>(
-            stream: &mut BlockStream<N>,
+            channel: &mut ChannelReceiver<N>,
+            db_sender: &Handle<DatabaseEvent, Result<(), DatabaseError>>,
+            federation: &Federation,
+            user: &User,
+            limit: usize,
+        ) -> Result<Incoming<M>, MessageReadError<M>> {
+            let Some(Envelope { seq, target }) = channel.recv::<Option<Envelope>>().await.map_err(|e| ReadEnvelope(e))? else {
+                return Ok(Incoming::End);
+            };
+            let route = route(db_sender, federation, user, target).await?;
+            let (message, size) = channel.recv_limited::<M>(limit).await.map_err(|e| ReadMessage(e))?;
+            Ok(match (route, message) {
+                (Ok(route), Some(message)) => Incoming::Message(seq, route, message, size),
+                (Err(reason), _) => Incoming::Notice(Notice::Rejected { seq, reason }),
+                (Ok(_), None) => Incoming::Notice(Notice::Rejected { seq, reason: Reason::TooLarge }),
+            })
+        }
+
+        async fn read_request<const N: usize, M: Message>(
+            channel: &mut ChannelReceiver<N>,
             db_sender: &Handle<DatabaseEvent, Result<(), DatabaseError>>,
-        ) -> Result<Option<(User, M)>, MessageReadError<M>> where [(); N + 16]: {
-            let Some(user) = stream.read_block::<Option<String>>().await.map_err(|e| ReadUser(e))? else {
-                return Ok(None);
+            federation: &Federation,
+            history: Option<&Handle<HistoryEvent, ()>>,
+            user: &User,
+            connection: u64,
+            admin: bool,
+        ) -> Result<Option<Incoming<M>>, MessageReadError<M>> {
+            let request = match channel.recv::<Request>().await {
+                Ok(request) => request,
+                Err(ReadError::Closed) => return Ok(None),
+                Err(e) => return Err(e.into()),
+            };
+            match request {
+                Request::Room(action, room) => {
+                    let (sender, receiver) = oneshot::channel();
+                    db_sender.send(DatabaseEvent::Room {
+                        action,
+                        name: room.clone(),
+                        user: user.clone(),
+                        channel: sender,
+                    })?;
+                    let ok = receiver.await?;
+                    Ok(Some(Incoming::Notice(Notice::Room { action, room, ok })))
+                }
+                Request::Read { id, from } => Ok(Some(Incoming::Read(id, from))),
+                Request::Block { user: name, blocked } => {
+                    let (sender, receiver) = oneshot::channel();
+                    db_sender.send(DatabaseEvent::SetBlocked {
+                        user: User::new(name.clone()),
+                        by: user.clone(),
+                        blocked,
+                        channel: sender,
+                    })?;
+                    let ok = receiver.await?;
+                    Ok(Some(Incoming::Notice(Notice::Block { user: name, blocked, ok })))
+                }
+                Request::Subscribe { user: name, subscribed: false } => Ok(Some(Incoming::Dispatch(Dispatch::Subscribe {
+                    user: user.clone(),
+                    to: User::new(name),
+                    subscribed: false,
+                }))),
+                Request::Subscribe { user: name, subscribed: true } => Ok(Some(match route(db_sender, federation, user, Target::User(name.clone())).await? {
+                    Ok(Route::Direct(to)) => Incoming::Dispatch(Dispatch::Subscribe {
+                        user: user.clone(),
+                        to,
+                        subscribed: true,
+                    }),
+                    // unknown, remote and blocking users all look offline
+                    _ => Incoming::Notice(Notice::Presence { user: name, presence: Presence::Offline, text: None }),
+                })),
+                Request::Presence { presence, text } => Ok(Some(Incoming::Dispatch(Dispatch::Presence {
+                    user: user.clone(),
+                    connection,
+                    presence,
+                    text,
+                }))),
+                Request::Admin(request) => Ok(Some(match admin {
+                    true => Incoming::Admin(request),
+                    false => Incoming::Notice(Notice::Admin(AdminReply::Denied)),
+                })),
+                Request::History { with, cursor, limit } => Ok(Some(Incoming::Notice(query_history(history, db_sender, user, with, cursor, limit as usize).await?))),
+            }
+        }
+
+        // bounded so a connection whose loop falls behind stops reading and the mux window pushes back on the client
+        let (incoming_sender, mut incoming) = mpsc::channel(INCOMING);
+        let reading = [chat_incoming, bulk_incoming].map(|mut channel| {
+            let incoming_sender = incoming_sender.clone();
+            let db_sender = db_sender.clone();
+            let federation = federation.clone();
+            let user = user_clone.clone();
+            task::spawn(async move {
+                loop {
+                    let m = read_message(&mut channel, &*db_sender, &federation, &user, max_message_size).await;
+                    let last = matches!(m, Ok(Incoming::End) | Err(_));
+                    if incoming_sender.send(m).await.is_err() || last {
+                        break;
+                    }
+                }
+            })
+        });
+        let requests = task::spawn({
+            let db_sender = db_sender.clone();
+            let federation = federation.clone();
+            let history = history.clone();
+            let user = user_clone.clone();
+            async move {
+                loop {
+                    let m = read_request(&mut control_incoming, &*db_sender, &federation, history.as_deref(), &user, connection, admin).await.transpose();
+                    let last = !matches!(m, Some(Ok(_)));
+                    let sent = match m {
+                        Some(m) => incoming_sender.send(m).await.is_ok(),
+                        None => true,
+                    };
+                    if !sent || last {
+                        break;
+                    }
+                }
+            }
+        });
+
+        // receipts are only forwarded for messages this connection was actually sent
+        let mut delivered = VecDeque::new();
+        let mut message_bucket = rates.messages.bucket();
+        let mut byte_bucket = rates.bytes.bucket();
+        let result = async {
+            let mut open = reading.len();
+            loop {
+                futures::select! {
+                    m = incoming.recv().fuse() => match m {
+                        Some(Ok(Incoming::Message(seq, _, _, _))) if mutes.muted(&user_clone) => {
+                            metrics.messages_dropped.fetch_add(1, Ordering::Relaxed);
+                            control.send(Notice::Rejected { seq, reason: Reason::Muted }).await?
+                        }
+                        Some(Ok(Incoming::Message(seq, _, _, size))) if !rates.take_message(message_bucket.as_mut(), byte_bucket.as_mut(), &user_clone, ip, size) => {
+                            metrics.messages_dropped.fetch_add(1, Ordering::Relaxed);
+                            control.send(Notice::Rejected { seq, reason: Reason::RateLimited }).await?
+                        }
+                        Some(Ok(Incoming::Message(seq, route, message, size))) => {
+                            let message = match hooks.check(&user_clone, &route.target(), &message) {
+                                Verdict::Allow => Some(message),
+                                Verdict::Modify(message) => Some(message),
+                                Verdict::Drop => None,
+                                Verdict::Reject(reason) => {
+                                    metrics.messages_dropped.fetch_add(1, Ordering::Relaxed);
+                                    control.send(Notice::Rejected { seq, reason }).await?;
+                                    continue
+                                }
+                            };
+                            let id = MessageId(ids.fetch_add(1, Ordering::Relaxed));
+                            control.send(Notice::Accepted { seq, id }).await?;
+                            counters_clone.sent.fetch_add(1, Ordering::Relaxed);
+                            counters_clone.bytes_sent.fetch_add(size as u64, Ordering::Relaxed);
+                            match message {
+                                Some(message) => message_sender.send(Dispatch::Message { from: user_clone.clone(), id, route, message })?,
+                                None => {
+                                    metrics.messages_dropped.fetch_add(1, Ordering::Relaxed);
+                                }
+                            }
+                        }
+                        Some(Ok(Incoming::Notice(notice))) => {
+                            if let Notice::Rejected { .. } = notice {
+                                metrics.messages_dropped.fetch_add(1, Ordering::Relaxed);
+                            }
+                            control.send(notice).await?
+                        }
+                        Some(Ok(Incoming::Read(id, from))) => {
+                            if let Some(index) = delivered.iter().position(|(delivered, sender)| *delivered == id && *sender == from) {
+                                delivered.remove(index);
+                                message_sender.send(Dispatch::Notice {
+                                    to: User::new(from),
+                                    notice: Notice::Status { id, user: user_clone.to_string(), status: Status::Read },
+                                })?
+                            }
+                        }
+                        Some(Ok(Incoming::Dispatch(dispatch))) => message_sender.send(dispatch)?,
+                        Some(Ok(Incoming::Admin(admin))) => {
+                            let (AdminRequest { admin, reply }, receiver) = AdminRequest::new(admin);
+                            message_sender.send(Dispatch::Admin { admin, reply })?;
+                            let control = control.clone();
+                            task::spawn(async move {
+                                if let Ok(reply) = receiver.await {
+                                    let _ = control.send(Notice::Admin(reply)).await;
+                                }
+                            });
+                        }
+                        Some(Ok(Incoming::End)) if open > 1 => open -= 1,
+                        Some(Ok(Incoming::End)) | None => break Ok::<_, ConnectionLoopError<M>>(()),
+                        Some(Err(e)) => break Err(e.into()),
+                    },
+                    m = receiver.recv().fuse() => {
+                        match m {
+                            Some(Outgoing::Message(delivery, message)) => {
+                                delivered.push_back((delivery.id, delivery.origin.sender().to_owned()));
+                                if delivered.len() > RECEIPTS {
+                                    delivered.pop_front();
+                                }
+                                let message = Encoded::new::<N, _>(message);
+                                counters_clone.received.fetch_add(1, Ordering::Relaxed);
+                                counters_clone.bytes_received.fetch_add(message.bytes() as u64, Ordering::Relaxed);
+                                let channel = if message.blocks() > 1 { &bulk } else { &chat };
+                                channel.send(delivery).await?;
+                                channel.send_encoded(message).await?;
+                            }
+                            Some(Outgoing::Notice(notice)) => control.send(notice).await?,
+                            None => break Ok(()),
+                        }
+                    },
+                }
+            }
+        }.await;
+        reading.iter().for_each(JoinHandle::abort);
+        requests.abort();
+        let closed = mux.shutdown(drain_timeout).await;
+        drop(receiver);
+        drop(permit);
+        result?;
+        Ok(closed?)
+    });
+
+    Ok((user, handle, counters))
+}
+
+// bot accounts carry a password derived from the server's bot secret, so an existing account only becomes the bot if it was created as one;
+// clients can never log in under a bot name. without a configured secret it is random, and bot accounts from earlier runs are refused
+async fn bot_user(db: &Handle<DatabaseEvent, Result<(), DatabaseError>>, secret: &[u8; 32], name: String) -> Result<Option<User>, TrySendError<DatabaseEvent>> {
+    let password = Sha256::new().chain_update(b"chat bot ").chain_update(secret).chain_update(&name).finalize().to_vec();
+    let (sender, receiver) = oneshot::channel();
+    db.send(DatabaseEvent::GetUser { name: name.clone(), channel: sender })?;
+    let (sender, user) = oneshot::channel();
+    match receiver.await {
+        Ok(_) => db.send(DatabaseEvent::LogIn { name, password, channel: sender })?,
+        Err(Canceled) => db.send(DatabaseEvent::CreateUser { name, password: Password::new(&password), channel: sender })?,
+    }
+    Ok(user.await.ok())
+}
+
+fn run_bot<M: Message + Send + 'static, H: MessageHook<M>>(
+    user: User,
+    connection: u64,
+    mut bot: Box<dyn Bot<M>>,
+    hooks: Arc<H>,
+    federation: Arc<Federation>,
+    ids: Arc<AtomicU64>,
+    db_sender: Arc<Handle<DatabaseEvent, Result<(), DatabaseError>>>,
+    message_sender: UnboundedSender<Dispatch<M>>,
+) -> ConnectionHandle<M> where ConnectionLoopError<M>: Send {
+    Handle::new(|receiver| async move {
+        let _disconnect = Disconnect { sender: message_sender.clone(), user: user.clone(), connection };
+        let mut receiver = receiver;
+        let (outbox, mut outgoing) = Outbox::new();
+        bot.start(outbox.clone());
+        let result = async {
+            loop {
+                futures::select! {
+                    m = receiver.recv().fuse() => match m {
+                        Some(Outgoing::Message(delivery, message)) => bot.message(delivery, message, &outbox),
+                        Some(Outgoing::Notice(notice)) => bot.notice(notice, &outbox),
+                        None => break Ok::<_, ConnectionLoopError<M>>(()),
+                    },
+                    m = outgoing.recv().fuse() => {
+                        let Some((seq, target, message)) = m else { continue };
+                        let notice = match route(&db_sender, &federation, &user, target).await? {
+                            Err(reason) => Notice::Rejected { seq, reason },
+                            Ok(route) => {
+                                let message = match hooks.check(&user, &route.target(), &message) {
+                                    Verdict::Allow => Some(message),
+                                    Verdict::Modify(message) => Some(message),
+                                    Verdict::Drop => None,
+                                    Verdict::Reject(reason) => {
+                                        bot.notice(Notice::Rejected { seq, reason }, &outbox);
+                                        continue
+                                    }
+                                };
+                                let id = MessageId(ids.fetch_add(1, Ordering::Relaxed));
+                                if let Some(message) = message {
+                                    message_sender.send(Dispatch::Message { from: user.clone(), id, route, message })?;
+                                }
+                                Notice::Accepted { seq, id }
+                            }
+                        };
+                        bot.notice(notice, &outbox);
+                    },
+                }
+            }
+        }.await;
+        drop(receiver);
+        result
+    })
+}
+
+async fn link_in<const N: usize, M: Message + Send + 'static, H: MessageHook<M>>(
+    stream: TcpStream,
+    _permit: Permit,
+    admitted: Handshake,
+    options: &Options,
+    federation: &Federation,
+    ids: &AtomicU64,
+    hooks: &H,
+    db_sender: &Handle<DatabaseEvent, Result<(), DatabaseError>>,
+    message_sender: &UnboundedSender<Dispatch<M>>,
+) -> Result<(), LinkError> where [(); N + 16]: {
+    let handshake = async {
+        let mut stream = BlockStream::<N>::accept(stream, &options.stream).await?;
+        let domain = federation::accept(&mut stream, &federation.peers).await?;
+        Ok::<_, LinkError>((stream, domain))
+    };
+    let (stream, domain) = match options.handshake_timeout {
+        Some(timeout) => time::timeout(timeout, handshake).await.map_err(|_| LinkError::Timeout)??,
+        None => handshake.await?,
+    };
+    drop(admitted);
+
+    let (block_reader, block_writer) = stream.split();
+    let mux = Mux::new(block_reader, block_writer, &options.stream);
+    let (_, mut relays) = mux.channel(CONTROL);
+    let result = async {
+        loop {
+            let relay = match relays.recv::<Relay>().await {
+                Ok(relay) => relay,
+                Err(ReadError::Closed) => break Ok(()),
+                Err(e) => break Err(e.into()),
             };
+            if relay.from.contains('@') {
+                continue;
+            }
+            let Some(message) = serialization::from_bytes::<M>(&relay.message) else { continue };
+            let from = User::new(format!("{}@{domain}", relay.from));
+            let route = match route::<M>(db_sender, federation, &from, Target::User(relay.to)).await {
+                Ok(Ok(route @ Route::Direct(_))) => route,
+                Ok(_) => continue,
+                Err(_) => break Err(LinkError::Closed),
+            };
+            let message = match hooks.check(&from, &route.target(), &message) {
+                Verdict::Allow => message,
+                Verdict::Modify(message) => message,
+                Verdict::Drop | Verdict::Reject(_) => continue,
+            };
+            let id = MessageId(ids.fetch_add(1, Ordering::Relaxed));
+            if message_sender.send(Dispatch::Message { from, id, route, message }).is_err() {
+                break Err(LinkError::Closed);
+            }
+        }
+    }.await;
+    mux.shutdown(Some(options.drain_timeout.unwrap_or(DRAIN_TIMEOUT))).await?;
+    result
+}
+
+async fn link_out<const N: usize, L: Logger>(
+    domain: String,
+    local: String,
+    peer: Peer,
+    config: stream::Config,
+    logger: L,
+    mut relays: mpsc::UnboundedReceiver<Relay>,
+) where [(); N + 16]: {
+    let mut pending = None;
+    loop {
+        if pending.is_none() {
+            pending = relays.recv().await;
+            if pending.is_none() {
+                break;
+            }
+        }
+        let link = async {
+            let mut stream = BlockStream::<N>::connect(TcpStream::connect(peer.addr).await?, &config).await?;
+            federation::dial(&mut stream, &local, &peer).await?;
+            let (block_reader, block_writer) = stream.split();
+            let mux = Mux::new(block_reader, block_writer, &config);
+            let (sender, _) = mux.channel(CONTROL);
+            logger.info(format!("federation link to {domain} established"));
+            while let Some(relay) = pending.take() {
+                if sender.send(relay.clone()).await.is_err() {
+                    pending = Some(relay);
+                    break;
+                }
+                pending = relays.recv().await;
+            }
+            mux.shutdown(None).await?;
+            Ok::<_, LinkError>(())
+        };
+        match link.await {
+            Ok(()) if pending.is_none() => logger.info(format!("federation link to {domain} closed")),
+            Ok(()) => {
+                logger.warn(format!("federation link to {domain} closed with a relay in flight"));
+                time::sleep(LINK_RETRY).await;
+            }
+            Err(e) => {
+                logger.warn(format!("federation link to {domain} failed: {e:?}"));
+                time::sleep(LINK_RETRY).await;
+            }
+        }
+    }
+}
+
+async fn query_history<M: Deserializable>(
+    history: Option<&Handle<HistoryEvent, ()>>,
+    db_sender: &Handle<DatabaseEvent, Result<(), DatabaseError>>,
+    user: &User,
+    with: Target,
+    cursor: Cursor,
+    limit: usize,
+) -> Result<Notice, MessageReadError<M>> {
+    let limit = limit.min(HISTORY_PAGE);
+    let Some(history) = history else {
+        return Ok(Notice::History { with, entries: Vec::new(), more: false });
+    };
+    if let Target::Room(room) = &with {
+        let (sender, receiver) = oneshot::channel();
+        db_sender.send(DatabaseEvent::RoomMembers {
+            name: room.clone(),
+            channel: sender,
+        })?;
+        if !receiver.await?.contains(user) {
+            return Ok(Notice::History { with, entries: Vec::new(), more: false });
+        }
+    }
+    let (sender, receiver) = oneshot::channel();
+    let mut records = match history.send(HistoryEvent::Query { user: user.clone(), with: with.clone(), cursor, limit: limit + 1, channel: sender }) {
+        Ok(()) => receiver.await.unwrap_or_default(),
+        Err(_) => Vec::new(),
+    };
+    let more = records.len() > limit;
+    if more {
+        match cursor {
+            Cursor::After(_) => records.truncate(limit),
+            Cursor::Latest | Cursor::Before(_) => {
+                records.drain(..records.len() - limit);
+            }
+        }
+    }
+    let entries = records.into_iter().map(|Record { id, from, to, message, sent }| HistoryEntry {
+        delivery: Delivery {
+            id,
+            origin: match to {
+                Target::User(_) => Origin::User(from.to_string()),
+                Target::Room(room) => Origin::Room { room, from: from.to_string() },
+            },
+        },
+        sent,
+        message,
+    }).collect();
+    Ok(Notice::History { with, entries, more })
+}
+
+async fn route<M: Deserializable>(
+    db_sender: &Handle<DatabaseEvent, Result<(), DatabaseError>>,
+    federation: &Federation,
+    user: &User,
+    target: Target,
+) -> Result<Result<Route, Reason>, MessageReadError<M>> {
+    Ok(match target {
+        Target::User(name) => match federation.resolve(name) {
+            Address::Remote { user: name, domain } => Ok(Route::Remote { user: name, domain }),
+            Address::Unknown => Err(Reason::UnknownRecipient),
+            Address::Local(name) => {
+                let (sender, receiver) = oneshot::channel();
+                db_sender.send(DatabaseEvent::GetUser {
+                    name,
+                    channel: sender,
+                })?;
+                match receiver.await {
+                    Ok(to) => {
+                        let (sender, receiver) = oneshot::channel();
+                        db_sender.send(DatabaseEvent::Blocked {
+                            user: user.clone(),
+                            by: to.clone(),
+                            channel: sender,
+                        })?;
+                        match receiver.await? {
+                            false => Ok(Route::Direct(to)),
+                            true => Err(Reason::Blocked),
+                        }
+                    }
+                    Err(Canceled) => Err(Reason::UnknownRecipient),
+                }
+            }
+        },
+        Target::Room(room) => {
             let (sender, receiver) = oneshot::channel();
-            db_sender.send(DatabaseEvent::GetUser {
-                name: user,
+            db_sender.send(DatabaseEvent::RoomMembers {
+                name: room.clone(),
                 channel: sender,
             })?;
-            let user = receiver.await?;
-            let message = stream.read_block::<M>().await.map_err(|e| ReadMessage(e))?;
-            Ok(Some((user, message)))
+            let members = receiver.await?;
+            match members.contains(user) {
+                true => Ok(Route::Room { room, members }),
+                false => Err(Reason::NotMember),
+            }
         }
+    })
+}
 
-        loop {
-            futures::select! {
-                m = read_message(&mut stream, &*db_sender).fuse() => {
-                    let Some((from, message)) = m? else {
-                        break Ok(())
-                    };
-                    message_sender.send(((from, user_clone.clone()), message))?;
-                },
-                m = receiver.recv().fuse() => {
-                    let Some((user, message)) = m else {
-                        break Ok(())
-                    };
-                    stream.write_block(user.into_bytes()).await?;
-                    stream.write_block(message).await?;
-                }
+// notices are best effort, a connection whose queue is full misses them instead of stalling the router
+fn notify<M: Deserializable>(handles: &Connections<M>, to: &User, notice: Notice) where ConnectionLoopError<M>: Send + 'static {
+    for (_, handle) in handles.get(to).into_iter().flatten() {
+        let _ = handle.send(Outgoing::Notice(notice.clone()));
+    }
+}
+
+fn publish<M: Deserializable>(handles: &Connections<M>, changed: Option<(Vec<User>, Notice)>) where ConnectionLoopError<M>: Send + 'static {
+    let Some((subscribers, notice)) = changed else { return };
+    for subscriber in subscribers {
+        notify(handles, &subscriber, notice.clone());
+    }
+}
+
+fn remove<M: Deserializable>(handles: &mut Connections<M>, user: &User, connection: u64) -> Option<ConnectionHandle<M>> {
+    let Entry::Occupied(mut e) = handles.entry(user.clone()) else { return None };
+    let index = e.get().iter().position(|(id, _)| *id == connection)?;
+    let (_, handle) = e.get_mut().swap_remove(index);
+    if e.get().is_empty() {
+        e.remove();
+    }
+    Some(handle)
+}
+
+pub(crate) fn peer_name(addr: Option<SocketAddr>) -> String {
+    addr.map_or_else(|| "unix".to_owned(), |addr| addr.to_string())
+}
+
+async fn report<M: Deserializable + Debug + Send, L: Logger>(
+    handle: ConnectionHandle<M>,
+    connection: u64,
+    session: Option<Session>,
+    deadline: Option<time::Instant>,
+    logger: L,
+) where ConnectionLoopError<M>: Send + 'static {
+    let context = match session {
+        Some(Session { user, addr, .. }) => format!("connection {connection} ({user} from {})", peer_name(addr)),
+        None => format!("connection {connection}"),
+    };
+    let result = match deadline {
+        Some(deadline) => handle.shutdown_by(deadline).await,
+        None => Some(handle.shutdown().await),
+    };
+    match result {
+        Some(Ok(Ok(()))) => logger.info(format!("{context} closed")),
+        Some(Ok(Err(e))) => logger.error(format!("{context} failed: {e:?}")),
+        Some(Err(e)) => logger.error(format!("{context} panicked: {e:?}")),
+        None => logger.warn(format!("{context} did not drain in time and was dropped")),
+    }
+}
+
+fn disconnect<M: Deserializable + Debug + Send, L: Logger + Send + 'static, F: FnMut(&User, u64) -> bool>(
+    handles: &mut Connections<M>,
+    sessions: &BTreeMap<u64, Session>,
+    logger: &L,
+    mut f: F,
+) -> bool where ConnectionLoopError<M>: Send + 'static {
+    let mut closed = Vec::new();
+    for (user, handles) in handles.iter_mut() {
+        let (kicked, kept): (Vec<_>, Vec<_>) = handles.drain(..).partition(|(connection, _)| f(user, *connection));
+        *handles = kept;
+        closed.extend(kicked.into_iter().map(|(connection, handle)| report(handle, connection, sessions.get(&connection).cloned(), None, logger.clone())));
+    }
+    handles.retain(|_, handles| !handles.is_empty());
+    let kicked = !closed.is_empty();
+    task::spawn(future::join_all(closed));
+    kicked
+}
+
+async fn administer<M: Deserializable + Debug + Send, L: Logger + Send + 'static>(
+    handles: &mut Connections<M>,
+    sessions: &BTreeMap<u64, Session>,
+    counters: &BTreeMap<u64, Arc<Counters>>,
+    mutes: &Mutes,
+    rates: &RateLimits,
+    options: &Options,
+    db: &Handle<DatabaseEvent, Result<(), DatabaseError>>,
+    logger: &L,
+    admin: Admin,
+) -> AdminReply where ConnectionLoopError<M>: Send + 'static {
+    match admin {
+        Admin::Sessions => AdminReply::Sessions(sessions.values().cloned().collect()),
+        Admin::Kick(name) => AdminReply::Done(disconnect(handles, sessions, logger, |user, _| user.to_string() == name)),
+        Admin::Ban { ban, duration } => {
+            let until = match duration.map(|duration| SystemTime::now().checked_add(duration)) {
+                Some(None) => return AdminReply::Failed("ban duration is too long".to_owned()),
+                until => until.flatten(),
+            };
+            let ok = db.send(DatabaseEvent::Ban { ban: ban.clone(), until }).is_ok();
+            match ban {
+                Ban::User(name) => disconnect(handles, sessions, logger, |user, _| user.to_string() == name),
+                Ban::Ip(ip) => disconnect(handles, sessions, logger, |_, connection| sessions.get(&connection).is_some_and(|session| session.addr.is_some_and(|addr| addr.ip() == ip))),
+            };
+            AdminReply::Done(ok)
+        }
+        Admin::Unban(ban) => {
+            let (sender, receiver) = oneshot::channel();
+            match db.send(DatabaseEvent::Unban { ban, channel: sender }) {
+                Ok(()) => AdminReply::Done(receiver.await.unwrap_or(false)),
+                Err(_) => AdminReply::Done(false),
             }
         }
-    });
+        Admin::Mute { user, duration } => {
+            let until = match duration.map(|duration| SystemTime::now().checked_add(duration)) {
+                Some(None) => return AdminReply::Failed("mute duration is too long".to_owned()),
+                until => until.flatten(),
+            };
+            mutes.0.lock().unwrap().insert(User::new(user), until);
+            AdminReply::Done(true)
+        }
+        Admin::Unmute(user) => AdminReply::Done(mutes.0.lock().unwrap().remove(&User::new(user)).is_some()),
+        Admin::Shutdown { .. } => AdminReply::Done(false),
+        Admin::Lockouts => AdminReply::Lockouts(rates.lockouts.list()),
+        Admin::Unlock(key) => AdminReply::Done(rates.lockouts.unlock(&key)),
+        Admin::Stats => AdminReply::Stats(sessions.values()
+            .filter_map(|session| counters.get(&session.connection).map(|counters| counters.stats(session.clone())))
+            .collect()),
+        Admin::Reload => match options.reload.as_ref().map(|reload| reload()) {
+            Some(Ok(Reload { admins })) => {
+                *options.admins.write().unwrap() = admins;
+                logger.info("configuration reloaded");
+                AdminReply::Done(true)
+            }
+            Some(Err(e)) => AdminReply::Failed(e),
+            None => AdminReply::Failed("reloading is not configured".to_owned()),
+        },
+    }
+}
 
-    Ok((user, handle))
+async fn history_loop<S: History + Send + 'static>(mut history: S, mut event_receiver: Receiver<HistoryEvent>) {
+    while let Some(event) = event_receiver.recv().await {
+        event.execute(&mut history).await;
+    }
 }
 
-async fn db_loop<DB: DataBase + Send + 'static>(mut db: DB, mut event_receiver: UnboundedReceiver<DatabaseEvent>) -> Result<(), DatabaseError> {
+async fn db_loop<DB: DataBase + Send + 'static, L: Logger>(mut db: DB, mut event_receiver: Receiver<DatabaseEvent>, metrics: Arc<Metrics>, logger: L) -> Result<(), DatabaseError> {
+    // hashed up front so the first unknown name is not slower than the rest
+    Password::dummy();
     while let Some(event) = event_receiver.recv().await {
-        event.execute(&mut db).await?;
+        let operation = event.name();
+        let start = time::Instant::now();
+        let result = event.execute(&mut db).await;
+        metrics.database(operation, start.elapsed());
+        if let Err(e) = result {
+            logger.warn(e);
+        }
     }
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // stands in for a database with room for a single account
+    fn db() -> Handle<DatabaseEvent, Result<(), DatabaseError>> {
+        Handle::new(|mut receiver| async move {
+            let mut account = None::<Password>;
+            while let Some(event) = receiver.recv().await {
+                match event {
+                    DatabaseEvent::GetUser { name, channel } if account.is_some() => {
+                        let _ = channel.send(User::new(name));
+                    }
+                    DatabaseEvent::CreateUser { name, password, channel } => {
+                        account = Some(password);
+                        let _ = channel.send(User::new(name));
+                    }
+                    DatabaseEvent::LogIn { name, password, channel } if account.as_ref().is_some_and(|account| account.verify(&password).is_ok()) => {
+                        let _ = channel.send(User::new(name));
+                    }
+                    _ => {}
+                }
+            }
+            Ok(())
+        })
+    }
+
+    #[tokio::test]
+    async fn bot_accounts_need_the_secret_they_were_created_with() {
+        let db = db();
+        assert_eq!(bot_user(&db, &[1; 32], "help".to_owned()).await.unwrap(), Some(User::new("help".to_owned())));
+        assert_eq!(bot_user(&db, &[1; 32], "help".to_owned()).await.unwrap(), Some(User::new("help".to_owned())));
+        assert_eq!(bot_user(&db, &[2; 32], "help".to_owned()).await.unwrap(), None);
+        db.shutdown().await.unwrap().unwrap();
+    }
+}
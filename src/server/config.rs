@@ -0,0 +1,583 @@
+use std::{
+    collections::HashMap,
+    fmt::{self, Display, Formatter},
+    fs::OpenOptions,
+    io,
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    str::FromStr,
+    time::Duration,
+};
+
+use serde::Deserialize;
+
+use crate::{
+    db::DataBase,
+    federation::Peer,
+    handle::Overflow,
+    logger::{AnyLogger, FileLogger, LevelFilter, LogLevel, StdioLogger},
+    CipherSuite,
+    KeyExchange,
+    RateLimit,
+    Scope,
+    Uninitialized,
+};
+
+use super::{AuthMethod, Bind, Builder, IntoAddr, Listener, Options, Reload};
+
+const BLOCK_SIZES: [usize; 5] = [512, 1024, 2048, 4096, 8192];
+
+#[derive(Debug)]
+pub enum ConfigError {
+    Io(PathBuf, io::Error),
+    Parse(toml::de::Error),
+    Invalid {
+        key: String,
+        message: String,
+    },
+}
+
+impl ConfigError {
+    pub fn invalid<K: Into<String>, M: Into<String>>(key: K, message: M) -> Self {
+        Self::Invalid { key: key.into(), message: message.into() }
+    }
+}
+
+impl Display for ConfigError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigError::Io(path, e) => write!(f, "{}: {e}", path.display()),
+            ConfigError::Parse(e) => write!(f, "{e}"),
+            ConfigError::Invalid { key, message } => write!(f, "{key}: {message}"),
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+impl From<toml::de::Error> for ConfigError {
+    fn from(value: toml::de::Error) -> Self {
+        Self::Parse(value)
+    }
+}
+
+pub struct DatabaseConfig {
+    pub backend: String,
+    pub path: Option<PathBuf>,
+}
+
+enum LogTarget {
+    Stdout,
+    File(PathBuf),
+    None,
+}
+
+// server.listen may be left out when listeners are configured
+pub struct ConfiguredAddr(Option<SocketAddr>);
+
+impl IntoAddr for ConfiguredAddr {
+    type Addr = SocketAddr;
+
+    fn into_addr(self) -> Option<Self::Addr> {
+        self.0
+    }
+}
+
+pub struct Config {
+    pub listen: Option<SocketAddr>,
+    pub block_size: usize,
+    pub database: DatabaseConfig,
+    log_target: LogTarget,
+    log_level: LogLevel,
+    options: Options,
+}
+
+impl Config {
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, ConfigError> {
+        let path = path.as_ref();
+        let text = std::fs::read_to_string(path).map_err(|e| ConfigError::Io(path.to_owned(), e))?;
+        let mut config = Self::from_str(&text)?;
+        let path = path.to_owned();
+        config.options.reload = Some(Box::new(move || {
+            let Config { options, .. } = Config::load(&path).map_err(|e| e.to_string())?;
+            Ok(Reload { admins: options.admins.into_inner().unwrap() })
+        }));
+        Ok(config)
+    }
+
+    pub fn builder<DB: DataBase + Send + 'static>(self, db: DB) -> Result<Builder<ConfiguredAddr, DB, LevelFilter<AnyLogger>>, ConfigError> {
+        let logger = match self.log_target {
+            LogTarget::Stdout => AnyLogger::Stdio(StdioLogger),
+            LogTarget::File(path) => AnyLogger::File(FileLogger::new(
+                OpenOptions::new().create(true).append(true).open(&path).map_err(|e| ConfigError::Io(path, e))?,
+            )),
+            LogTarget::None => AnyLogger::None,
+        };
+        Ok(Builder {
+            addr: ConfiguredAddr(self.listen),
+            db,
+            logger: LevelFilter::new(logger, self.log_level),
+            hooks: (),
+            bots: (),
+            history: Uninitialized,
+            options: self.options,
+        })
+    }
+}
+
+impl FromStr for Config {
+    type Err = ConfigError;
+
+    fn from_str(text: &str) -> Result<Self, Self::Err> {
+        toml::from_str::<Raw>(text)?.validate()
+    }
+}
+
+#[derive(Deserialize, Default)]
+#[serde(deny_unknown_fields, default)]
+struct Raw {
+    server: RawServer,
+    logger: RawLogger,
+    database: RawDatabase,
+    limits: RawLimits,
+    rates: RawRates,
+    security: RawSecurity,
+    federation: RawFederation,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(deny_unknown_fields, default)]
+struct RawServer {
+    listen: Option<String>,
+    block_size: Option<usize>,
+    admin_socket: Option<PathBuf>,
+    metrics: Option<String>,
+    listeners: Vec<RawListener>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawListener {
+    tcp: Option<String>,
+    unix: Option<PathBuf>,
+    auth: Option<Vec<String>>,
+    admin: Option<bool>,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(deny_unknown_fields, default)]
+struct RawLogger {
+    target: Option<String>,
+    path: Option<PathBuf>,
+    level: Option<String>,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(deny_unknown_fields, default)]
+struct RawDatabase {
+    backend: Option<String>,
+    path: Option<PathBuf>,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(deny_unknown_fields, default)]
+struct RawLimits {
+    max_message_size: Option<usize>,
+    max_connections: Option<usize>,
+    max_connections_per_ip: Option<usize>,
+    max_handshakes: Option<usize>,
+    handshake_timeout: Option<String>,
+    drain_timeout: Option<String>,
+    keepalive: Option<String>,
+    idle_timeout: Option<String>,
+    read_timeout: Option<String>,
+    queue: Option<RawQueue>,
+    offline_messages: Option<usize>,
+    offline_retention: Option<String>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawQueue {
+    capacity: usize,
+    overflow: String,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(deny_unknown_fields, default)]
+struct RawRates {
+    messages: RawScopes,
+    bytes: RawScopes,
+    logins: RawScopes,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(deny_unknown_fields, default)]
+struct RawScopes {
+    connection: Option<RawRate>,
+    user: Option<RawRate>,
+    ip: Option<RawRate>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawRate {
+    per_second: u32,
+    burst: u32,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(deny_unknown_fields, default)]
+struct RawSecurity {
+    admins: Vec<String>,
+    cipher_suites: Option<Vec<String>>,
+    key_exchanges: Option<Vec<String>>,
+    resumption_tickets: Option<String>,
+    ticket_key: Option<String>,
+    bot_secret: Option<String>,
+    login_backoff: Option<RawBackoff>,
+    login_lockout: Option<RawLockout>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawBackoff {
+    base: String,
+    max: String,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawLockout {
+    failures: u32,
+    duration: String,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(deny_unknown_fields, default)]
+struct RawFederation {
+    domain: Option<String>,
+    listen: Option<String>,
+    peers: HashMap<String, RawPeer>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawPeer {
+    addr: String,
+    secret: String,
+}
+
+impl Raw {
+    fn validate(self) -> Result<Config, ConfigError> {
+        let Raw { server, logger, database, limits, rates, security, federation } = self;
+        let mut options = Options::default();
+
+        let listen = match (server.listen, server.listeners.is_empty()) {
+            (Some(listen), _) => Some(addr("server.listen", &listen)?),
+            (None, true) => Some(addr("server.listen", "0.0.0.0:5000")?),
+            (None, false) => None,
+        };
+        let block_size = server.block_size.unwrap_or(1024);
+        if !BLOCK_SIZES.contains(&block_size) {
+            return Err(ConfigError::invalid("server.block_size", format!("must be one of {BLOCK_SIZES:?}")));
+        }
+        options.admin_socket = server.admin_socket;
+        options.metrics = server.metrics.map(|metrics| addr("server.metrics", &metrics)).transpose()?;
+        for (index, RawListener { tcp, unix, auth, admin }) in server.listeners.into_iter().enumerate() {
+            let key = format!("server.listeners[{index}]");
+            let mut listener = match (tcp, unix) {
+                (Some(tcp), None) => Listener::new(Bind::Tcp(addr(&format!("{key}.tcp"), &tcp)?)),
+                (None, Some(unix)) => Listener::new(Bind::Unix(unix)),
+                _ => return Err(ConfigError::invalid(key, "needs exactly one of tcp or unix")),
+            };
+            if let Some(auth) = auth {
+                let methods = auth.iter().map(|method| match method.as_str() {
+                    "password" => Ok(AuthMethod::Password),
+                    "register" => Ok(AuthMethod::Register),
+                    "ticket" => Ok(AuthMethod::Ticket),
+                    method => Err(ConfigError::invalid(format!("{key}.auth"), format!("unknown method {method:?}, expected \"password\", \"register\" or \"ticket\""))),
+                }).collect::<Result<Vec<_>, _>>()?;
+                listener = listener.methods(methods);
+            }
+            if let Some(admin) = admin {
+                listener = listener.admin(admin);
+            }
+            options.listeners.push(listener);
+        }
+
+        let log_target = match (logger.target.as_deref().unwrap_or("stdout"), logger.path) {
+            ("stdout", _) => LogTarget::Stdout,
+            ("file", Some(path)) => LogTarget::File(path),
+            ("file", None) => return Err(ConfigError::invalid("logger.path", "required when logger.target is \"file\"")),
+            ("none", _) => LogTarget::None,
+            (target, _) => return Err(ConfigError::invalid("logger.target", format!("unknown target {target:?}, expected \"stdout\", \"file\" or \"none\""))),
+        };
+        let log_level = match logger.level.as_deref().unwrap_or("info") {
+            "fatal" => LogLevel::Fatal,
+            "error" => LogLevel::Error,
+            "warn" => LogLevel::Warn,
+            "info" => LogLevel::Info,
+            level => return Err(ConfigError::invalid("logger.level", format!("unknown level {level:?}, expected \"fatal\", \"error\", \"warn\" or \"info\""))),
+        };
+
+        let database = DatabaseConfig {
+            backend: database.backend.unwrap_or_else(|| "file".to_owned()),
+            path: database.path,
+        };
+
+        options.max_message_size = limits.max_message_size.map(|size| positive("limits.max_message_size", size)).transpose()?;
+        options.max_connections = limits.max_connections.map(|limit| positive("limits.max_connections", limit)).transpose()?;
+        options.max_connections_per_ip = limits.max_connections_per_ip.map(|limit| positive("limits.max_connections_per_ip", limit)).transpose()?;
+        options.max_handshakes = limits.max_handshakes.map(|limit| positive("limits.max_handshakes", limit)).transpose()?;
+        options.handshake_timeout = limits.handshake_timeout.map(|timeout| duration("limits.handshake_timeout", &timeout)).transpose()?;
+        options.drain_timeout = limits.drain_timeout.map(|timeout| duration("limits.drain_timeout", &timeout)).transpose()?;
+        if let Some(interval) = limits.keepalive {
+            options.stream.keepalive(duration("limits.keepalive", &interval)?);
+        }
+        if let Some(timeout) = limits.idle_timeout {
+            options.stream.idle_timeout(duration("limits.idle_timeout", &timeout)?);
+        }
+        if let Some(timeout) = limits.read_timeout {
+            options.stream.read_timeout(duration("limits.read_timeout", &timeout)?);
+        }
+        if let Some(RawQueue { capacity, overflow }) = limits.queue {
+            let overflow = match overflow.as_str() {
+                "block" => Overflow::Block,
+                "drop_oldest" => Overflow::DropOldest,
+                "disconnect" => Overflow::Disconnect,
+                overflow => return Err(ConfigError::invalid("limits.queue.overflow", format!("unknown policy {overflow:?}, expected \"block\", \"drop_oldest\" or \"disconnect\""))),
+            };
+            options.queue = Some((positive("limits.queue.capacity", capacity)?, overflow));
+        }
+        options.offline_limit = limits.offline_messages.map(|limit| positive("limits.offline_messages", limit)).transpose()?;
+        options.offline_retention = limits.offline_retention.map(|age| duration("limits.offline_retention", &age)).transpose()?;
+
+        for (name, scopes, limits) in [
+            ("messages", rates.messages, &mut options.rates.messages),
+            ("bytes", rates.bytes, &mut options.rates.bytes),
+            ("logins", rates.logins, &mut options.rates.logins),
+        ] {
+            for (key, scope, rate) in [
+                ("connection", Scope::Connection, scopes.connection),
+                ("user", Scope::User, scopes.user),
+                ("ip", Scope::Ip, scopes.ip),
+            ] {
+                let Some(RawRate { per_second, burst }) = rate else { continue };
+                if name == "logins" && scope == Scope::Connection {
+                    return Err(ConfigError::invalid(format!("rates.{name}.{key}"), "not supported, each connection makes a single login attempt"));
+                }
+                if per_second == 0 {
+                    return Err(ConfigError::invalid(format!("rates.{name}.{key}.per_second"), "must be greater than 0"));
+                }
+                limits.set(scope, RateLimit::new(per_second, burst));
+            }
+        }
+
+        options.admins.get_mut().unwrap().extend(security.admins);
+        if let Some(suites) = security.cipher_suites {
+            let suites = suites.iter().map(|suite| match suite.as_str() {
+                "aes256-gcm-siv" => Ok(CipherSuite::Aes256GcmSiv),
+                "aes256-gcm" => Ok(CipherSuite::Aes256Gcm),
+                "chacha20-poly1305" => Ok(CipherSuite::ChaCha20Poly1305),
+                suite => Err(ConfigError::invalid("security.cipher_suites", format!("unknown cipher suite {suite:?}"))),
+            }).collect::<Result<Vec<_>, _>>()?;
+            if suites.is_empty() {
+                return Err(ConfigError::invalid("security.cipher_suites", "must not be empty"));
+            }
+            options.stream.suites(suites);
+        }
+        if let Some(exchanges) = security.key_exchanges {
+            let exchanges = exchanges.iter().map(|exchange| match exchange.as_str() {
+                "x25519" => Ok(KeyExchange::X25519),
+                "x25519-mlkem768" => Ok(KeyExchange::X25519MlKem768),
+                exchange => Err(ConfigError::invalid("security.key_exchanges", format!("unknown key exchange {exchange:?}"))),
+            }).collect::<Result<Vec<_>, _>>()?;
+            if exchanges.is_empty() {
+                return Err(ConfigError::invalid("security.key_exchanges", "must not be empty"));
+            }
+            options.stream.exchanges(exchanges);
+        }
+        options.ticket_lifetime = security.resumption_tickets.map(|lifetime| duration("security.resumption_tickets", &lifetime)).transpose()?;
+        options.ticket_key = security.ticket_key.map(|key| key32("security.ticket_key", &key)).transpose()?;
+        if options.ticket_key.is_some() && options.ticket_lifetime.is_none() {
+            return Err(ConfigError::invalid("security.ticket_key", "requires security.resumption_tickets"));
+        }
+        options.bot_secret = security.bot_secret.map(|secret| key32("security.bot_secret", &secret)).transpose()?;
+        if let Some(RawBackoff { base, max }) = security.login_backoff {
+            let base = duration("security.login_backoff.base", &base)?;
+            let max = duration("security.login_backoff.max", &max)?;
+            if max < base {
+                return Err(ConfigError::invalid("security.login_backoff.max", "must not be less than security.login_backoff.base"));
+            }
+            options.rates.lockouts.backoff(base, max);
+        }
+        if let Some(RawLockout { failures, duration: lockout }) = security.login_lockout {
+            let failures = positive("security.login_lockout.failures", failures as usize)? as u32;
+            options.rates.lockouts.lockout(failures, duration("security.login_lockout.duration", &lockout)?);
+        }
+
+        options.federation.listen = federation.listen.map(|listen| addr("federation.listen", &listen)).transpose()?;
+        if federation.domain.is_none() && (options.federation.listen.is_some() || !federation.peers.is_empty()) {
+            return Err(ConfigError::invalid("federation.domain", "required when federation.listen or federation.peers is set"));
+        }
+        options.federation.domain = federation.domain;
+        for (domain, RawPeer { addr: peer, secret }) in federation.peers {
+            let key = format!("federation.peers.{domain:?}");
+            let peer = Peer {
+                addr: addr(&format!("{key}.addr"), &peer)?,
+                secret: key32(&format!("{key}.secret"), &secret)?,
+            };
+            options.federation.peers.insert(domain, peer);
+        }
+
+        Ok(Config { listen, block_size, database, log_target, log_level, options })
+    }
+}
+
+fn addr(key: &str, value: &str) -> Result<SocketAddr, ConfigError> {
+    value.parse().map_err(|_| ConfigError::invalid(key, format!("invalid socket address {value:?}")))
+}
+
+fn positive(key: &str, value: usize) -> Result<usize, ConfigError> {
+    match value {
+        0 => Err(ConfigError::invalid(key, "must be greater than 0")),
+        value => Ok(value),
+    }
+}
+
+fn duration(key: &str, value: &str) -> Result<Duration, ConfigError> {
+    let invalid = || ConfigError::invalid(key, format!("invalid duration {value:?}, expected a number followed by ms, s, m, h or d"));
+    let split = value.find(|c: char| !c.is_ascii_digit()).ok_or_else(invalid)?;
+    let (amount, unit) = value.split_at(split);
+    let amount = amount.parse::<u64>().map_err(|_| invalid())?;
+    let seconds = |factor: u64| amount.checked_mul(factor).map(Duration::from_secs).ok_or_else(|| ConfigError::invalid(key, format!("duration {value:?} is too long")));
+    let duration = match unit {
+        "ms" => Duration::from_millis(amount),
+        "s" => seconds(1)?,
+        "m" => seconds(60)?,
+        "h" => seconds(60 * 60)?,
+        "d" => seconds(24 * 60 * 60)?,
+        _ => return Err(invalid()),
+    };
+    match duration.is_zero() {
+        true => Err(ConfigError::invalid(key, "must be greater than 0")),
+        false => Ok(duration),
+    }
+}
+
+fn key32(key: &str, value: &str) -> Result<[u8; 32], ConfigError> {
+    let invalid = || ConfigError::invalid(key, "expected 64 hexadecimal digits");
+    if value.len() != 64 || !value.is_ascii() {
+        return Err(invalid());
+    }
+    let mut bytes = [0; 32];
+    for (byte, pair) in bytes.iter_mut().zip(value.as_bytes().chunks(2)) {
+        *byte = u8::from_str_radix(std::str::from_utf8(pair).map_err(|_| invalid())?, 16).map_err(|_| invalid())?;
+    }
+    Ok(bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn invalid(text: &str) -> String {
+        match Config::from_str(text) {
+            Err(ConfigError::Invalid { key, .. }) => key,
+            Err(e) => panic!("expected a validation error, got {e}"),
+            Ok(_) => panic!("expected a validation error"),
+        }
+    }
+
+    #[test]
+    fn empty_config_uses_defaults() {
+        let config = Config::from_str("").unwrap();
+        assert_eq!(config.listen, Some("0.0.0.0:5000".parse().unwrap()));
+        assert_eq!(config.block_size, 1024);
+        assert_eq!(config.database.backend, "file");
+        assert!(matches!(config.log_target, LogTarget::Stdout));
+        assert_eq!(config.log_level, LogLevel::Info);
+    }
+
+    #[test]
+    fn settings_reach_the_options() {
+        let config = Config::from_str(r#"
+            [server]
+            block_size = 4096
+            listeners = [{ unix = "/tmp/chat.sock", auth = ["ticket"] }]
+
+            [logger]
+            target = "file"
+            path = "chat.log"
+            level = "warn"
+
+            [limits]
+            max_connections = 10
+            handshake_timeout = "1500ms"
+            queue = { capacity = 8, overflow = "drop_oldest" }
+
+            [security]
+            admins = ["alice"]
+            resumption_tickets = "7d"
+            ticket_key = "0101010101010101010101010101010101010101010101010101010101010101"
+        "#).unwrap();
+        assert_eq!(config.listen, None);
+        assert_eq!(config.block_size, 4096);
+        assert!(matches!(config.log_target, LogTarget::File(path) if path == Path::new("chat.log")));
+        assert_eq!(config.log_level, LogLevel::Warn);
+        assert_eq!(config.options.listeners.len(), 1);
+        assert_eq!(config.options.max_connections, Some(10));
+        assert_eq!(config.options.handshake_timeout, Some(Duration::from_millis(1500)));
+        assert!(matches!(config.options.queue, Some((8, Overflow::DropOldest))));
+        assert!(config.options.admins.read().unwrap().contains("alice"));
+        assert_eq!(config.options.ticket_lifetime, Some(Duration::from_secs(7 * 24 * 60 * 60)));
+        assert_eq!(config.options.ticket_key, Some([1; 32]));
+    }
+
+    #[test]
+    fn unknown_keys_are_parse_errors() {
+        assert!(matches!(Config::from_str("[server]\nport = 5000"), Err(ConfigError::Parse(_))));
+    }
+
+    #[test]
+    fn errors_name_the_offending_key() {
+        assert_eq!(invalid("[server]\nblock_size = 1000"), "server.block_size");
+        assert_eq!(invalid("[server]\nlisten = \"localhost\""), "server.listen");
+        assert_eq!(invalid("[[server.listeners]]\ntcp = \"127.0.0.1:5000\"\nunix = \"chat.sock\""), "server.listeners[0]");
+        assert_eq!(invalid("[[server.listeners]]\ntcp = \"127.0.0.1:5000\"\nauth = [\"magic\"]"), "server.listeners[0].auth");
+        assert_eq!(invalid("[logger]\ntarget = \"file\""), "logger.path");
+        assert_eq!(invalid("[limits]\nmax_connections = 0"), "limits.max_connections");
+        assert_eq!(invalid("[limits]\ndrain_timeout = \"5 minutes\""), "limits.drain_timeout");
+        assert_eq!(invalid("[limits]\nqueue = { capacity = 8, overflow = \"spill\" }"), "limits.queue.overflow");
+        assert_eq!(invalid("[rates.logins.connection]\nper_second = 1\nburst = 1"), "rates.logins.connection");
+        assert_eq!(invalid("[rates.messages.ip]\nper_second = 0\nburst = 1"), "rates.messages.ip.per_second");
+        assert_eq!(invalid("[security]\ncipher_suites = []"), "security.cipher_suites");
+        assert_eq!(invalid("[security]\nticket_key = \"0101010101010101010101010101010101010101010101010101010101010101\""), "security.ticket_key");
+        assert_eq!(invalid("[security]\nbot_secret = \"01\""), "security.bot_secret");
+        assert_eq!(invalid("[security.login_backoff]\nbase = \"1m\"\nmax = \"1s\""), "security.login_backoff.max");
+        assert_eq!(invalid("[federation]\nlisten = \"0.0.0.0:5001\""), "federation.domain");
+        assert_eq!(
+            invalid("[federation]\ndomain = \"a.example\"\npeers = { \"b.example\" = { addr = \"10.0.0.2:5001\", secret = \"zz\" } }"),
+            "federation.peers.\"b.example\".secret",
+        );
+    }
+
+    #[test]
+    fn durations_need_a_unit_and_must_fit() {
+        assert_eq!(duration("key", "250ms").unwrap(), Duration::from_millis(250));
+        assert_eq!(duration("key", "2h").unwrap(), Duration::from_secs(2 * 60 * 60));
+        assert!(duration("key", "30").is_err());
+        assert!(duration("key", "0s").is_err());
+        assert!(duration("key", "-1s").is_err());
+        assert!(duration("key", "18446744073709551615d").is_err());
+    }
+
+    #[test]
+    fn keys_are_64_hex_digits() {
+        assert_eq!(key32("key", &"ab".repeat(32)).unwrap(), [0xab; 32]);
+        assert!(key32("key", &"ab".repeat(31)).is_err());
+        assert!(key32("key", &"zz".repeat(32)).is_err());
+        assert!(key32("key", &"é".repeat(32)).is_err());
+    }
+}
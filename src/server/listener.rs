@@ -0,0 +1,232 @@
+use std::{
+    collections::HashSet,
+    fs::{self, Permissions},
+    io,
+    net::SocketAddr,
+    os::unix::{fs::{DirBuilderExt, FileTypeExt, PermissionsExt}, net::UnixStream as StdUnixStream},
+    path::{Path, PathBuf},
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+use tokio::{
+    io::{AsyncRead, AsyncWrite, ReadBuf},
+    net::{TcpListener, TcpStream, UnixListener, UnixStream},
+};
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum AuthMethod {
+    Password,
+    Register,
+    Ticket,
+}
+
+impl AuthMethod {
+    pub const ALL: [AuthMethod; 3] = [
+        AuthMethod::Password,
+        AuthMethod::Register,
+        AuthMethod::Ticket,
+    ];
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Bind {
+    Tcp(SocketAddr),
+    Unix(PathBuf),
+}
+
+#[derive(Debug, Clone)]
+pub struct Listener {
+    bind: Bind,
+    methods: HashSet<AuthMethod>,
+    admin: bool,
+}
+
+impl Listener {
+    pub fn tcp<A: Into<SocketAddr>>(addr: A) -> Self {
+        Self::new(Bind::Tcp(addr.into()))
+    }
+
+    pub fn unix<P: Into<PathBuf>>(path: P) -> Self {
+        Self::new(Bind::Unix(path.into()))
+    }
+
+    pub fn new(bind: Bind) -> Self {
+        Self {
+            bind,
+            methods: AuthMethod::ALL.into_iter().collect(),
+            admin: true,
+        }
+    }
+
+    pub fn methods(mut self, methods: impl IntoIterator<Item=AuthMethod>) -> Self {
+        self.methods = methods.into_iter().collect();
+        self
+    }
+
+    pub fn admin(mut self, allowed: bool) -> Self {
+        self.admin = allowed;
+        self
+    }
+
+    pub fn bind(&self) -> &Bind {
+        &self.bind
+    }
+
+    pub(crate) fn allows(&self, method: AuthMethod) -> bool {
+        self.methods.contains(&method)
+    }
+
+    pub(crate) fn allows_admin(&self) -> bool {
+        self.admin
+    }
+}
+
+pub(super) enum Bound {
+    Tcp(TcpListener),
+    Unix(UnixListener, PathBuf),
+}
+
+impl Bound {
+    pub async fn bind(bind: &Bind) -> io::Result<Self> {
+        match bind {
+            Bind::Tcp(addr) => Ok(Bound::Tcp(TcpListener::bind(addr).await?)),
+            Bind::Unix(path) => Ok(Bound::Unix(bind_unix(path)?, path.clone())),
+        }
+    }
+
+    // unix socket peers have no address
+    pub async fn accept(&self) -> io::Result<(Socket, Option<SocketAddr>)> {
+        match self {
+            Bound::Tcp(listener) => {
+                let (stream, addr) = listener.accept().await?;
+                Ok((Socket::Tcp(stream), Some(addr)))
+            }
+            Bound::Unix(listener, _) => {
+                let (stream, _) = listener.accept().await?;
+                Ok((Socket::Unix(stream), None))
+            }
+        }
+    }
+}
+
+// only a socket nobody answers on is stale, anything else at the path is left alone
+pub(super) fn bind_unix(path: &Path) -> io::Result<UnixListener> {
+    remove_stale(path)?;
+    UnixListener::bind(path)
+}
+
+// the socket is bound inside a directory only the server can enter and only moved into place once it is
+// restricted to the owner, so there is no moment where anyone else could connect to it
+pub(super) fn bind_private(path: &Path) -> io::Result<UnixListener> {
+    let name = path.file_name().ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, format!("{} is not a file path", path.display())))?;
+    remove_stale(path)?;
+    let staging = path.with_file_name(format!(".{}.{}", name.to_string_lossy(), std::process::id()));
+    fs::DirBuilder::new().mode(0o700).create(&staging)?;
+    let staged = staging.join(name);
+    let bound = UnixListener::bind(&staged).and_then(|listener| {
+        fs::set_permissions(&staged, Permissions::from_mode(0o600))?;
+        fs::rename(&staged, path)?;
+        Ok(listener)
+    });
+    let _ = fs::remove_file(&staged);
+    let _ = fs::remove_dir(&staging);
+    bound
+}
+
+fn remove_stale(path: &Path) -> io::Result<()> {
+    match fs::symlink_metadata(path) {
+        Ok(metadata) if metadata.file_type().is_socket() => {
+            if StdUnixStream::connect(path).is_ok() {
+                return Err(io::Error::new(io::ErrorKind::AddrInUse, format!("{} is in use", path.display())));
+            }
+            fs::remove_file(path)
+        }
+        Ok(_) => Err(io::Error::new(io::ErrorKind::AlreadyExists, format!("{} is not a socket", path.display()))),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+        Err(e) => Err(e),
+    }
+}
+
+impl Drop for Bound {
+    fn drop(&mut self) {
+        if let Bound::Unix(_, path) = self {
+            let _ = std::fs::remove_file(path);
+        }
+    }
+}
+
+pub(super) enum Socket {
+    Tcp(TcpStream),
+    Unix(UnixStream),
+}
+
+impl AsyncRead for Socket {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Socket::Tcp(stream) => Pin::new(stream).poll_read(cx, buf),
+            Socket::Unix(stream) => Pin::new(stream).poll_read(cx, buf),
+        }
+    }
+}
+
+impl AsyncWrite for Socket {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            Socket::Tcp(stream) => Pin::new(stream).poll_write(cx, buf),
+            Socket::Unix(stream) => Pin::new(stream).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Socket::Tcp(stream) => Pin::new(stream).poll_flush(cx),
+            Socket::Unix(stream) => Pin::new(stream).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Socket::Tcp(stream) => Pin::new(stream).poll_shutdown(cx),
+            Socket::Unix(stream) => Pin::new(stream).poll_shutdown(cx),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::os::unix::fs::MetadataExt;
+
+    use super::*;
+
+    fn scratch(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("chat-listener-{name}-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir(&dir).unwrap();
+        dir
+    }
+
+    #[tokio::test]
+    async fn private_sockets_are_owner_only_and_staged_out_of_sight() {
+        let dir = scratch("private");
+        let path = dir.join("admin.sock");
+        let listener = bind_private(&path).unwrap();
+        assert_eq!(fs::metadata(&path).unwrap().mode() & 0o777, 0o600);
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 1);
+        assert!(bind_private(&path).is_err());
+        drop(listener);
+        drop(bind_private(&path).unwrap());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn other_files_are_not_replaced() {
+        let dir = scratch("file");
+        let path = dir.join("admin.sock");
+        fs::write(&path, "keep").unwrap();
+        assert!(bind_unix(&path).is_err());
+        assert!(bind_private(&path).is_err());
+        assert_eq!(fs::read_to_string(&path).unwrap(), "keep");
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
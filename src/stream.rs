@@ -1,30 +1,58 @@
-use std::fmt::{Debug, Formatter};
+use std::{
+    fmt::{Debug, Formatter},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
 
 use tokio::{
     io::{
         self,
         AsyncReadExt,
         AsyncWriteExt,
+        ReadHalf,
+        WriteHalf,
     },
     net::TcpStream,
 };
 
-use rand_core2::{
-    OsRng,
-    RngCore,
-};
+use rand_core2::OsRng;
 
-use aes::{
-    Aes256,
-    cipher::KeyInit,
-};
+use aes::Aes256;
+
+use aes_gcm::Aes256Gcm;
 
 use aes_gcm_siv::{aead::{
     AeadMutInPlace,
     Error,
     heapless,
+    KeyInit,
 }, AesGcmSiv, Nonce};
 
+use chacha20poly1305::ChaCha20Poly1305;
+
+use hkdf::Hkdf;
+
+use ml_kem::{
+    kem::{
+        Decapsulate,
+        Encapsulate,
+        EncapsulationKey,
+    },
+    Ciphertext,
+    Encoded,
+    EncodedSizeUser,
+    KemCore,
+    MlKem768,
+    MlKem768Params,
+};
+
+use rand_core1::OsRng as OsRng1;
+
+use sha2::{Digest, Sha256};
+
 use x25519_dalek::{
     EphemeralSecret,
     PublicKey,
@@ -32,90 +60,469 @@ use x25519_dalek::{
 
 use crate::serialization::{Deserializable, Deserializer, Serializable, Serializer};
 
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum CipherSuite {
+    Aes256GcmSiv,
+    Aes256Gcm,
+    ChaCha20Poly1305,
+}
+
+impl CipherSuite {
+    pub const ALL: [CipherSuite; 3] = [
+        CipherSuite::Aes256GcmSiv,
+        CipherSuite::Aes256Gcm,
+        CipherSuite::ChaCha20Poly1305,
+    ];
+
+    fn id(self) -> u8 {
+        match self {
+            CipherSuite::Aes256GcmSiv => 0,
+            CipherSuite::Aes256Gcm => 1,
+            CipherSuite::ChaCha20Poly1305 => 2,
+        }
+    }
+
+    fn from_id(id: u8) -> Option<Self> {
+        Self::ALL.into_iter().find(|suite| suite.id() == id)
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum KeyExchange {
+    X25519,
+    X25519MlKem768,
+}
+
+impl KeyExchange {
+    pub const ALL: [KeyExchange; 2] = [
+        KeyExchange::X25519MlKem768,
+        KeyExchange::X25519,
+    ];
+
+    fn id(self) -> u8 {
+        match self {
+            KeyExchange::X25519 => 0,
+            KeyExchange::X25519MlKem768 => 1,
+        }
+    }
+
+    fn from_id(id: u8) -> Option<Self> {
+        Self::ALL.into_iter().find(|exchange| exchange.id() == id)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Config {
+    suites: Vec<CipherSuite>,
+    exchanges: Vec<KeyExchange>,
+    pub(crate) keepalive: Option<Duration>,
+    pub(crate) idle_timeout: Option<Duration>,
+    pub(crate) read_timeout: Option<Duration>,
+    traffic: Option<Arc<Traffic>>,
+}
+
+#[derive(Debug, Default)]
+pub(crate) struct Traffic {
+    pub bytes_in: AtomicU64,
+    pub bytes_out: AtomicU64,
+}
+
+impl Config {
+    pub fn suites(&mut self, suites: impl IntoIterator<Item=CipherSuite>) {
+        self.suites = suites.into_iter().collect();
+    }
+
+    pub fn exchanges(&mut self, exchanges: impl IntoIterator<Item=KeyExchange>) {
+        self.exchanges = exchanges.into_iter().collect();
+    }
+
+    pub fn keepalive(&mut self, interval: Duration) {
+        self.keepalive = Some(interval);
+    }
+
+    pub fn idle_timeout(&mut self, timeout: Duration) {
+        self.idle_timeout = Some(timeout);
+    }
+
+    pub fn read_timeout(&mut self, timeout: Duration) {
+        self.read_timeout = Some(timeout);
+    }
+
+    pub(crate) fn traffic(&mut self, traffic: Arc<Traffic>) {
+        self.traffic = Some(traffic);
+    }
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            suites: CipherSuite::ALL.to_vec(),
+            exchanges: KeyExchange::ALL.to_vec(),
+            keepalive: None,
+            idle_timeout: None,
+            read_timeout: None,
+            traffic: None,
+        }
+    }
+}
+
+#[derive(Clone)]
+enum Cipher {
+    Aes256GcmSiv(AesGcmSiv<Aes256>),
+    Aes256Gcm(Aes256Gcm),
+    ChaCha20Poly1305(ChaCha20Poly1305),
+}
+
+impl Cipher {
+    fn new(suite: CipherSuite, key: [u8; 32]) -> Self {
+        let key = key.into();
+        match suite {
+            CipherSuite::Aes256GcmSiv => Self::Aes256GcmSiv(AesGcmSiv::new(&key)),
+            CipherSuite::Aes256Gcm => Self::Aes256Gcm(Aes256Gcm::new(&key)),
+            CipherSuite::ChaCha20Poly1305 => Self::ChaCha20Poly1305(ChaCha20Poly1305::new(&key)),
+        }
+    }
+
+    fn encrypt_in_place<const N: usize>(&mut self, nonce: &Nonce, buf: &mut heapless::Vec<u8, N>) -> Result<(), Error> {
+        match self {
+            Cipher::Aes256GcmSiv(c) => c.encrypt_in_place(nonce, b"", buf),
+            Cipher::Aes256Gcm(c) => c.encrypt_in_place(nonce, b"", buf),
+            Cipher::ChaCha20Poly1305(c) => c.encrypt_in_place(nonce, b"", buf),
+        }
+    }
+
+    fn decrypt_in_place<const N: usize>(&mut self, nonce: &Nonce, buf: &mut heapless::Vec<u8, N>) -> Result<(), Error> {
+        match self {
+            Cipher::Aes256GcmSiv(c) => c.decrypt_in_place(nonce, b"", buf),
+            Cipher::Aes256Gcm(c) => c.decrypt_in_place(nonce, b"", buf),
+            Cipher::ChaCha20Poly1305(c) => c.decrypt_in_place(nonce, b"", buf),
+        }
+    }
+}
+
+// counted nonces are unique per key, and a replayed or reordered block is refused
+struct Direction {
+    cipher: Cipher,
+    counter: u64,
+}
+
+impl Direction {
+    fn counted(suite: CipherSuite, key: [u8; 32]) -> Self {
+        Self { cipher: Cipher::new(suite, key), counter: 0 }
+    }
+
+    fn next(&mut self) -> Option<Nonce> {
+        let nonce = counted(self.counter);
+        self.counter = self.counter.checked_add(1)?;
+        Some(nonce)
+    }
+
+    fn expect(&mut self, nonce: &Nonce) -> bool {
+        *nonce == counted(self.counter) && self.counter.checked_add(1).map(|next| self.counter = next).is_some()
+    }
+}
+
+fn counted(counter: u64) -> Nonce {
+    let mut nonce = [0; 12];
+    nonce[4..].copy_from_slice(&counter.to_be_bytes());
+    Nonce::from(nonce)
+}
+
 pub struct BlockStream<const N: usize = 1024, S: AsyncWriteExt + AsyncReadExt + Unpin + Send + 'static = TcpStream> {
     stream: S,
-    aes: AesGcmSiv<Aes256>,
+    sending: Direction,
+    receiving: Direction,
+    exporter: [u8; 32],
+    traffic: Option<Arc<Traffic>>,
 }
 
 impl<S: AsyncWriteExt + AsyncReadExt + Unpin + Send + 'static, const N: usize> BlockStream<N, S> where [(); N + 16]: {
-    pub async fn new(mut stream: S) -> Result<Self, io::Error> {
-        let my_secret = EphemeralSecret::new(OsRng);
+    pub async fn connect(mut stream: S, config: &Config) -> Result<Self, io::Error> {
+        let offer = config.suites.iter()
+            .map(|suite| suite.id())
+            .chain(config.exchanges.iter().map(|exchange| EXCHANGE | exchange.id()))
+            .collect::<Vec<_>>();
+        stream.write_u8(offer.len().try_into().map_err(|_| invalid_data("too many offered algorithms"))?).await?;
+        stream.write_all(&offer).await?;
+
+        let chosen = [stream.read_u8().await?, stream.read_u8().await?];
+        let suite = CipherSuite::from_id(chosen[0])
+            .filter(|suite| config.suites.contains(suite))
+            .ok_or_else(|| invalid_data("no common cipher suite"))?;
+        let exchange = KeyExchange::from_id(chosen[1])
+            .filter(|exchange| config.exchanges.contains(exchange))
+            .ok_or_else(|| invalid_data("no common key exchange"))?;
+
+        let mut transcript = Sha256::new();
+        transcript.update(&offer);
+        transcript.update(chosen);
+        let (mut secret, mine, theirs) = x25519(&mut stream).await?;
+        transcript.update(mine);
+        transcript.update(theirs);
+        if exchange == KeyExchange::X25519MlKem768 {
+            let (decapsulation, encapsulation) = MlKem768::generate(&mut OsRng1);
+            stream.write_all(&encapsulation.as_bytes()).await?;
+
+            let mut ciphertext = Ciphertext::<MlKem768>::default();
+            stream.read_exact(&mut ciphertext).await?;
+            let shared = decapsulation.decapsulate(&ciphertext).map_err(|_| invalid_data("ml-kem decapsulation failed"))?;
+            transcript.update(encapsulation.as_bytes());
+            transcript.update(&ciphertext);
+            secret.extend_from_slice(&shared);
+        }
 
-        let my_public = PublicKey::from(&my_secret);
-        stream.write_all(&my_public.to_bytes()).await?;
+        Self::finish(stream, suite, &secret, transcript.finalize().into(), true, config)
+    }
+
+    pub async fn accept(mut stream: S, config: &Config) -> Result<Self, io::Error> {
+        let mut offer = vec![0; stream.read_u8().await? as usize];
+        stream.read_exact(&mut offer).await?;
+        let (offered_exchanges, offered_suites): (Vec<u8>, Vec<u8>) = offer.iter().copied().partition(|id| id & EXCHANGE != 0);
+
+        let suite = config.suites
+            .iter()
+            .copied()
+            .find(|suite| offered_suites.contains(&suite.id()));
+        let exchange = config.exchanges
+            .iter()
+            .copied()
+            .find(|exchange| offered_exchanges.contains(&(EXCHANGE | exchange.id())));
+        let chosen = [suite.map_or(NONE, CipherSuite::id), exchange.map_or(NONE, KeyExchange::id)];
+        stream.write_all(&chosen).await?;
+        let suite = suite.ok_or_else(|| invalid_data("no common cipher suite"))?;
+        let exchange = exchange.ok_or_else(|| invalid_data("no common key exchange"))?;
 
-        let mut public = [0; 32];
-        stream.read_exact(&mut public).await?;
-        let public = PublicKey::from(public);
+        let mut transcript = Sha256::new();
+        transcript.update(&offer);
+        transcript.update(chosen);
+        let (mut secret, mine, theirs) = x25519(&mut stream).await?;
+        transcript.update(theirs);
+        transcript.update(mine);
+        if exchange == KeyExchange::X25519MlKem768 {
+            let mut encoded = Encoded::<EncapsulationKey<MlKem768Params>>::default();
+            stream.read_exact(&mut encoded).await?;
+            let encapsulation = EncapsulationKey::<MlKem768Params>::from_bytes(&encoded);
 
-        let shared = my_secret.diffie_hellman(&public);
+            let (ciphertext, shared) = encapsulation.encapsulate(&mut OsRng1).map_err(|_| invalid_data("ml-kem encapsulation failed"))?;
+            stream.write_all(&ciphertext).await?;
+            transcript.update(&encoded);
+            transcript.update(&ciphertext);
+            secret.extend_from_slice(&shared);
+        }
 
-        let key = shared.to_bytes().into();
-        let aes = aes::Aes256::new(&key).into();
+        Self::finish(stream, suite, &secret, transcript.finalize().into(), false, config)
+    }
 
+    fn finish(stream: S, suite: CipherSuite, secret: &[u8], transcript: [u8; 32], client: bool, config: &Config) -> Result<Self, io::Error> {
+        let hkdf = Hkdf::<Sha256>::new(None, secret);
+        let key = |role: &[u8]| {
+            let mut key = [0; 32];
+            hkdf.expand(&[b"chat block stream ".as_slice(), role, &transcript].concat(), &mut key)
+                .map(|()| key)
+                .map_err(|_| invalid_data("key derivation failed"))
+        };
+        let (client_key, server_key) = (key(b"client")?, key(b"server")?);
+        let (sending, receiving) = if client { (client_key, server_key) } else { (server_key, client_key) };
         Ok(Self {
             stream,
-            aes,
+            sending: Direction::counted(suite, sending),
+            receiving: Direction::counted(suite, receiving),
+            exporter: key(b"exporter")?,
+            traffic: config.traffic.clone(),
         })
     }
 
+    pub(crate) fn exporter(&self) -> [u8; 32] {
+        self.exporter
+    }
+
     pub async fn write_block<B: Serializable>(&mut self, block: B) -> Result<(), WriteError> {
         let mut serializer = block.serializer();
-        let mut buf = heapless::Vec::<_, { N + 16 }>::from_slice(&[0; N]).unwrap();
         loop {
-            let finished = match serializer.fill(&mut buf[8..N]) {
-                None => {
-                    buf[..8].fill(0);
-                    false
-                }
-                Some(len) => {
-                    let t = (len + 1).to_be_bytes();
-                    buf.iter_mut().zip(t.into_iter()).for_each(|(a, b)| *a = b);
-                    true
-                }
-            };
-            let mut nonce = [0; 12];
-            OsRng.fill_bytes(&mut nonce);
-            let nonce = Nonce::from(nonce);
-
-            self.aes.encrypt_in_place(&nonce, b"", &mut buf).map_err(|e| WriteError::EncryptError(e))?;
-            self.stream.write_all(&nonce).await.map_err(|e| WriteError::NetworkError(e))?;
-            self.stream.write_all(&buf).await.map_err(|e| WriteError::NetworkError(e))?;
-
-            if finished {
-                break Ok(());
+            let mut chunk = [0; N];
+            match serializer.fill(&mut chunk[..N - HEADER]) {
+                None => write_raw::<N, _>(&mut self.stream, &mut self.sending, self.traffic.as_deref(), DATA, 0, &chunk[..N - HEADER], false).await?,
+                Some(len) => break write_raw::<N, _>(&mut self.stream, &mut self.sending, self.traffic.as_deref(), DATA, 0, &chunk[..len], true).await,
             }
         }
     }
 
     pub async fn read_block<T: Deserializable>(&mut self) -> Result<T, ReadError<T>> where [(); N + 16]: {
+        self.read_limited(usize::MAX).await
+    }
+
+    pub async fn read_limited<T: Deserializable>(&mut self, blocks: usize) -> Result<T, ReadError<T>> where [(); N + 16]: {
         let mut output = T::deserializer();
+        let mut read = 0;
         loop {
-            let mut nonce = [0; 12];
-            self.stream.read_exact(&mut nonce).await.map_err(|e| ReadError::NetworkError(e))?;
-            let nonce = Nonce::from(nonce);
-
-            let mut block = [0; N + 16];
-            self.stream.read_exact(&mut block).await.map_err(|e| ReadError::NetworkError(e))?;
-
-            let mut decrypted = heapless::Vec::<_, { N + 16 }>::from_slice(&block).unwrap();
-            self.aes.decrypt_in_place(&nonce, b"", &mut decrypted).map_err(|e| ReadError::DecryptError(e))?;
-            let n = usize::from_be_bytes(decrypted[..8].try_into().unwrap());
-            if n == 0 {
-                output.update(&decrypted[8..]).map_err(|e| ReadError::UpdateError(e))?;
-            } else {
-                output.update(&decrypted[8..7 + n]).map_err(|e| ReadError::UpdateError(e))?;
-                break;
+            match read_raw::<N, _>(&mut self.stream, &mut self.receiving, self.traffic.as_deref()).await? {
+                Block::Data { channel: 0, payload, last } => {
+                    read += 1;
+                    if read > blocks {
+                        break Err(ReadError::TooLarge);
+                    }
+                    output.update(&payload).map_err(|e| ReadError::UpdateError(e))?;
+                    if last {
+                        break Ok(output.finalize().map_err(|e| ReadError::FinalizeError(e))?);
+                    }
+                }
+                Block::Ping => write_raw::<N, _>(&mut self.stream, &mut self.sending, self.traffic.as_deref(), PONG, 0, &[], true)
+                    .await
+                    .map_err(|e| ReadError::Pong(e))?,
+                Block::Pong => {}
+                _ => break Err(ReadError::Control),
             }
         }
-        Ok(output.finalize().map_err(|e| ReadError::FinalizeError(e))?)
     }
+
+    pub fn split(self) -> (BlockReader<N, ReadHalf<S>>, BlockWriter<N, WriteHalf<S>>) {
+        let Self { stream, sending, receiving, traffic, .. } = self;
+        let (reader, writer) = io::split(stream);
+        (
+            BlockReader {
+                stream: reader,
+                receiving,
+                traffic: traffic.clone(),
+            },
+            BlockWriter {
+                stream: writer,
+                sending,
+                traffic,
+            },
+        )
+    }
+}
+
+#[derive(Debug)]
+pub enum Block {
+    Data {
+        channel: u32,
+        payload: Vec<u8>,
+        last: bool,
+    },
+    Ping,
+    Pong,
+    Window {
+        channel: u32,
+        credit: u32,
+    },
+}
+
+pub struct BlockReader<const N: usize, S: AsyncReadExt + Unpin + Send + 'static> {
+    stream: S,
+    receiving: Direction,
+    traffic: Option<Arc<Traffic>>,
+}
+
+impl<S: AsyncReadExt + Unpin + Send + 'static, const N: usize> BlockReader<N, S> where [(); N + 16]: {
+    pub async fn read_raw(&mut self) -> Result<Block, BlockError> {
+        read_raw::<N, _>(&mut self.stream, &mut self.receiving, self.traffic.as_deref()).await
+    }
+}
+
+pub struct BlockWriter<const N: usize, S: AsyncWriteExt + Unpin + Send + 'static> {
+    stream: S,
+    sending: Direction,
+    traffic: Option<Arc<Traffic>>,
+}
+
+impl<S: AsyncWriteExt + Unpin + Send + 'static, const N: usize> BlockWriter<N, S> where [(); N + 16]: {
+    pub async fn write_raw(&mut self, block: Block) -> Result<(), WriteError> {
+        let (kind, channel, payload, last) = match block {
+            Block::Data { channel, payload, last } => (DATA, channel, payload, last),
+            Block::Ping => (PING, 0, Vec::new(), true),
+            Block::Pong => (PONG, 0, Vec::new(), true),
+            Block::Window { channel, credit } => (WINDOW, channel, credit.to_be_bytes().to_vec(), true),
+        };
+        write_raw::<N, _>(&mut self.stream, &mut self.sending, self.traffic.as_deref(), kind, channel, &payload, last).await
+    }
+}
+
+const DATA: u8 = 0;
+const PING: u8 = 1;
+const PONG: u8 = 2;
+const WINDOW: u8 = 3;
+
+pub const HEADER: usize = 13;
+
+async fn write_raw<const N: usize, S: AsyncWriteExt + Unpin>(
+    stream: &mut S,
+    sending: &mut Direction,
+    traffic: Option<&Traffic>,
+    kind: u8,
+    channel: u32,
+    payload: &[u8],
+    last: bool,
+) -> Result<(), WriteError> where [(); N + 16]: {
+    let mut buf = heapless::Vec::<_, { N + 16 }>::from_slice(&[0; N]).unwrap();
+    buf[0] = kind;
+    buf[1..5].copy_from_slice(&channel.to_be_bytes());
+    let len = if last { payload.len() as u64 + 1 } else { 0 };
+    buf[5..HEADER].copy_from_slice(&len.to_be_bytes());
+    buf[HEADER..HEADER + payload.len()].copy_from_slice(payload);
+
+    let nonce = sending.next().ok_or(WriteError::NonceExhausted)?;
+    sending.cipher.encrypt_in_place(&nonce, &mut buf).map_err(|e| WriteError::EncryptError(e))?;
+    stream.write_all(&nonce).await.map_err(|e| WriteError::NetworkError(e))?;
+    stream.write_all(&buf).await.map_err(|e| WriteError::NetworkError(e))?;
+    if let Some(traffic) = traffic {
+        traffic.bytes_out.fetch_add((12 + N + 16) as u64, Ordering::Relaxed);
+    }
+    Ok(())
+}
+
+async fn read_raw<const N: usize, S: AsyncReadExt + Unpin>(
+    stream: &mut S,
+    receiving: &mut Direction,
+    traffic: Option<&Traffic>,
+) -> Result<Block, BlockError> where [(); N + 16]: {
+    let mut nonce = [0; 12];
+    stream.read_exact(&mut nonce).await.map_err(|e| BlockError::Network(e))?;
+    let nonce = Nonce::from(nonce);
+
+    let mut block = [0; N + 16];
+    stream.read_exact(&mut block).await.map_err(|e| BlockError::Network(e))?;
+    if let Some(traffic) = traffic {
+        traffic.bytes_in.fetch_add((12 + N + 16) as u64, Ordering::Relaxed);
+    }
+
+    if !receiving.expect(&nonce) {
+        return Err(BlockError::Nonce);
+    }
+    let mut decrypted = heapless::Vec::<_, { N + 16 }>::from_slice(&block).unwrap();
+    receiving.cipher.decrypt_in_place(&nonce, &mut decrypted).map_err(|e| BlockError::Decrypt(e))?;
+    let channel = u32::from_be_bytes(decrypted[1..5].try_into().unwrap());
+    let len = usize::try_from(u64::from_be_bytes(decrypted[5..HEADER].try_into().unwrap())).map_err(|_| BlockError::Malformed)?;
+    let payload = match len {
+        0 => &decrypted[HEADER..],
+        len => (HEADER - 1).checked_add(len).and_then(|end| decrypted.get(HEADER..end)).ok_or(BlockError::Malformed)?,
+    };
+    let last = len != 0;
+    Ok(match decrypted[0] {
+        DATA => Block::Data { channel, payload: payload.to_vec(), last },
+        PING if last => Block::Ping,
+        PONG if last => Block::Pong,
+        WINDOW if last => Block::Window {
+            channel,
+            credit: u32::from_be_bytes(payload.try_into().map_err(|_| BlockError::Malformed)?),
+        },
+        _ => return Err(BlockError::Malformed),
+    })
 }
 
 #[derive(Debug)]
 pub enum WriteError {
     NetworkError(io::Error),
     EncryptError(Error),
+    NonceExhausted,
+}
+
+#[derive(Debug)]
+pub enum BlockError {
+    Network(io::Error),
+    Decrypt(Error),
+    Nonce,
+    Malformed,
 }
 
 pub enum ReadError<T: Deserializable> {
@@ -123,6 +530,22 @@ pub enum ReadError<T: Deserializable> {
     DecryptError(Error),
     UpdateError(<T::Deserializer as Deserializer<T>>::UpdateError),
     FinalizeError(<T::Deserializer as Deserializer<T>>::FinalizeError),
+    Malformed,
+    TooLarge,
+    Control,
+    Pong(WriteError),
+    Timeout,
+    Closed,
+}
+
+impl<T: Deserializable> From<BlockError> for ReadError<T> {
+    fn from(value: BlockError) -> Self {
+        match value {
+            BlockError::Network(e) => Self::NetworkError(e),
+            BlockError::Decrypt(e) => Self::DecryptError(e),
+            BlockError::Nonce | BlockError::Malformed => Self::Malformed,
+        }
+    }
 }
 
 impl<T: Deserializable> Debug for ReadError<T> {
@@ -146,6 +569,118 @@ impl<T: Deserializable> Debug for ReadError<T> {
             ReadError::DecryptError(e) => write!(f, "DecryptError({e:?})"),
             ReadError::UpdateError(e) => write!(f, "UpdateError({:?})", S(e)),
             ReadError::FinalizeError(e) => write!(f, "FinalizeError({:?})", S(e)),
+            ReadError::Malformed => write!(f, "Malformed"),
+            ReadError::TooLarge => write!(f, "TooLarge"),
+            ReadError::Control => write!(f, "Control"),
+            ReadError::Pong(e) => write!(f, "Pong({e:?})"),
+            ReadError::Timeout => write!(f, "Timeout"),
+            ReadError::Closed => write!(f, "Closed"),
         }
     }
 }
+
+const NONE: u8 = u8::MAX;
+
+const EXCHANGE: u8 = 0x80;
+
+async fn x25519<S: AsyncWriteExt + AsyncReadExt + Unpin>(stream: &mut S) -> Result<(Vec<u8>, [u8; 32], [u8; 32]), io::Error> {
+    let my_secret = EphemeralSecret::new(OsRng);
+
+    let my_public = PublicKey::from(&my_secret).to_bytes();
+    stream.write_all(&my_public).await?;
+
+    let mut public = [0; 32];
+    stream.read_exact(&mut public).await?;
+
+    Ok((my_secret.diffie_hellman(&PublicKey::from(public)).to_bytes().to_vec(), my_public, public))
+}
+
+fn invalid_data(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::{duplex, DuplexStream};
+
+    use super::*;
+
+    fn config(suite: CipherSuite, exchange: KeyExchange) -> Config {
+        let mut config = Config::default();
+        config.suites([suite]);
+        config.exchanges([exchange]);
+        config
+    }
+
+    async fn pair(client: &Config, server: &Config) -> (io::Result<BlockStream<1024, DuplexStream>>, io::Result<BlockStream<1024, DuplexStream>>) {
+        let (client_stream, server_stream) = duplex(1 << 16);
+        tokio::join!(BlockStream::connect(client_stream, client), BlockStream::accept(server_stream, server))
+    }
+
+    async fn round_trip(mut client: BlockStream<1024, DuplexStream>, mut server: BlockStream<1024, DuplexStream>) {
+        let long = "block ".repeat(1000);
+        client.write_block(long.as_str()).await.unwrap();
+        assert_eq!(server.read_block::<String>().await.unwrap(), long);
+        server.write_block("reply").await.unwrap();
+        assert_eq!(client.read_block::<String>().await.unwrap(), "reply");
+        client.write_block("again").await.unwrap();
+        assert_eq!(server.read_block::<String>().await.unwrap(), "again");
+    }
+
+    #[tokio::test]
+    async fn every_suite_and_exchange_round_trips() {
+        for suite in CipherSuite::ALL {
+            for exchange in KeyExchange::ALL {
+                let config = config(suite, exchange);
+                let (client, server) = pair(&config, &config).await;
+                let (client, server) = (client.unwrap(), server.unwrap());
+                assert_eq!(client.exporter(), server.exporter());
+                round_trip(client, server).await;
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn disjoint_exchanges_fail() {
+        let (client, server) = pair(
+            &config(CipherSuite::Aes256Gcm, KeyExchange::X25519),
+            &config(CipherSuite::Aes256Gcm, KeyExchange::X25519MlKem768),
+        ).await;
+        assert!(client.is_err());
+        assert!(server.is_err());
+    }
+
+    #[tokio::test]
+    async fn limited_read_rejects_long_blocks() {
+        let config = Config::default();
+        let (client, server) = pair(&config, &config).await;
+        let (mut client, mut server) = (client.unwrap(), server.unwrap());
+        client.write_block("block ".repeat(1000).as_str()).await.unwrap();
+        assert!(matches!(server.read_limited::<String>(1).await, Err(ReadError::TooLarge)));
+    }
+
+    #[tokio::test]
+    async fn overflowing_block_lengths_are_malformed() {
+        let mut sending = Direction::counted(CipherSuite::Aes256Gcm, [7; 32]);
+        let mut receiving = Direction::counted(CipherSuite::Aes256Gcm, [7; 32]);
+        let mut block = heapless::Vec::<u8, { 64 + 16 }>::from_slice(&[0; 64]).unwrap();
+        block[0] = DATA;
+        block[5..HEADER].copy_from_slice(&u64::MAX.to_be_bytes());
+        let nonce = sending.next().unwrap();
+        sending.cipher.encrypt_in_place(&nonce, &mut block).unwrap();
+        let bytes = [nonce.as_slice(), &block].concat();
+        assert!(matches!(read_raw::<64, _>(&mut bytes.as_slice(), &mut receiving, None).await, Err(BlockError::Malformed)));
+    }
+
+    #[test]
+    fn counted_nonces_reject_replays() {
+        let mut sending = Direction::counted(CipherSuite::Aes256Gcm, [7; 32]);
+        let mut receiving = Direction::counted(CipherSuite::Aes256Gcm, [7; 32]);
+        let first = sending.next().unwrap();
+        let second = sending.next().unwrap();
+        assert_ne!(first, second);
+        assert!(receiving.expect(&first));
+        assert!(!receiving.expect(&first));
+        assert!(receiving.expect(&second));
+    }
+}
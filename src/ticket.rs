@@ -0,0 +1,93 @@
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use aes_gcm_siv::{
+    aead::{
+        Aead,
+        KeyInit,
+    },
+    Aes256GcmSiv,
+    Nonce,
+};
+
+use rand_core1::{
+    OsRng,
+    RngCore,
+};
+
+use crate::db::User;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub struct TicketId([u8; 16]);
+
+impl TicketId {
+    pub fn as_bytes(&self) -> &[u8; 16] {
+        &self.0
+    }
+}
+
+#[derive(Debug)]
+pub struct Ticket {
+    pub id: TicketId,
+    pub name: String,
+    pub issued: SystemTime,
+    pub expires: SystemTime,
+}
+
+pub(crate) struct Tickets {
+    cipher: Aes256GcmSiv,
+    lifetime: Duration,
+}
+
+impl Tickets {
+    pub fn new(key: [u8; 32], lifetime: Duration) -> Self {
+        Self {
+            cipher: Aes256GcmSiv::new(&key.into()),
+            lifetime,
+        }
+    }
+
+    pub fn random_key() -> [u8; 32] {
+        let mut key = [0; 32];
+        OsRng.fill_bytes(&mut key);
+        key
+    }
+
+    pub fn issue(&self, user: &User) -> Vec<u8> {
+        let mut id = [0; 16];
+        OsRng.fill_bytes(&mut id);
+        let expires = match SystemTime::now().checked_add(self.lifetime) {
+            Some(expires) => expires.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs(),
+            // a lifetime too long to represent never runs out
+            None => u64::MAX,
+        };
+
+        let mut plaintext = id.to_vec();
+        plaintext.extend_from_slice(&expires.to_be_bytes());
+        plaintext.extend_from_slice(user.as_bytes());
+
+        let mut nonce = [0; 12];
+        OsRng.fill_bytes(&mut nonce);
+        let mut sealed = nonce.to_vec();
+        sealed.extend(self.cipher.encrypt(&Nonce::from(nonce), plaintext.as_slice()).unwrap());
+        sealed
+    }
+
+    pub fn open(&self, sealed: &[u8]) -> Option<Ticket> {
+        let nonce = sealed.get(..12)?;
+        let plaintext = self.cipher.decrypt(Nonce::from_slice(nonce), &sealed[12..]).ok()?;
+        let id = plaintext.get(..16)?;
+        let expires = plaintext.get(16..24)?;
+        let name = &plaintext[24..];
+
+        let expires = UNIX_EPOCH + Duration::from_secs(u64::from_be_bytes(expires.try_into().ok()?));
+        if expires <= SystemTime::now() {
+            return None;
+        }
+        Some(Ticket {
+            id: TicketId(id.try_into().ok()?),
+            name: String::from_utf8(name.to_vec()).ok()?,
+            issued: expires.checked_sub(self.lifetime)?,
+            expires,
+        })
+    }
+}